@@ -1,5 +1,6 @@
 use crate::memory::Memory;
-use crate::header::StoryHeader; // To access header fields for sp, pc initialization
+use crate::MemoryError;
+use std::fmt;
 
 #[derive(Debug)]
 pub struct Cpu {
@@ -7,18 +8,38 @@ pub struct Cpu {
     pub sp: u64, // Stack Pointer
     pub fp: u64, // Frame Pointer
     initial_sp: u64, // To check for stack underflow
+    stack_limit: u64, // Lowest valid SP; the live globals sit below it
 }
 
 #[derive(Debug, PartialEq)]
 pub enum StackError {
     Overflow,
     Underflow,
-    MemoryAccess(String),
+    MemoryAccess(MemoryError),
 }
 
-impl From<String> for StackError {
-    fn from(s: String) -> Self {
-        StackError::MemoryAccess(s)
+impl From<MemoryError> for StackError {
+    fn from(e: MemoryError) -> Self {
+        StackError::MemoryAccess(e)
+    }
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::Overflow => write!(f, "stack overflow"),
+            StackError::Underflow => write!(f, "stack underflow"),
+            StackError::MemoryAccess(e) => write!(f, "stack access failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StackError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StackError::MemoryAccess(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
@@ -31,6 +52,7 @@ impl Cpu {
             sp: initial_sp_val,
             fp: initial_sp_val, // Typically FP is initialized like SP
             initial_sp: initial_sp_val,
+            stack_limit: memory.stack_limit(),
         }
     }
 
@@ -40,8 +62,7 @@ impl Cpu {
         }
         self.sp -= 8;
 
-        let header = memory.header(); // Re-borrow header immutably after mutable borrow for write_word
-        if self.sp < header.dynamic_data_section_start {
+        if self.sp < self.stack_limit {
             self.sp += 8; // Revert SP change before erroring
             return Err(StackError::Overflow);
         }
@@ -54,28 +75,44 @@ impl Cpu {
         }
         // No need to check against dynamic_data_section_start + length for pop if initial_sp is the absolute top.
         // SP must be < initial_sp to be valid for pop.
-        // If sp is, for example, header.dynamic_data_section_start, it's a valid address to read from.
+        // If sp is, for example, stack_limit, it's a valid address to read from.
 
         let value = memory.read_word(self.sp).map_err(StackError::from)?;
         self.sp += 8;
         Ok(value)
     }
+
+    /// Reads the top of the stack without popping it.
+    pub fn peek_value(&self, memory: &Memory) -> Result<u64, StackError> {
+        if self.sp >= self.initial_sp {
+            return Err(StackError::Underflow);
+        }
+        memory.read_word(self.sp).map_err(StackError::from)
+    }
+
+    /// Overwrites the top of the stack in place.
+    pub fn replace_top_value(&mut self, value: u64, memory: &mut Memory) -> Result<(), StackError> {
+        if self.sp >= self.initial_sp {
+            return Err(StackError::Underflow);
+        }
+        memory.write_word(self.sp, value).map_err(StackError::from)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::memory::Memory; // For creating a Memory instance
+    use crate::header::StoryHeader;
     use crate::header::create_dummy_header_bytes; // Test utility
 
     fn create_test_memory() -> Memory {
         let mut story_data = create_dummy_header_bytes(); // 1024 bytes
         // Configure header for a small dynamic section for stack
-        // Default dummy header has dynamic_data_section_start = 1408, length = 64
-        // So, dynamic section is 1408 to 1471. Stack grows downwards from 1472.
-        // Let's make memory large enough for this.
-        // Header points to sections up to ~1538. Let's make memory 2048 bytes.
-        story_data.resize(2048, 0xDA);
+        // Default dummy header has dynamic_data_section_start = 3328, length = 2048
+        // So, dynamic section is 3328 to 5375: globals take the first 1920 bytes
+        // and the stack grows downwards from 5376 to 5248.
+        // Memory allocates the dynamic section itself; the file only needs code + static.
+        story_data.resize(3328, 0xDA);
         Memory::new(story_data).expect("Failed to create test memory")
     }
 
@@ -95,6 +132,7 @@ mod tests {
         assert_eq!(cpu.sp, expected_sp);
         assert_eq!(cpu.fp, expected_sp);
         assert_eq!(cpu.initial_sp, expected_sp);
+        assert_eq!(cpu.stack_limit, memory.stack_limit());
     }
 
     #[test]
@@ -123,6 +161,24 @@ mod tests {
         assert_eq!(cpu.sp, cpu.initial_sp);
     }
 
+    #[test]
+    fn test_peek_and_replace_top_value() {
+        let mut memory = create_test_memory();
+        let mut cpu = Cpu::new(&memory);
+
+        assert_eq!(cpu.peek_value(&memory), Err(StackError::Underflow));
+        assert_eq!(cpu.replace_top_value(1, &mut memory), Err(StackError::Underflow));
+
+        cpu.push_value(10, &mut memory).unwrap();
+        cpu.push_value(20, &mut memory).unwrap();
+        let sp = cpu.sp;
+        assert_eq!(cpu.peek_value(&memory), Ok(20));
+        cpu.replace_top_value(25, &mut memory).unwrap();
+        assert_eq!(cpu.sp, sp);
+        assert_eq!(cpu.pop_value(&memory), Ok(25));
+        assert_eq!(cpu.pop_value(&memory), Ok(10));
+    }
+
     #[test]
     fn test_stack_underflow() {
         let memory = create_test_memory();
@@ -133,15 +189,16 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_stack_overflow() {
         let mut memory = create_test_memory();
         let mut cpu = Cpu::new(&memory);
-        let dynamic_data_start = memory.header().dynamic_data_section_start; // Extract value
+        let stack_limit = cpu.stack_limit; // Extract value
         let initial_sp_val = cpu.initial_sp; // Extract value
 
         // Calculate how many items can be pushed onto the stack
-        // Stack grows from initial_sp downwards to dynamic_data_start
-        let stack_capacity_bytes = initial_sp_val - dynamic_data_start;
+        // Stack grows from initial_sp downwards to stack_limit
+        let stack_capacity_bytes = initial_sp_val - stack_limit;
         let stack_capacity_items = stack_capacity_bytes / 8;
 
         for i in 0..stack_capacity_items {
@@ -152,22 +209,23 @@ mod tests {
         let result = cpu.push_value(999, &mut memory);
         assert_eq!(result, Err(StackError::Overflow));
 
-        // SP should be at dynamic_data_section_start after filling capacity
-        assert_eq!(cpu.sp, dynamic_data_start);
+        // SP should be at stack_limit after filling capacity
+        assert_eq!(cpu.sp, stack_limit);
 
         // Popping one value should now be possible
         cpu.pop_value(&memory).unwrap();
-        assert_eq!(cpu.sp, dynamic_data_start + 8);
+        assert_eq!(cpu.sp, stack_limit + 8);
     }
      #[test]
+    #[allow(clippy::unnecessary_cast)]
     fn test_stack_push_revert_sp_on_overflow() {
         let mut memory = create_test_memory();
         let mut cpu = Cpu::new(&memory);
-        let dynamic_data_start = memory.header().dynamic_data_section_start; // Extract value
+        let stack_limit = cpu.stack_limit; // Extract value
         let initial_sp_val = cpu.initial_sp; // Extract value
 
 
-        let stack_capacity_bytes = initial_sp_val - dynamic_data_start;
+        let stack_capacity_bytes = initial_sp_val - stack_limit;
         let stack_capacity_items = stack_capacity_bytes / 8;
 
         for i in 0..stack_capacity_items {
@@ -175,7 +233,7 @@ mod tests {
         }
 
         let sp_before_overflow_attempt = cpu.sp;
-        assert_eq!(sp_before_overflow_attempt, dynamic_data_start);
+        assert_eq!(sp_before_overflow_attempt, stack_limit);
 
         let result = cpu.push_value(999, &mut memory); // This should overflow
         assert_eq!(result, Err(StackError::Overflow));
@@ -0,0 +1,284 @@
+// zm2_vm/src/dictionary.rs
+
+//! The dictionary (spec section g; Z-Machine Standard 1.1, section 13).
+//!
+//! A dictionary starts with a byte giving the number of word separators,
+//! followed by the separators as ZSCII codes. Then comes a byte giving the
+//! length of each entry and a 64-bit entry count, and then the entries. Each
+//! entry starts with the word encoded in `text::DICTIONARY_WORD_BYTES` bytes;
+//! the rest of the entry is game data.
+//!
+//! A positive count means the entries are sorted by their encoded words,
+//! compared as numbers, and are looked up with a binary search. A negative
+//! count means `-count` entries in no particular order, which are searched
+//! one by one. Stories mostly use the unsorted form for dictionaries built at
+//! run time and passed to `tokenise`.
+
+use crate::memory::Memory;
+use crate::text::{self, TextError};
+use crate::MemoryError;
+use std::fmt;
+
+/// Bytes in the entry count that follows the entry length.
+const COUNT_SIZE: u64 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DictionaryError {
+    /// The dictionary at `dictionary` declares entries shorter than an encoded word.
+    InvalidEntryLength { dictionary: u64, entry_length: u64 },
+    Memory(MemoryError),
+    /// Decoding an entry's word failed.
+    Text(TextError),
+}
+
+impl fmt::Display for DictionaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DictionaryError::InvalidEntryLength { dictionary, entry_length } => write!(
+                f,
+                "dictionary 0x{:X} has {}-byte entries, shorter than an encoded word",
+                dictionary, entry_length
+            ),
+            DictionaryError::Memory(e) => write!(f, "reading the dictionary failed: {}", e),
+            DictionaryError::Text(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DictionaryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DictionaryError::Memory(e) => Some(e),
+            DictionaryError::Text(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MemoryError> for DictionaryError {
+    fn from(e: MemoryError) -> Self {
+        DictionaryError::Memory(e)
+    }
+}
+
+impl From<TextError> for DictionaryError {
+    fn from(e: TextError) -> Self {
+        DictionaryError::Text(e)
+    }
+}
+
+/// A view of a dictionary in memory. The header is read once; entries are
+/// read from memory on each lookup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dictionary {
+    address: u64,
+    separators: Vec<u8>,
+    entry_length: u64,
+    entries_start: u64,
+    count: u64,
+    sorted: bool,
+}
+
+impl Dictionary {
+    /// Reads the header of the dictionary at `address` and checks that its
+    /// entries lie in memory.
+    pub fn new(memory: &Memory, address: u64) -> Result<Dictionary, DictionaryError> {
+        let separator_count = memory.read_byte(address)? as u64;
+        let separators = memory.read_slice(address + 1, separator_count)?.to_vec();
+        let entry_length = memory.read_byte(address + 1 + separator_count)? as u64;
+        if entry_length < text::DICTIONARY_WORD_BYTES as u64 {
+            return Err(DictionaryError::InvalidEntryLength { dictionary: address, entry_length });
+        }
+        let count = memory.read_word(address + 2 + separator_count)? as i64;
+        let entries_start = address + 2 + separator_count + COUNT_SIZE;
+        let dictionary = Dictionary {
+            address,
+            separators,
+            entry_length,
+            entries_start,
+            count: count.unsigned_abs(),
+            sorted: count >= 0,
+        };
+        let len = dictionary.count.checked_mul(entry_length)
+            .ok_or(MemoryError::OutOfBounds { address: entries_start, len: u64::MAX })?;
+        memory.read_slice(entries_start, len)?;
+        Ok(dictionary)
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// The word separators, as ZSCII codes.
+    pub fn separators(&self) -> &[u8] {
+        &self.separators
+    }
+
+    /// Size in bytes of each entry, including the encoded word.
+    pub fn entry_length(&self) -> u64 {
+        self.entry_length
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Whether the entries are sorted (the count is not negative).
+    pub fn is_sorted(&self) -> bool {
+        self.sorted
+    }
+
+    /// Address of entry `index` (0-based).
+    pub fn entry_address(&self, index: u64) -> u64 {
+        self.entries_start + index * self.entry_length
+    }
+
+    fn encoded_word<'m>(&self, memory: &'m Memory, index: u64) -> Result<&'m [u8], MemoryError> {
+        memory.read_slice(self.entry_address(index), text::DICTIONARY_WORD_BYTES as u64)
+    }
+
+    /// The address of the entry for the ZSCII word `word`, comparing the
+    /// first `text::DICTIONARY_WORD_BYTES` bytes of its encoding, or `None`.
+    pub fn lookup(&self, memory: &Memory, word: &[u16]) -> Result<Option<u64>, MemoryError> {
+        let key = text::encode_dictionary_word(word);
+        if self.sorted {
+            let (mut low, mut high) = (0, self.count);
+            while low < high {
+                let middle = low + (high - low) / 2;
+                match self.encoded_word(memory, middle)?.cmp(&key[..]) {
+                    std::cmp::Ordering::Less => low = middle + 1,
+                    std::cmp::Ordering::Greater => high = middle,
+                    std::cmp::Ordering::Equal => return Ok(Some(self.entry_address(middle))),
+                }
+            }
+        } else {
+            for index in 0..self.count {
+                if self.encoded_word(memory, index)? == key {
+                    return Ok(Some(self.entry_address(index)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The address of the entry for `word`, after lower-casing it.
+    pub fn lookup_str(&self, memory: &Memory, word: &str) -> Result<Option<u64>, MemoryError> {
+        self.lookup(memory, &text::to_zscii(&word.to_lowercase()))
+    }
+
+    /// Every entry's address and decoded word, in table order.
+    pub fn words(&self, memory: &Memory) -> Result<Vec<(u64, String)>, DictionaryError> {
+        (0..self.count)
+            .map(|index| {
+                let address = self.entry_address(index);
+                Ok((address, text::decode_string(memory, address)?.0))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::create_dummy_header_bytes;
+
+    const DICTIONARY: u64 = 2000;
+
+    /// Memory with a dictionary of `words` at `DICTIONARY`, in the order
+    /// given, with 8-byte entries whose data is the word's index.
+    fn memory_with_dictionary(separators: &[u8], words: &[&str], count: i64) -> Memory {
+        let mut story_data = create_dummy_header_bytes();
+        story_data.resize(3328, 0);
+        let mut dictionary = vec![separators.len() as u8];
+        dictionary.extend_from_slice(separators);
+        dictionary.push(8);
+        dictionary.extend_from_slice(&count.to_be_bytes());
+        for (i, word) in words.iter().enumerate() {
+            dictionary.extend_from_slice(&text::encode_dictionary_word(&text::to_zscii(word)));
+            dictionary.extend_from_slice(&(i as u16).to_be_bytes());
+        }
+        let start = DICTIONARY as usize;
+        story_data[start..start + dictionary.len()].copy_from_slice(&dictionary);
+        Memory::new(story_data).unwrap()
+    }
+
+    /// `words` sorted by their encodings, as a sorted dictionary needs.
+    fn sorted(words: &[&'static str]) -> Vec<&'static str> {
+        let mut words = words.to_vec();
+        words.sort_by_key(|w| text::encode_dictionary_word(&text::to_zscii(w)));
+        words
+    }
+
+    const WORDS: [&str; 7] = ["north", "south", "lamp", "take", "xyzzy", "a", "brass"];
+
+    #[test]
+    fn test_header() {
+        let memory = memory_with_dictionary(b".,\"", &WORDS, -7);
+        let dictionary = Dictionary::new(&memory, DICTIONARY).unwrap();
+        assert_eq!(dictionary.address(), DICTIONARY);
+        assert_eq!(dictionary.separators(), b".,\"");
+        assert_eq!(dictionary.entry_length(), 8);
+        assert_eq!(dictionary.len(), 7);
+        assert!(!dictionary.is_sorted());
+        assert_eq!(dictionary.entry_address(2), DICTIONARY + 13 + 16);
+    }
+
+    #[test]
+    fn test_sorted_lookup() {
+        let words = sorted(&WORDS);
+        let memory = memory_with_dictionary(&[], &words, words.len() as i64);
+        let dictionary = Dictionary::new(&memory, DICTIONARY).unwrap();
+        assert!(dictionary.is_sorted());
+        for (i, word) in words.iter().enumerate() {
+            assert_eq!(dictionary.lookup_str(&memory, word), Ok(Some(dictionary.entry_address(i as u64))), "{}", word);
+        }
+        assert_eq!(dictionary.lookup_str(&memory, "LAMP"), Ok(Some(dictionary.entry_address(words.iter().position(|&w| w == "lamp").unwrap() as u64))));
+        assert_eq!(dictionary.lookup_str(&memory, "lantern"), Ok(None));
+        assert_eq!(dictionary.lookup_str(&memory, "zzz"), Ok(None));
+        assert_eq!(dictionary.lookup_str(&memory, ""), Ok(None));
+    }
+
+    #[test]
+    fn test_unsorted_lookup() {
+        let memory = memory_with_dictionary(&[], &WORDS, -(WORDS.len() as i64));
+        let dictionary = Dictionary::new(&memory, DICTIONARY).unwrap();
+        for (i, word) in WORDS.iter().enumerate() {
+            assert_eq!(dictionary.lookup_str(&memory, word), Ok(Some(dictionary.entry_address(i as u64))), "{}", word);
+        }
+        assert_eq!(dictionary.lookup_str(&memory, "west"), Ok(None));
+    }
+
+    #[test]
+    fn test_words() {
+        let memory = memory_with_dictionary(&[], &["lamp", "go", "screwdriver"], -3);
+        let dictionary = Dictionary::new(&memory, DICTIONARY).unwrap();
+        let words: Vec<String> = dictionary.words(&memory).unwrap().into_iter().map(|(_, w)| w).collect();
+        assert_eq!(words, ["lamp", "go", "screwdriv"]);
+        assert_eq!(dictionary.words(&memory).unwrap()[1].0, dictionary.entry_address(1));
+        // Only the first nine Z-characters take part.
+        assert_eq!(dictionary.lookup_str(&memory, "screwdriving"), Ok(Some(dictionary.entry_address(2))));
+
+        let empty = memory_with_dictionary(&[], &[], 0);
+        let dictionary = Dictionary::new(&empty, DICTIONARY).unwrap();
+        assert!(dictionary.is_empty());
+        assert_eq!(dictionary.lookup_str(&empty, "lamp"), Ok(None));
+    }
+
+    #[test]
+    fn test_invalid_dictionaries() {
+        let mut memory = memory_with_dictionary(&[], &["lamp"], 1);
+        memory.write_privileged(DICTIONARY + 1, &[4]).unwrap();
+        assert_eq!(
+            Dictionary::new(&memory, DICTIONARY),
+            Err(DictionaryError::InvalidEntryLength { dictionary: DICTIONARY, entry_length: 4 })
+        );
+
+        let memory = memory_with_dictionary(&[], &["lamp"], i64::MAX);
+        assert!(matches!(Dictionary::new(&memory, DICTIONARY), Err(DictionaryError::Memory(MemoryError::OutOfBounds { .. }))));
+    }
+}
@@ -0,0 +1,289 @@
+// zm2_vm/src/error.rs
+
+//! Error types returned by the VM.
+//!
+//! `MemoryError` and `cpu::StackError` describe failures of a single memory or
+//! stack access. `VmError` covers everything that can stop an instruction and,
+//! once `run` has seen it, wraps the failure in `VmError::Execution` with the
+//! faulting PC and opcode. The underlying error stays reachable through
+//! `std::error::Error::source`.
+
+use crate::cpu::StackError;
+use crate::dictionary::DictionaryError;
+use crate::header::{HeaderError, LayoutError};
+use crate::memory::Region;
+use crate::object::ObjectError;
+use crate::output::OutputError;
+use crate::screen::ScreenError;
+use crate::text::TextError;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum StoryFileError {
+    Io(std::io::ErrorKind),
+    /// The header is too short or uses the legacy pre-spec layout.
+    InvalidHeader(HeaderError),
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    /// A code, static or dynamic section does not fit in memory.
+    SectionTooLarge(LayoutError),
+    /// The header describes an invalid layout; one entry per problem.
+    InvalidLayout(Vec<LayoutError>),
+}
+
+impl From<std::io::Error> for StoryFileError {
+    fn from(err: std::io::Error) -> Self {
+        StoryFileError::Io(err.kind())
+    }
+}
+
+impl From<HeaderError> for StoryFileError {
+    fn from(err: HeaderError) -> Self {
+        match err {
+            HeaderError::UnsupportedVersion(version) => StoryFileError::UnsupportedVersion(version),
+            other => StoryFileError::InvalidHeader(other),
+        }
+    }
+}
+
+impl fmt::Display for StoryFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoryFileError::Io(kind) => write!(f, "failed to read story file: {}", kind),
+            StoryFileError::InvalidHeader(e) => write!(f, "invalid story header: {}", e),
+            StoryFileError::UnsupportedVersion(v) => write!(f, "unsupported story version 0x{:04X}", v),
+            StoryFileError::ChecksumMismatch => write!(f, "story file checksum does not match the header"),
+            StoryFileError::SectionTooLarge(e) => write!(f, "section too large: {}", e),
+            StoryFileError::InvalidLayout(errors) => {
+                write!(f, "invalid story layout")?;
+                for (i, e) in errors.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { ":" } else { ";" }, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for StoryFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StoryFileError::InvalidHeader(e) => Some(e),
+            StoryFileError::SectionTooLarge(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryError {
+    /// An access of `len` bytes at `address` runs past the end of memory.
+    OutOfBounds { address: u64, len: u64 },
+    /// A write hit a read-only region (header, code, or static data unless enabled).
+    WriteProtected { address: u64, region: Region },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryError::OutOfBounds { address, len } => {
+                write!(f, "{}-byte access at 0x{:X} is out of bounds", len, address)
+            }
+            MemoryError::WriteProtected { address, region } => {
+                write!(f, "write to 0x{:X} in protected region {:?}", address, region)
+            }
+        }
+    }
+}
+
+impl Error for MemoryError {}
+
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+    /// A memory access made by the instruction failed.
+    Memory(MemoryError),
+    /// A stack push or pop failed.
+    Stack(StackError),
+    /// An operand type byte is not LC, SC, VAR or PADDR.
+    InvalidOperandType(u8),
+    /// An operand has a valid type, but not the one the opcode requires.
+    UnexpectedOperandType { expected: u8, found: u8 },
+    /// A variable-length operand list holds more than `max` operands.
+    TooManyOperands { max: usize },
+    /// A variable-length operand list holds fewer than `min` operands.
+    TooFewOperands { min: usize },
+    UnknownOpcode(u64),
+    /// A call's packed address does not point into the code section.
+    InvalidRoutineAddress(u64),
+    /// The routine at packed address `routine` declares more than the 15
+    /// locals (L00-L14) a routine can have.
+    TooManyLocals { routine: u64, num_locals: u8 },
+    /// `check_arg_count` was asked about argument 0 (arguments are 1-based).
+    InvalidArgumentNumber(u64),
+    /// A local variable that the current routine does not have (`local` is 0-based).
+    InvalidVariable { local: u8, num_locals: u64 },
+    /// `div` or `mod` by zero with the header's DivByZeroHalt flag set, or
+    /// without `LoadOptions::div_by_zero_returns_zero`.
+    DivisionByZero,
+    /// A loadw/loadb/storew/storeb on element `index` of the table at `array` failed.
+    ArrayAccess { array: u64, index: u64, error: MemoryError },
+    /// An object opcode named an invalid object or found the tree inconsistent.
+    Object(ObjectError),
+    /// Decoding a Z-encoded string failed.
+    Text(TextError),
+    /// A dictionary is malformed or could not be read.
+    Dictionary(DictionaryError),
+    /// Selecting an output stream or printing to one failed.
+    Output(OutputError),
+    /// A window opcode named an invalid window or position.
+    Screen(ScreenError),
+    /// The input source has no more input to give.
+    EndOfInput,
+    /// A StringPADDR does not point into the static data section.
+    InvalidStringAddress(u64),
+    /// A branch or jump would move the PC below address 0.
+    InvalidJumpTarget(i64),
+    /// `error` was raised by `opcode`, fetched from `pc`. `opcode` is `None`
+    /// if the opcode itself could not be fetched.
+    Execution { pc: u64, opcode: Option<u64>, error: Box<VmError> },
+}
+
+impl VmError {
+    /// The PC of the faulting instruction, if the error carries one.
+    pub fn pc(&self) -> Option<u64> {
+        match self {
+            VmError::Execution { pc, .. } => Some(*pc),
+            _ => None,
+        }
+    }
+
+    /// The faulting opcode, if the error carries one.
+    pub fn opcode(&self) -> Option<u64> {
+        match self {
+            VmError::Execution { opcode, .. } => *opcode,
+            _ => None,
+        }
+    }
+
+    /// The error without its execution context.
+    pub fn kind(&self) -> &VmError {
+        match self {
+            VmError::Execution { error, .. } => error.kind(),
+            other => other,
+        }
+    }
+
+    pub(crate) fn at(self, pc: u64, opcode: Option<u64>) -> VmError {
+        VmError::Execution { pc, opcode, error: Box::new(self) }
+    }
+}
+
+impl From<MemoryError> for VmError {
+    fn from(e: MemoryError) -> Self {
+        VmError::Memory(e)
+    }
+}
+
+impl From<StackError> for VmError {
+    fn from(e: StackError) -> Self {
+        VmError::Stack(e)
+    }
+}
+
+impl From<ObjectError> for VmError {
+    fn from(e: ObjectError) -> Self {
+        VmError::Object(e)
+    }
+}
+
+impl From<TextError> for VmError {
+    fn from(e: TextError) -> Self {
+        VmError::Text(e)
+    }
+}
+
+impl From<DictionaryError> for VmError {
+    fn from(e: DictionaryError) -> Self {
+        VmError::Dictionary(e)
+    }
+}
+
+impl From<OutputError> for VmError {
+    fn from(e: OutputError) -> Self {
+        VmError::Output(e)
+    }
+}
+
+impl From<ScreenError> for VmError {
+    fn from(e: ScreenError) -> Self {
+        VmError::Screen(e)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Memory(e) => write!(f, "{}", e),
+            VmError::Stack(e) => write!(f, "{}", e),
+            VmError::InvalidOperandType(t) => write!(f, "invalid operand type 0x{:02X}", t),
+            VmError::UnexpectedOperandType { expected, found } => {
+                write!(f, "expected operand type 0x{:02X}, found 0x{:02X}", expected, found)
+            }
+            VmError::TooManyOperands { max } => write!(f, "more than {} operands in operand list", max),
+            VmError::TooFewOperands { min } => write!(f, "fewer than {} operands in operand list", min),
+            VmError::UnknownOpcode(op) => write!(f, "unknown opcode 0x{:04X}", op),
+            VmError::InvalidRoutineAddress(paddr) => {
+                write!(f, "packed routine address 0x{:X} is outside the code section", paddr)
+            }
+            VmError::TooManyLocals { routine, num_locals } => write!(
+                f,
+                "routine at packed address 0x{:X} declares {} locals (at most 15)",
+                routine, num_locals
+            ),
+            VmError::InvalidArgumentNumber(n) => write!(f, "invalid argument number {}", n),
+            VmError::InvalidVariable { local, num_locals } => write!(
+                f,
+                "local variable L{:02} does not exist (routine has {} locals)",
+                local, num_locals
+            ),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+            VmError::ArrayAccess { array, index, .. } => {
+                write!(f, "table access at index {} of array 0x{:X} failed", index, array)
+            }
+            VmError::Object(e) => write!(f, "{}", e),
+            VmError::Text(e) => write!(f, "{}", e),
+            VmError::Dictionary(e) => write!(f, "{}", e),
+            VmError::Output(e) => write!(f, "{}", e),
+            VmError::Screen(e) => write!(f, "{}", e),
+            VmError::EndOfInput => write!(f, "the input source has run out of input"),
+            VmError::InvalidStringAddress(paddr) => {
+                write!(f, "packed string address 0x{:X} is outside the static data section", paddr)
+            }
+            VmError::InvalidJumpTarget(target) => write!(f, "jump to negative address {}", target),
+            VmError::Execution { pc, opcode: Some(op), error } => {
+                write!(f, "opcode 0x{:04X} at PC 0x{:X}: {}", op, pc, error)
+            }
+            VmError::Execution { pc, opcode: None, error } => {
+                write!(f, "fetching opcode at PC 0x{:X}: {}", pc, error)
+            }
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Memory(e) => Some(e),
+            VmError::Stack(e) => Some(e),
+            VmError::ArrayAccess { error, .. } => Some(error),
+            VmError::Object(e) => Some(e),
+            VmError::Text(e) => Some(e),
+            VmError::Dictionary(e) => Some(e),
+            VmError::Output(e) => Some(e),
+            VmError::Screen(e) => Some(e),
+            VmError::Execution { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
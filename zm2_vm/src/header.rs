@@ -1,7 +1,182 @@
-use byteorder::{BigEndian, ReadBytesExt};
-use std::io::{Cursor, Read}; // Added std::io::Read
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
 
-#[derive(Debug, PartialEq)]
+use crate::memory::{MAX_MEMORY_SIZE, SUPPORTED_VERSION};
+
+/// Size of the story file header in bytes.
+pub const HEADER_SIZE: usize = 1024;
+
+/// Offset of the first byte covered by the story checksum (the byte
+/// immediately following the 8-byte `checksum` field at offset 12).
+pub const CHECKSUM_START_OFFSET: usize = 20;
+
+/// Size of the trailing reserved block (offset 164 to the end of the header).
+/// The spec reserves from offset 156; the first 8 of those bytes hold the
+/// `terminating_characters_table_start` extension.
+pub const HEADER_RESERVED_LENGTH: usize = 860;
+
+// flags1 bits (Header offset 100, 4 bytes)
+pub const FLAGS1_TRANSCRIPTING: u32 = 1 << 0;
+pub const FLAGS1_FIXED_PITCH_FONT: u32 = 1 << 1;
+pub const FLAGS1_STRICT_ZSCII_COMPAT_MODE: u32 = 1 << 2;
+pub const FLAGS1_DEBUG_MODE: u32 = 1 << 3;
+pub const FLAGS1_LLM_PARSE_ENABLE: u32 = 1 << 4;
+pub const FLAGS1_LLM_GENERATE_ENABLE: u32 = 1 << 5;
+pub const FLAGS1_DIV_BY_ZERO_HALT: u32 = 1 << 6;
+pub const FLAGS1_SAVE_LOAD_ENABLE: u32 = 1 << 7;
+/// Bits 8-31 of flags1 are reserved and must be 0.
+pub const FLAGS1_RESERVED_MASK: u32 = 0xFFFF_FF00;
+
+// flags2 bits (Header offset 104, 4 bytes)
+pub const FLAGS2_FORCE_LLM_SYNC: u32 = 1 << 0;
+pub const FLAGS2_ENABLE_EXTENDED_OPCODES: u32 = 1 << 1;
+pub const FLAGS2_BYPASS_LLM_MODERATION: u32 = 1 << 2;
+/// Bits 3-31 of flags2 are reserved and must be 0.
+pub const FLAGS2_RESERVED_MASK: u32 = 0xFFFF_FFF8;
+
+/// Number of global variables (G00-G239).
+pub const NUM_GLOBALS: u64 = 240;
+
+/// Size in bytes of the global variables table (240 64-bit values).
+pub const GLOBALS_TABLE_SIZE: u64 = NUM_GLOBALS * 8;
+
+/// Why a header field failed layout validation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutProblem {
+    /// `start + length` does not fit in a 64-bit address.
+    AddressOverflow,
+    /// The range extends beyond the end of memory.
+    ExceedsMemory { memory_size: u64 },
+    /// A section that must be loaded from the story file extends past its end.
+    ExceedsStoryFile { file_size: u64 },
+    /// The section is shorter than the VM requires.
+    TooSmall { minimum_length: u64 },
+    /// The range starts inside the 1024-byte header.
+    OverlapsHeader,
+    /// The section starts before the end of the section that must precede it
+    /// (the spec orders them header, code, static data, dynamic data).
+    OverlapsSection { previous: &'static str, previous_end: u64 },
+    /// A table or pointer lies outside the section that must contain it.
+    OutsideSection { section: &'static str, section_start: u64, section_end: u64 },
+}
+
+/// A single problem found by `StoryHeader::validate_layout`: which header
+/// field, the byte range it describes (`start..end`) and why it is invalid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutError {
+    pub field: &'static str,
+    pub start: u64,
+    pub end: u64,
+    pub problem: LayoutProblem,
+}
+
+impl std::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (0x{:X}..0x{:X}): ", self.field, self.start, self.end)?;
+        match &self.problem {
+            LayoutProblem::AddressOverflow => write!(f, "start + length overflows a 64-bit address"),
+            LayoutProblem::ExceedsMemory { memory_size } => {
+                write!(f, "extends beyond memory size 0x{:X}", memory_size)
+            }
+            LayoutProblem::ExceedsStoryFile { file_size } => {
+                write!(f, "extends beyond the end of the story file (0x{:X} bytes)", file_size)
+            }
+            LayoutProblem::TooSmall { minimum_length } => {
+                write!(f, "is shorter than the required 0x{:X} bytes", minimum_length)
+            }
+            LayoutProblem::OverlapsHeader => write!(f, "overlaps the 1024-byte header"),
+            LayoutProblem::OverlapsSection { previous, previous_end } => write!(
+                f,
+                "starts before the end of {} at 0x{:X}",
+                previous, previous_end
+            ),
+            LayoutProblem::OutsideSection { section, section_start, section_end } => write!(
+                f,
+                "lies outside {} (0x{:X}..0x{:X})",
+                section, section_start, section_end
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+/// Why a story's header could not be read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderError {
+    /// The data is shorter than the 1024-byte header.
+    TooShort { len: usize },
+    /// The header's version is not `SUPPORTED_VERSION`.
+    UnsupportedVersion(u16),
+    /// Reserved flag bits or reserved header bytes are set, as they are in
+    /// story files built for the pre-spec header layout.
+    LegacyLayout { flags1: u32, flags2: u32 },
+    /// A header being written has reserved flag bits or reserved bytes set,
+    /// so `StoryHeader::from_bytes` would reject it as `LegacyLayout`.
+    ReservedFieldsSet { flags1: u32, flags2: u32 },
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::TooShort { len } => {
+                write!(f, "header data too short: expected {} bytes, got {}", HEADER_SIZE, len)
+            }
+            HeaderError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported Z-machine version 0x{:04X} (expected 0x{:04X})",
+                version, SUPPORTED_VERSION
+            ),
+            HeaderError::LegacyLayout { flags1, flags2 } => write!(
+                f,
+                "reserved header bits are set (flags1=0x{:08X}, flags2=0x{:08X}); the story file appears \
+                 to use the legacy pre-spec header layout and must be recompiled",
+                flags1, flags2
+            ),
+            HeaderError::ReservedFieldsSet { flags1, flags2 } => write!(
+                f,
+                "cannot write a header with reserved bits or bytes set (flags1=0x{:08X}, flags2=0x{:08X})",
+                flags1, flags2
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// The story file header, laid out as in Section 2 of the design spec, with
+/// one deliberate extension: `terminating_characters_table_start` at offset
+/// 156 takes the first 8 bytes of the spec's reserved block. The spec gives
+/// `aread` no way to find its terminating characters table, and a story that
+/// leaves those bytes zeroed, as the spec requires, simply has no table.
+///
+/// | Offset | Size | Field                                            |
+/// |--------|------|--------------------------------------------------|
+/// | 0      | 2    | `version`                                        |
+/// | 2      | 2    | `release_number`                                 |
+/// | 4      | 8    | `story_id`                                       |
+/// | 12     | 8    | `checksum`                                       |
+/// | 20     | 8    | `code_section_start`                             |
+/// | 28     | 8    | `code_section_length`                            |
+/// | 36     | 8    | `static_data_section_start`                      |
+/// | 44     | 8    | `static_data_section_length`                     |
+/// | 52     | 8    | `dynamic_data_section_start`                     |
+/// | 60     | 8    | `dynamic_data_section_length`                    |
+/// | 68     | 8    | `globals_table_start`                            |
+/// | 76     | 8    | `objects_table_start`                            |
+/// | 84     | 8    | `dictionary_table_start`                         |
+/// | 92     | 8    | `abbreviations_table_start`                      |
+/// | 100    | 4    | `flags1`                                         |
+/// | 104    | 4    | `flags2`                                         |
+/// | 108    | 8    | `llm_api_endpoint_ptr`                           |
+/// | 116    | 8    | `llm_parameters_ptr`                             |
+/// | 124    | 8    | `context_globals_list_ptr`                       |
+/// | 132    | 8    | `recent_events_buffer_ptr`                       |
+/// | 140    | 8    | `recent_events_count_ptr`                        |
+/// | 148    | 8    | `property_defaults_table_start`                  |
+/// | 156    | 8    | `terminating_characters_table_start` (extension) |
+/// | 164    | 860  | `reserved`                                       |
+#[derive(Debug, PartialEq, Clone)]
 pub struct StoryHeader {
     pub version: u16,
     pub release_number: u16,
@@ -14,91 +189,29 @@ pub struct StoryHeader {
     pub dynamic_data_section_start: u64,
     pub dynamic_data_section_length: u64,
     pub globals_table_start: u64,
-    pub globals_table_length: u64,
-    pub abbreviations_table_start: u64,
-    pub abbreviations_table_length: u64,
     pub objects_table_start: u64,
-    pub objects_table_length: u64,
-    pub properties_table_start: u64,
-    pub properties_table_length: u64,
-    pub attributes_table_start: u64,
-    pub attributes_table_length: u64,
-    pub string_table_start: u64,
-    pub string_table_length: u64,
     pub dictionary_table_start: u64,
-    pub dictionary_table_length: u64,
-    pub system_functions_table_start: u64,
-    pub system_functions_table_length: u64,
-    pub opcodes_table_start: u64,
-    pub opcodes_table_length: u64,
-    pub flags: u64,
-    pub reserved1: u64,
-    pub reserved2: u64,
-    pub reserved3: u64,
-    // The following 32 fields are 8 bytes each, total 256 bytes
-    pub reserved_block: [u64; 32], // 32 * 8 = 256 bytes
-    // Padding to 1024 bytes.
-    // Total size of fields above:
-    // version (2) + release_number (2) = 4
-    // story_id (8) + checksum (8) = 16
-    // code_section_start (8) ... opcodes_table_length (8) = 24 fields * 8 bytes/field = 192
-    // flags (8) + reserved1 (8) + reserved2 (8) + reserved3 (8) = 32
-    // reserved_block (256)
-    // Current total: 4 + 16 + 192 + 32 + 256 = 500 bytes.
-    // Remaining padding: 1024 - 500 = 524 bytes.
-    // This means 524 / 8 = 65.5 u64 fields, which is not right.
-    // Let's re-check ZMACHINE_MARK_2_DESIGN_SPEC.md for the exact layout.
-
-    // Section 2: Memory Model -> Header Structure
-    // Version: 2 bytes
-    // Release Number: 2 bytes
-    // Story ID: 8 bytes
-    // Checksum: 8 bytes
-    // Code Section Start Address: 8 bytes
-    // Code Section Length: 8 bytes
-    // Static Data Section Start Address: 8 bytes
-    // Static Data Section Length: 8 bytes
-    // Dynamic Data Section Start Address: 8 bytes
-    // Dynamic Data Section Length: 8 bytes
-    // Globals Table Start Address: 8 bytes
-    // Globals Table Length: 8 bytes
-    // Abbreviations Table Start Address: 8 bytes
-    // Abbreviations Table Length: 8 bytes
-    // Objects Table Start Address: 8 bytes
-    // Objects Table Length: 8 bytes
-    // Properties Table Start Address: 8 bytes
-    // Properties Table Length: 8 bytes
-    // Attributes Table Start Address: 8 bytes
-    // Attributes Table Length: 8 bytes
-    // String Table Start Address: 8 bytes
-    // String Table Length: 8 bytes
-    // Dictionary Table Start Address: 8 bytes
-    // Dictionary Table Length: 8 bytes
-    // System Functions Table Start Address: 8 bytes
-    // System Functions Table Length: 8 bytes
-    // Opcodes Table Start Address: 8 bytes
-    // Opcodes Table Length: 8 bytes
-    // Flags: 8 bytes (e.g., transcripting, fixed-pitch font, screen refresh required)
-    // Reserved 1: 8 bytes
-    // Reserved 2: 8 bytes
-    // Reserved 3: 8 bytes
-    // Reserved Block (256 bytes): This is likely an array of 32 u64s.
-    // Total so far: 2+2+8+8 + (24 * 8) + (4*8) + (32*8) = 4 + 16 + 192 + 32 + 256 = 500 bytes.
-
-    // The spec says: "The header is 1024 bytes long."
-    // "Any unused space within the header should be padded with zeros."
-    // So, the remaining 1024 - 500 = 524 bytes are padding.
-    // We can represent this as a byte array.
-    pub padding: [u8; 524],
+    pub abbreviations_table_start: u64,
+    pub flags1: u32,
+    pub flags2: u32,
+    pub llm_api_endpoint_ptr: u64,
+    pub llm_parameters_ptr: u64,
+    pub context_globals_list_ptr: u64,
+    pub recent_events_buffer_ptr: u64,
+    pub recent_events_count_ptr: u64,
+    pub property_defaults_table_start: u64,
+    // Extension, not in the spec: zero-terminated list of ZSCII codes,
+    // besides newline, that end `aread` input (255 means every function key).
+    // 0 if not used.
+    pub terminating_characters_table_start: u64,
+    // Reserved for future expansion. Must be initialized to zero.
+    pub reserved: [u8; HEADER_RESERVED_LENGTH],
 }
 
 impl StoryHeader {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
-        if bytes.len() < 1024 {
-            return Err(format!(
-                "Header data too short. Expected 1024 bytes, got {}",
-                bytes.len()
-            ));
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HeaderError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(HeaderError::TooShort { len: bytes.len() });
         }
 
         let mut cursor = Cursor::new(bytes);
@@ -114,37 +227,23 @@ impl StoryHeader {
         let dynamic_data_section_start = cursor.read_u64::<BigEndian>().unwrap();
         let dynamic_data_section_length = cursor.read_u64::<BigEndian>().unwrap();
         let globals_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let globals_table_length = cursor.read_u64::<BigEndian>().unwrap();
-        let abbreviations_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let abbreviations_table_length = cursor.read_u64::<BigEndian>().unwrap();
         let objects_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let objects_table_length = cursor.read_u64::<BigEndian>().unwrap();
-        let properties_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let properties_table_length = cursor.read_u64::<BigEndian>().unwrap();
-        let attributes_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let attributes_table_length = cursor.read_u64::<BigEndian>().unwrap();
-        let string_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let string_table_length = cursor.read_u64::<BigEndian>().unwrap();
         let dictionary_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let dictionary_table_length = cursor.read_u64::<BigEndian>().unwrap();
-        let system_functions_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let system_functions_table_length = cursor.read_u64::<BigEndian>().unwrap();
-        let opcodes_table_start = cursor.read_u64::<BigEndian>().unwrap();
-        let opcodes_table_length = cursor.read_u64::<BigEndian>().unwrap();
-        let flags = cursor.read_u64::<BigEndian>().unwrap();
-        let reserved1 = cursor.read_u64::<BigEndian>().unwrap();
-        let reserved2 = cursor.read_u64::<BigEndian>().unwrap();
-        let reserved3 = cursor.read_u64::<BigEndian>().unwrap();
-
-        let mut reserved_block = [0u64; 32];
-        for i in 0..32 {
-            reserved_block[i] = cursor.read_u64::<BigEndian>().unwrap();
-        }
+        let abbreviations_table_start = cursor.read_u64::<BigEndian>().unwrap();
+        let flags1 = cursor.read_u32::<BigEndian>().unwrap();
+        let flags2 = cursor.read_u32::<BigEndian>().unwrap();
+        let llm_api_endpoint_ptr = cursor.read_u64::<BigEndian>().unwrap();
+        let llm_parameters_ptr = cursor.read_u64::<BigEndian>().unwrap();
+        let context_globals_list_ptr = cursor.read_u64::<BigEndian>().unwrap();
+        let recent_events_buffer_ptr = cursor.read_u64::<BigEndian>().unwrap();
+        let recent_events_count_ptr = cursor.read_u64::<BigEndian>().unwrap();
+        let property_defaults_table_start = cursor.read_u64::<BigEndian>().unwrap();
+        let terminating_characters_table_start = cursor.read_u64::<BigEndian>().unwrap();
 
-        let mut padding = [0u8; 524];
-        cursor.read_exact(&mut padding).unwrap();
+        let mut reserved = [0u8; HEADER_RESERVED_LENGTH];
+        cursor.read_exact(&mut reserved).unwrap();
 
-        Ok(StoryHeader {
+        let header = StoryHeader {
             version,
             release_number,
             story_id,
@@ -156,30 +255,276 @@ impl StoryHeader {
             dynamic_data_section_start,
             dynamic_data_section_length,
             globals_table_start,
-            globals_table_length,
-            abbreviations_table_start,
-            abbreviations_table_length,
             objects_table_start,
-            objects_table_length,
-            properties_table_start,
-            properties_table_length,
-            attributes_table_start,
-            attributes_table_length,
-            string_table_start,
-            string_table_length,
             dictionary_table_start,
-            dictionary_table_length,
-            system_functions_table_start,
-            system_functions_table_length,
-            opcodes_table_start,
-            opcodes_table_length,
-            flags,
-            reserved1,
-            reserved2,
-            reserved3,
-            reserved_block,
-            padding,
-        })
+            abbreviations_table_start,
+            flags1,
+            flags2,
+            llm_api_endpoint_ptr,
+            llm_parameters_ptr,
+            context_globals_list_ptr,
+            recent_events_buffer_ptr,
+            recent_events_count_ptr,
+            property_defaults_table_start,
+            terminating_characters_table_start,
+            reserved,
+        };
+
+        // Story files produced for the pre-spec layout kept table start/length
+        // pairs from offset 68 up to offset 244 and a reserved block after that.
+        // In the spec layout those bytes land in the flags' reserved bits and the
+        // trailing reserved area, both of which must be zero.
+        if header.reserved_fields_set() {
+            return Err(HeaderError::LegacyLayout { flags1, flags2 });
+        }
+        Ok(header)
+    }
+
+    /// Whether any reserved flag bit or reserved header byte is nonzero.
+    fn reserved_fields_set(&self) -> bool {
+        self.flags1 & FLAGS1_RESERVED_MASK != 0
+            || self.flags2 & FLAGS2_RESERVED_MASK != 0
+            || self.reserved.iter().any(|&b| b != 0)
+    }
+
+    fn check_reserved_fields(&self) -> Result<(), HeaderError> {
+        if self.reserved_fields_set() {
+            return Err(HeaderError::ReservedFieldsSet { flags1: self.flags1, flags2: self.flags2 });
+        }
+        Ok(())
+    }
+
+    /// Serializes the header into exactly `HEADER_SIZE` big-endian bytes.
+    /// Fails with `HeaderError::ReservedFieldsSet` if a reserved flag bit or
+    /// reserved byte is set, so `StoryHeader::from_bytes(&h.to_bytes()?)`
+    /// always yields a header equal to `h`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, HeaderError> {
+        self.check_reserved_fields()?;
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        self.write_fields(&mut bytes)
+            .expect("writing a header to a Vec<u8> cannot fail");
+        Ok(bytes)
+    }
+
+    /// Writes the 1024-byte header to `writer` in the spec layout. A header
+    /// with reserved fields set is refused with an `InvalidInput` error
+    /// wrapping `HeaderError::ReservedFieldsSet`, and nothing is written.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.check_reserved_fields()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        self.write_fields(writer)
+    }
+
+    fn write_fields<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_u16::<BigEndian>(self.version)?;
+        writer.write_u16::<BigEndian>(self.release_number)?;
+        writer.write_u64::<BigEndian>(self.story_id)?;
+        writer.write_u64::<BigEndian>(self.checksum)?;
+        writer.write_u64::<BigEndian>(self.code_section_start)?;
+        writer.write_u64::<BigEndian>(self.code_section_length)?;
+        writer.write_u64::<BigEndian>(self.static_data_section_start)?;
+        writer.write_u64::<BigEndian>(self.static_data_section_length)?;
+        writer.write_u64::<BigEndian>(self.dynamic_data_section_start)?;
+        writer.write_u64::<BigEndian>(self.dynamic_data_section_length)?;
+        writer.write_u64::<BigEndian>(self.globals_table_start)?;
+        writer.write_u64::<BigEndian>(self.objects_table_start)?;
+        writer.write_u64::<BigEndian>(self.dictionary_table_start)?;
+        writer.write_u64::<BigEndian>(self.abbreviations_table_start)?;
+        writer.write_u32::<BigEndian>(self.flags1)?;
+        writer.write_u32::<BigEndian>(self.flags2)?;
+        writer.write_u64::<BigEndian>(self.llm_api_endpoint_ptr)?;
+        writer.write_u64::<BigEndian>(self.llm_parameters_ptr)?;
+        writer.write_u64::<BigEndian>(self.context_globals_list_ptr)?;
+        writer.write_u64::<BigEndian>(self.recent_events_buffer_ptr)?;
+        writer.write_u64::<BigEndian>(self.recent_events_count_ptr)?;
+        writer.write_u64::<BigEndian>(self.property_defaults_table_start)?;
+        writer.write_u64::<BigEndian>(self.terminating_characters_table_start)?;
+        writer.write_all(&self.reserved)?;
+        Ok(())
+    }
+
+    /// Size of the VM's memory for a story file of `file_size` bytes: the file
+    /// image extended to the end of the dynamic data section. `None` if the
+    /// dynamic section overflows or would exceed `MAX_MEMORY_SIZE`.
+    pub fn memory_size(&self, file_size: u64) -> Option<u64> {
+        let dynamic_end = self.dynamic_data_section_start.checked_add(self.dynamic_data_section_length)?;
+        if dynamic_end > MAX_MEMORY_SIZE {
+            return None;
+        }
+        Some(dynamic_end.max(file_size))
+    }
+
+    /// Checks the section layout for a story file of `file_size` bytes: the
+    /// code and static sections must be present in the file, the dynamic
+    /// section (which the VM allocates) must hold the live globals and fit in
+    /// `MAX_MEMORY_SIZE`, sections must follow the header in spec order
+    /// without overlapping, and every table pointer must lie inside the
+    /// section that must contain it. Returns one `LayoutError` per problem found.
+    pub fn validate_layout(&self, file_size: u64) -> Result<(), Vec<LayoutError>> {
+        let mut errors = Vec::new();
+        let memory_size = self.memory_size(file_size).unwrap_or(file_size);
+
+        let sections = [
+            ("code_section", self.code_section_start, self.code_section_length),
+            ("static_data_section", self.static_data_section_start, self.static_data_section_length),
+            ("dynamic_data_section", self.dynamic_data_section_start, self.dynamic_data_section_length),
+        ];
+        let mut previous: Option<(&'static str, u64)> = None;
+        for &(field, start, length) in &sections {
+            let end = match start.checked_add(length) {
+                Some(end) => end,
+                None => {
+                    errors.push(LayoutError { field, start, end: u64::MAX, problem: LayoutProblem::AddressOverflow });
+                    previous = None;
+                    continue;
+                }
+            };
+            if start < HEADER_SIZE as u64 {
+                errors.push(LayoutError { field, start, end, problem: LayoutProblem::OverlapsHeader });
+            }
+            if let Some((previous, previous_end)) = previous {
+                if start < previous_end {
+                    errors.push(LayoutError { field, start, end, problem: LayoutProblem::OverlapsSection { previous, previous_end } });
+                }
+            }
+            if field == "dynamic_data_section" {
+                if end > MAX_MEMORY_SIZE {
+                    errors.push(LayoutError { field, start, end, problem: LayoutProblem::ExceedsMemory { memory_size: MAX_MEMORY_SIZE } });
+                }
+                if length < GLOBALS_TABLE_SIZE {
+                    errors.push(LayoutError { field, start, end, problem: LayoutProblem::TooSmall { minimum_length: GLOBALS_TABLE_SIZE } });
+                }
+            } else if end > file_size {
+                errors.push(LayoutError { field, start, end, problem: LayoutProblem::ExceedsStoryFile { file_size } });
+            }
+            previous = Some((field, end));
+        }
+
+        let static_start = self.static_data_section_start;
+        let static_end = static_start.saturating_add(self.static_data_section_length);
+        let in_static = |field: &'static str, start: u64, length: u64, errors: &mut Vec<LayoutError>| {
+            let end = start.saturating_add(length);
+            if start < static_start || end > static_end {
+                errors.push(LayoutError {
+                    field,
+                    start,
+                    end,
+                    problem: LayoutProblem::OutsideSection {
+                        section: "static_data_section",
+                        section_start: static_start,
+                        section_end: static_end,
+                    },
+                });
+            }
+        };
+
+        // The globals table always holds initial values for all 240 globals.
+        in_static("globals_table", self.globals_table_start, GLOBALS_TABLE_SIZE, &mut errors);
+
+        // The remaining static tables are optional (0 if not used) and their
+        // sizes are not recorded in the header, so only the start is checked.
+        let static_pointers = [
+            ("objects_table", self.objects_table_start),
+            ("dictionary_table", self.dictionary_table_start),
+            ("abbreviations_table", self.abbreviations_table_start),
+            ("property_defaults_table", self.property_defaults_table_start),
+            ("terminating_characters_table", self.terminating_characters_table_start),
+            ("llm_api_endpoint", self.llm_api_endpoint_ptr),
+            ("llm_parameters", self.llm_parameters_ptr),
+        ];
+        for &(field, start) in &static_pointers {
+            if start != 0 {
+                in_static(field, start, 1, &mut errors);
+            }
+        }
+
+        // These may point into dynamic memory; they only have to be addressable.
+        let memory_pointers = [
+            ("context_globals_list", self.context_globals_list_ptr),
+            ("recent_events_buffer", self.recent_events_buffer_ptr),
+            ("recent_events_count", self.recent_events_count_ptr),
+        ];
+        for &(field, start) in &memory_pointers {
+            if start != 0 && start >= memory_size {
+                errors.push(LayoutError {
+                    field,
+                    start,
+                    end: start.saturating_add(1),
+                    problem: LayoutProblem::ExceedsMemory { memory_size },
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Overwrites the first `HEADER_SIZE` bytes of `story_data` with this
+    /// header. `story_data` is left untouched if it is too short or the
+    /// header cannot be written (see `to_bytes`).
+    pub fn patch_into(&self, story_data: &mut [u8]) -> Result<(), HeaderError> {
+        if story_data.len() < HEADER_SIZE {
+            return Err(HeaderError::TooShort { len: story_data.len() });
+        }
+        story_data[..HEADER_SIZE].copy_from_slice(&self.to_bytes()?);
+        Ok(())
+    }
+
+    // --- flags1 accessors ---
+
+    pub fn transcripting(&self) -> bool { self.flags1 & FLAGS1_TRANSCRIPTING != 0 }
+    pub fn set_transcripting(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_TRANSCRIPTING, on) }
+
+    pub fn fixed_pitch_font(&self) -> bool { self.flags1 & FLAGS1_FIXED_PITCH_FONT != 0 }
+    pub fn set_fixed_pitch_font(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_FIXED_PITCH_FONT, on) }
+
+    pub fn strict_zscii_compat_mode(&self) -> bool { self.flags1 & FLAGS1_STRICT_ZSCII_COMPAT_MODE != 0 }
+    pub fn set_strict_zscii_compat_mode(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_STRICT_ZSCII_COMPAT_MODE, on) }
+
+    pub fn debug_mode(&self) -> bool { self.flags1 & FLAGS1_DEBUG_MODE != 0 }
+    pub fn set_debug_mode(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_DEBUG_MODE, on) }
+
+    pub fn llm_parse_enabled(&self) -> bool { self.flags1 & FLAGS1_LLM_PARSE_ENABLE != 0 }
+    pub fn set_llm_parse_enabled(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_LLM_PARSE_ENABLE, on) }
+
+    pub fn llm_generate_enabled(&self) -> bool { self.flags1 & FLAGS1_LLM_GENERATE_ENABLE != 0 }
+    pub fn set_llm_generate_enabled(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_LLM_GENERATE_ENABLE, on) }
+
+    pub fn div_by_zero_halt(&self) -> bool { self.flags1 & FLAGS1_DIV_BY_ZERO_HALT != 0 }
+    pub fn set_div_by_zero_halt(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_DIV_BY_ZERO_HALT, on) }
+
+    pub fn save_load_enabled(&self) -> bool { self.flags1 & FLAGS1_SAVE_LOAD_ENABLE != 0 }
+    pub fn set_save_load_enabled(&mut self, on: bool) { set_bit(&mut self.flags1, FLAGS1_SAVE_LOAD_ENABLE, on) }
+
+    // --- flags2 accessors ---
+
+    pub fn force_llm_sync(&self) -> bool { self.flags2 & FLAGS2_FORCE_LLM_SYNC != 0 }
+    pub fn set_force_llm_sync(&mut self, on: bool) { set_bit(&mut self.flags2, FLAGS2_FORCE_LLM_SYNC, on) }
+
+    pub fn extended_opcodes_enabled(&self) -> bool { self.flags2 & FLAGS2_ENABLE_EXTENDED_OPCODES != 0 }
+    pub fn set_extended_opcodes_enabled(&mut self, on: bool) { set_bit(&mut self.flags2, FLAGS2_ENABLE_EXTENDED_OPCODES, on) }
+
+    pub fn bypass_llm_moderation(&self) -> bool { self.flags2 & FLAGS2_BYPASS_LLM_MODERATION != 0 }
+    pub fn set_bypass_llm_moderation(&mut self, on: bool) { set_bit(&mut self.flags2, FLAGS2_BYPASS_LLM_MODERATION, on) }
+}
+
+/// Computes the 64-bit story checksum: the wrapping sum of every byte from
+/// `CHECKSUM_START_OFFSET` to the end of the story file.
+pub fn compute_checksum(story_data: &[u8]) -> u64 {
+    story_data
+        .iter()
+        .skip(CHECKSUM_START_OFFSET)
+        .fold(0u64, |sum, &b| sum.wrapping_add(b as u64))
+}
+
+fn set_bit(flags: &mut u32, bit: u32, on: bool) {
+    if on {
+        *flags |= bit;
+    } else {
+        *flags &= !bit;
     }
 }
 
@@ -200,64 +545,41 @@ pub(crate) fn create_dummy_header_bytes() -> Vec<u8> {
         bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]);
         // Static Data Section Start Address: 1024+256 = 1280 (0x500)
         bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00]);
-        // Static Data Section Length: 128 (0x80)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80]);
-        // Dynamic Data Section Start Address: 1280+128 = 1408 (0x580)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x80]);
-        // Dynamic Data Section Length: 64 (0x40)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40]);
-        // Globals Table Start Address: 1408+64 = 1472 (0x5C0)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xC0]);
-        // Globals Table Length: 32 (0x20)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20]);
-        // Abbreviations Table Start Address: 1472+32 = 1504 (0x5E0)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xE0]);
-        // Abbreviations Table Length: 16 (0x10)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10]);
-        // Objects Table Start Address: 1504+16 = 1520 (0x5F0)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xF0]);
-        // Objects Table Length: 8 (0x08)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08]);
-        // Properties Table Start Address: 1520+8 = 1528 (0x5F8)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xF8]);
-        // Properties Table Length: 4 (0x04)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04]);
-        // Attributes Table Start Address: 1528+4 = 1532 (0x5FC)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xFC]);
-        // Attributes Table Length: 2 (0x02)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02]);
-        // String Table Start Address: 1532+2 = 1534 (0x5FE)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xFE]);
-        // String Table Length: 1 (0x01)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
-        // Dictionary Table Start Address: 1534+1 = 1535 (0x5FF)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0xFF]);
-        // Dictionary Table Length: 1 (0x01) - Making it small
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
-        // System Functions Table Start Address: 1535+1 = 1536 (0x600)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00]);
-        // System Functions Table Length: 1 (0x01)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
-        // Opcodes Table Start Address: 1536+1 = 1537 (0x601)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x01]);
-        // Opcodes Table Length: 1 (0x01)
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
-        // Flags: 0
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
-        // Reserved 1: 0
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
-        // Reserved 2: 0
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
-        // Reserved 3: 0
-        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
-
-        // Reserved Block (256 bytes of 0s)
-        for _ in 0..32 {
-            bytes.extend_from_slice(&[0u8; 8]);
-        }
+        // Static Data Section Length: 2048 (0x800)
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00]);
+        // Dynamic Data Section Start Address: 1280+2048 = 3328 (0xD00)
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0D, 0x00]);
+        // Dynamic Data Section Length: 2048 (0x800)
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00]);
+        // Globals Table Start Address: 1280 (0x500), 240 * 8 = 1920 bytes
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00]);
+        // Objects Table Start Address: 3216 (0xC90)
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x90]);
+        // Dictionary Table Start Address: 3312 (0xCF0)
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0xF0]);
+        // Abbreviations Table Start Address: 3200 (0xC80)
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x80]);
+        // Flags1: 0
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        // Flags2: 0
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        // LLM API Endpoint Ptr: 0
+        bytes.extend_from_slice(&[0u8; 8]);
+        // LLM Parameters Ptr: 0
+        bytes.extend_from_slice(&[0u8; 8]);
+        // Context Globals List Ptr: 0
+        bytes.extend_from_slice(&[0u8; 8]);
+        // Recent Events Buffer Ptr: 0
+        bytes.extend_from_slice(&[0u8; 8]);
+        // Recent Events Count Ptr: 0
+        bytes.extend_from_slice(&[0u8; 8]);
+        // Property Defaults Table Start: 0
+        bytes.extend_from_slice(&[0u8; 8]);
+        // Terminating Characters Table Start: 0
+        bytes.extend_from_slice(&[0u8; 8]);
 
-        // Padding (524 bytes of 0s)
-        bytes.extend_from_slice(&[0u8; 524]);
+        // Reserved (860 bytes of 0s)
+        bytes.extend_from_slice(&[0u8; HEADER_RESERVED_LENGTH]);
 
         assert_eq!(bytes.len(), 1024);
         bytes
@@ -282,44 +604,256 @@ mod tests {
         assert_eq!(header.code_section_start, 1024);
         assert_eq!(header.code_section_length, 256);
         assert_eq!(header.static_data_section_start, 1280);
-        assert_eq!(header.static_data_section_length, 128);
-        assert_eq!(header.dynamic_data_section_start, 1408);
-        assert_eq!(header.dynamic_data_section_length, 64);
-        assert_eq!(header.globals_table_start, 1472);
-        assert_eq!(header.globals_table_length, 32);
-        assert_eq!(header.abbreviations_table_start, 1504);
-        assert_eq!(header.abbreviations_table_length, 16);
-        assert_eq!(header.objects_table_start, 1520);
-        assert_eq!(header.objects_table_length, 8);
-        assert_eq!(header.properties_table_start, 1528);
-        assert_eq!(header.properties_table_length, 4);
-        assert_eq!(header.attributes_table_start, 1532);
-        assert_eq!(header.attributes_table_length, 2);
-        assert_eq!(header.string_table_start, 1534);
-        assert_eq!(header.string_table_length, 1);
-        assert_eq!(header.dictionary_table_start, 1535);
-        assert_eq!(header.dictionary_table_length, 1);
-        assert_eq!(header.system_functions_table_start, 1536);
-        assert_eq!(header.system_functions_table_length, 1);
-        assert_eq!(header.opcodes_table_start, 1537);
-        assert_eq!(header.opcodes_table_length, 1);
-        assert_eq!(header.flags, 0);
-        assert_eq!(header.reserved1, 0);
-        assert_eq!(header.reserved2, 0);
-        assert_eq!(header.reserved3, 0);
-        assert_eq!(header.reserved_block, [0u64; 32]);
-        assert_eq!(header.padding, [0u8; 524]);
+        assert_eq!(header.static_data_section_length, 2048);
+        assert_eq!(header.dynamic_data_section_start, 3328);
+        assert_eq!(header.dynamic_data_section_length, 2048);
+        assert_eq!(header.globals_table_start, 1280);
+        assert_eq!(header.objects_table_start, 3216);
+        assert_eq!(header.dictionary_table_start, 3312);
+        assert_eq!(header.abbreviations_table_start, 3200);
+        assert_eq!(header.flags1, 0);
+        assert_eq!(header.flags2, 0);
+        assert_eq!(header.llm_api_endpoint_ptr, 0);
+        assert_eq!(header.llm_parameters_ptr, 0);
+        assert_eq!(header.context_globals_list_ptr, 0);
+        assert_eq!(header.recent_events_buffer_ptr, 0);
+        assert_eq!(header.recent_events_count_ptr, 0);
+        assert_eq!(header.property_defaults_table_start, 0);
+        assert_eq!(header.terminating_characters_table_start, 0);
+        assert_eq!(header.reserved, [0u8; HEADER_RESERVED_LENGTH]);
+    }
+
+    #[test]
+    fn test_story_header_spec_offsets() {
+        let mut header_bytes = create_dummy_header_bytes();
+        header_bytes[100..104].copy_from_slice(&0x0000_00B5u32.to_be_bytes());
+        header_bytes[104..108].copy_from_slice(&0x0000_0005u32.to_be_bytes());
+        header_bytes[108..116].copy_from_slice(&0x1111u64.to_be_bytes());
+        header_bytes[116..124].copy_from_slice(&0x2222u64.to_be_bytes());
+        header_bytes[124..132].copy_from_slice(&0x3333u64.to_be_bytes());
+        header_bytes[132..140].copy_from_slice(&0x4444u64.to_be_bytes());
+        header_bytes[140..148].copy_from_slice(&0x5555u64.to_be_bytes());
+        header_bytes[148..156].copy_from_slice(&0x6666u64.to_be_bytes());
+        header_bytes[156..164].copy_from_slice(&0x7777u64.to_be_bytes());
+        let header = StoryHeader::from_bytes(&header_bytes).unwrap();
+
+        assert_eq!(header.flags1, 0xB5);
+        assert_eq!(header.flags2, 0x05);
+        assert_eq!(header.llm_api_endpoint_ptr, 0x1111);
+        assert_eq!(header.llm_parameters_ptr, 0x2222);
+        assert_eq!(header.context_globals_list_ptr, 0x3333);
+        assert_eq!(header.recent_events_buffer_ptr, 0x4444);
+        assert_eq!(header.recent_events_count_ptr, 0x5555);
+        assert_eq!(header.property_defaults_table_start, 0x6666);
+        assert_eq!(header.terminating_characters_table_start, 0x7777);
+
+        // flags1 = 0b1011_0101
+        assert!(header.transcripting());
+        assert!(!header.fixed_pitch_font());
+        assert!(header.strict_zscii_compat_mode());
+        assert!(!header.debug_mode());
+        assert!(header.llm_parse_enabled());
+        assert!(header.llm_generate_enabled());
+        assert!(!header.div_by_zero_halt());
+        assert!(header.save_load_enabled());
+        // flags2 = 0b101
+        assert!(header.force_llm_sync());
+        assert!(!header.extended_opcodes_enabled());
+        assert!(header.bypass_llm_moderation());
+    }
+
+    #[test]
+    fn test_story_header_flag_setters() {
+        let mut header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        header.set_debug_mode(true);
+        header.set_div_by_zero_halt(true);
+        header.set_extended_opcodes_enabled(true);
+        assert_eq!(header.flags1, FLAGS1_DEBUG_MODE | FLAGS1_DIV_BY_ZERO_HALT);
+        assert_eq!(header.flags2, FLAGS2_ENABLE_EXTENDED_OPCODES);
+
+        header.set_debug_mode(false);
+        assert!(!header.debug_mode());
+        assert!(header.div_by_zero_halt());
+    }
+
+    #[test]
+    fn test_story_header_to_bytes_round_trip() {
+        let header_bytes = create_dummy_header_bytes();
+        let header = StoryHeader::from_bytes(&header_bytes).unwrap();
+        let written = header.to_bytes().unwrap();
+        assert_eq!(written.len(), HEADER_SIZE);
+        assert_eq!(written, header_bytes);
+
+        let mut modified = header.clone();
+        modified.release_number = 0x0102;
+        modified.set_llm_parse_enabled(true);
+        modified.set_force_llm_sync(true);
+        modified.llm_parameters_ptr = 0x1300;
+        modified.property_defaults_table_start = 0x1400;
+        let reparsed = StoryHeader::from_bytes(&modified.to_bytes().unwrap()).unwrap();
+        assert_eq!(reparsed, modified);
+
+        // Headers `from_bytes` would reject are refused rather than written.
+        let mut reserved_flag = header.clone();
+        reserved_flag.flags1 |= 1 << 8;
+        assert_eq!(
+            reserved_flag.to_bytes(),
+            Err(HeaderError::ReservedFieldsSet { flags1: 1 << 8, flags2: 0 })
+        );
+        let mut reserved_byte = header.clone();
+        reserved_byte.reserved[0] = 1;
+        assert_eq!(reserved_byte.to_bytes(), Err(HeaderError::ReservedFieldsSet { flags1: 0, flags2: 0 }));
+        let mut out = Vec::new();
+        let err = reserved_byte.write_to(&mut out).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(out.is_empty());
+        let mut story_data = header_bytes.clone();
+        assert!(reserved_byte.patch_into(&mut story_data).is_err());
+        assert_eq!(story_data, header_bytes);
+    }
+
+    #[test]
+    fn test_story_header_write_to() {
+        let header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        let mut out = Vec::new();
+        out.extend_from_slice(&[0xEE; 3]);
+        header.write_to(&mut out).unwrap();
+        assert_eq!(out.len(), 3 + HEADER_SIZE);
+        assert_eq!(&out[3..], &header.to_bytes().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_story_header_patch_into() {
+        let mut story_data = create_dummy_header_bytes();
+        story_data.resize(2048, 0xCC);
+        let mut header = StoryHeader::from_bytes(&story_data).unwrap();
+        header.release_number = 7;
+        header.patch_into(&mut story_data).unwrap();
+        assert_eq!(&story_data[2..4], &[0x00, 0x07]);
+        assert_eq!(story_data[1024], 0xCC);
+        assert_eq!(header.patch_into(&mut [0u8; 100]), Err(HeaderError::TooShort { len: 100 }));
+    }
+
+    #[test]
+    fn test_compute_checksum() {
+        let mut story_data = create_dummy_header_bytes();
+        story_data.resize(1030, 0);
+        story_data[1024..1030].copy_from_slice(&[1, 2, 3, 0xFF, 0xFF, 0xFF]);
+        // Header bytes from offset 20 onward: code/static/dynamic/globals/...
+        let header_sum: u64 = story_data[20..1024].iter().map(|&b| b as u64).sum();
+        assert_eq!(compute_checksum(&story_data), header_sum + 6 + 3 * 0xFF);
+
+        // The version, release number, story ID and checksum itself are not covered.
+        let before = compute_checksum(&story_data);
+        story_data[0..20].copy_from_slice(&[0xAB; 20]);
+        assert_eq!(compute_checksum(&story_data), before);
+
+        assert_eq!(compute_checksum(&[0xFF; 10]), 0);
+    }
+
+    // The dummy header's static section ends at 3328; the VM allocates the
+    // dynamic section (3328..5376) itself.
+    const DUMMY_FILE_SIZE: u64 = 3328;
+
+    #[test]
+    fn test_validate_layout_valid() {
+        let header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        assert_eq!(header.validate_layout(DUMMY_FILE_SIZE), Ok(()));
+        assert_eq!(header.memory_size(DUMMY_FILE_SIZE), Some(5376));
+        assert_eq!(header.memory_size(8000), Some(8000));
+    }
+
+    #[test]
+    fn test_validate_layout_static_exceeds_story_file() {
+        let header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        let errors = header.validate_layout(3000).unwrap_err();
+        assert_eq!(errors, vec![LayoutError {
+            field: "static_data_section",
+            start: 1280,
+            end: 3328,
+            problem: LayoutProblem::ExceedsStoryFile { file_size: 3000 },
+        }]);
+        assert_eq!(
+            errors[0].to_string(),
+            "static_data_section (0x500..0xD00): extends beyond the end of the story file (0xBB8 bytes)"
+        );
+    }
+
+    #[test]
+    fn test_validate_layout_dynamic_section_limits() {
+        let mut header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        header.dynamic_data_section_length = MAX_MEMORY_SIZE;
+        assert_eq!(header.memory_size(DUMMY_FILE_SIZE), None);
+        let errors = header.validate_layout(DUMMY_FILE_SIZE).unwrap_err();
+        assert_eq!(errors[0].field, "dynamic_data_section");
+        assert_eq!(errors[0].problem, LayoutProblem::ExceedsMemory { memory_size: MAX_MEMORY_SIZE });
+
+        header.dynamic_data_section_length = 64;
+        let errors = header.validate_layout(DUMMY_FILE_SIZE).unwrap_err();
+        assert_eq!(errors[0].problem, LayoutProblem::TooSmall { minimum_length: GLOBALS_TABLE_SIZE });
+    }
+
+    #[test]
+    fn test_validate_layout_overlaps() {
+        let mut header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        header.code_section_start = 512;
+        header.static_data_section_start = 1200; // code ends at 768, fine
+        header.dynamic_data_section_start = 3000; // static ends at 3248
+        let errors = header.validate_layout(DUMMY_FILE_SIZE).unwrap_err();
+        assert!(errors.contains(&LayoutError {
+            field: "code_section",
+            start: 512,
+            end: 768,
+            problem: LayoutProblem::OverlapsHeader,
+        }));
+        assert!(errors.contains(&LayoutError {
+            field: "dynamic_data_section",
+            start: 3000,
+            end: 5048,
+            problem: LayoutProblem::OverlapsSection { previous: "static_data_section", previous_end: 3248 },
+        }));
+    }
+
+    #[test]
+    fn test_validate_layout_address_overflow() {
+        let mut header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        header.code_section_length = u64::MAX;
+        let errors = header.validate_layout(DUMMY_FILE_SIZE).unwrap_err();
+        assert_eq!(errors[0].field, "code_section");
+        assert_eq!(errors[0].problem, LayoutProblem::AddressOverflow);
+    }
+
+    #[test]
+    fn test_validate_layout_table_pointers() {
+        let mut header = StoryHeader::from_bytes(&create_dummy_header_bytes()).unwrap();
+        header.globals_table_start = 2000; // 2000 + 1920 runs past static end 3328
+        header.objects_table_start = 1100; // in the code section
+        header.dictionary_table_start = 0; // unused tables are fine
+        header.recent_events_count_ptr = 9999; // memory ends at 5376
+        let errors = header.validate_layout(DUMMY_FILE_SIZE).unwrap_err();
+        let static_section = LayoutProblem::OutsideSection {
+            section: "static_data_section",
+            section_start: 1280,
+            section_end: 3328,
+        };
+        assert_eq!(errors, vec![
+            LayoutError { field: "globals_table", start: 2000, end: 3920, problem: static_section.clone() },
+            LayoutError { field: "objects_table", start: 1100, end: 1101, problem: static_section },
+            LayoutError {
+                field: "recent_events_count",
+                start: 9999,
+                end: 10000,
+                problem: LayoutProblem::ExceedsMemory { memory_size: 5376 },
+            },
+        ]);
     }
 
     #[test]
     fn test_story_header_from_bytes_too_short() {
         let bytes = vec![0u8; 512]; // Less than 1024
         let result = StoryHeader::from_bytes(&bytes);
-        assert!(result.is_err());
-        assert_eq!(
-            result.unwrap_err(),
-            "Header data too short. Expected 1024 bytes, got 512"
-        );
+        assert_eq!(result, Err(HeaderError::TooShort { len: 512 }));
+        assert_eq!(result.unwrap_err().to_string(), "header data too short: expected 1024 bytes, got 512");
     }
 
     #[test]
@@ -332,4 +866,23 @@ mod tests {
         let header = StoryHeader::from_bytes(&header_bytes).unwrap();
         assert_eq!(header.version, 0x0300);
     }
+
+    #[test]
+    fn test_story_header_rejects_legacy_layout() {
+        // Legacy layout: table start/length pairs from offset 68, objects table
+        // start (u64) at offset 100, flags (u64) at offset 212.
+        let mut header_bytes = create_dummy_header_bytes();
+        header_bytes[100..108].copy_from_slice(&1520u64.to_be_bytes()); // objects_table_start
+        header_bytes[108..116].copy_from_slice(&8u64.to_be_bytes()); // objects_table_length
+        header_bytes[164..172].copy_from_slice(&1535u64.to_be_bytes()); // dictionary_table_start
+        let result = StoryHeader::from_bytes(&header_bytes);
+        assert_eq!(result, Err(HeaderError::LegacyLayout { flags1: 0, flags2: 1520 }));
+    }
+
+    #[test]
+    fn test_story_header_rejects_nonzero_reserved_area() {
+        let mut header_bytes = create_dummy_header_bytes();
+        header_bytes[1023] = 0x01;
+        assert_eq!(StoryHeader::from_bytes(&header_bytes), Err(HeaderError::LegacyLayout { flags1: 0, flags2: 0 }));
+    }
 }
@@ -0,0 +1,639 @@
+// zm2_vm/src/input.rs
+
+//! Line input and lexical analysis (spec section g, `sread`).
+//!
+//! The VM reads player input through an `InputSource` supplied by the host.
+//! A line is stored in a text buffer laid out as in the Z-Machine Standard:
+//! byte 0 holds the maximum number of characters, byte 1 receives the number
+//! stored, and the lower-cased ZSCII text follows, with a terminating 0 if
+//! there is room for one.
+//!
+//! Tokenising splits that text into words at spaces and at the dictionary's
+//! word separators, which are words in their own right, and looks each word
+//! up in a `dictionary::Dictionary`. The parse buffer's byte 0 holds the
+//! maximum number of words and byte 1 receives the number parsed. Each word
+//! then gets a 13-byte entry: the 64-bit address of its
+//! dictionary entry (0 if the word is not in the dictionary), its length in
+//! characters, and the 32-bit offset of its first character from the start
+//! of the text buffer.
+//!
+//! The spec has `sread` show a prompt; the story prints its own instead, as
+//! classic games do.
+//!
+//! `aread` can time out. The VM measures time with a `Clock`, which tests
+//! replace with a `ManualClock`, and asks the input source for a line with
+//! the time left before the next timeout.
+//!
+//! `read_char` reads single keys. Keys that are not characters have the
+//! ZSCII input codes of the Z-Machine Standard (section 3.8): delete,
+//! newline, escape, the cursor keys, function keys, the keypad and mouse
+//! clicks. `aread` ends at a newline or at any function key listed in the
+//! story's terminating characters table. The spec's header has no field for
+//! that table, so its address comes from the header extension
+//! `terminating_characters_table_start` (offset 156).
+
+use crate::dictionary::Dictionary;
+use crate::memory::Memory;
+use crate::text;
+use crate::MemoryError;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Bytes before the text in a text buffer: the maximum and actual lengths.
+pub const TEXT_BUFFER_HEADER: u64 = 2;
+
+/// Bytes before the entries in a parse buffer: the maximum and actual word counts.
+pub const PARSE_BUFFER_HEADER: u64 = 2;
+
+/// Size of one parse buffer entry: dictionary address, length and offset.
+pub const PARSE_ENTRY_SIZE: u64 = 13;
+
+// ZSCII input codes for keys that are not characters.
+pub const KEY_DELETE: u16 = 8;
+pub const KEY_NEWLINE: u16 = text::ZSCII_NEWLINE;
+pub const KEY_ESCAPE: u16 = 27;
+pub const KEY_CURSOR_UP: u16 = 129;
+pub const KEY_CURSOR_DOWN: u16 = 130;
+pub const KEY_CURSOR_LEFT: u16 = 131;
+pub const KEY_CURSOR_RIGHT: u16 = 132;
+pub const KEY_F1: u16 = 133; // F1 to F12 are 133 to 144
+pub const KEY_F12: u16 = 144;
+pub const KEY_KEYPAD_0: u16 = 145; // keypad 0 to 9 are 145 to 154
+pub const KEY_KEYPAD_9: u16 = 154;
+pub const KEY_MENU_CLICK: u16 = 252;
+pub const KEY_DOUBLE_CLICK: u16 = 253;
+pub const KEY_SINGLE_CLICK: u16 = 254;
+
+/// A terminating characters table entry meaning every function key.
+pub const TERMINATE_ON_ANY_FUNCTION_KEY: u8 = 255;
+
+/// Whether `key` is a function key: a cursor key, F1-F12, a keypad key or
+/// a mouse click. Only these can end line input early.
+pub fn is_function_key(key: u16) -> bool {
+    matches!(key, KEY_CURSOR_UP..=KEY_KEYPAD_9 | KEY_MENU_CLICK..=KEY_SINGLE_CLICK)
+}
+
+/// The function keys listed in the zero-terminated terminating characters
+/// table at `address`, with `TERMINATE_ON_ANY_FUNCTION_KEY` expanded. Other
+/// codes in the table are ignored.
+pub fn terminating_characters(memory: &Memory, address: u64) -> Result<Vec<u16>, MemoryError> {
+    let mut keys = Vec::new();
+    let mut address = address;
+    loop {
+        match memory.read_byte(address)? {
+            0 => return Ok(keys),
+            TERMINATE_ON_ANY_FUNCTION_KEY => keys.extend((0..=u8::MAX as u16).filter(|&k| is_function_key(k))),
+            key if is_function_key(key as u16) => keys.push(key as u16),
+            _ => {}
+        }
+        address += 1;
+    }
+}
+
+/// The outcome of waiting for a line of input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineEvent {
+    /// A line ended by newline.
+    Line(String),
+    /// A line ended by one of the terminating keys the VM asked for.
+    Terminated(String, u16),
+    /// The time allowed ran out before a line was finished.
+    TimedOut,
+    /// The input has ended.
+    End,
+}
+
+/// The outcome of waiting for a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharEvent {
+    /// A key's ZSCII input code.
+    Key(u16),
+    TimedOut,
+    End,
+}
+
+/// Supplies player input. Implemented by the host.
+pub trait InputSource {
+    /// Reads a line of up to `max_len` characters, without its terminator.
+    /// Longer lines are truncated. `None` means the input has ended.
+    fn read_line(&mut self, max_len: usize) -> Option<String>;
+
+    /// As `read_line`, but gives up after `timeout`. Text typed before a
+    /// timeout is kept for the next call. Returning early is allowed: the VM
+    /// checks its clock and waits again if the timeout has not passed. The
+    /// default waits for a line however long it takes.
+    fn read_line_timeout(&mut self, max_len: usize, _timeout: Duration) -> LineEvent {
+        self.read_line(max_len).map_or(LineEvent::End, LineEvent::Line)
+    }
+
+    /// As `read_line_timeout`, but the function keys in `terminators` also
+    /// end the line. The default ignores them.
+    fn read_line_terminated(&mut self, max_len: usize, timeout: Duration, _terminators: &[u16]) -> LineEvent {
+        self.read_line_timeout(max_len, timeout)
+    }
+
+    /// Waits for a single key and returns its ZSCII input code. `None` means
+    /// the input has ended.
+    fn read_char(&mut self) -> Option<u16>;
+
+    /// As `read_char`, but gives up after `timeout`, under the same rules as
+    /// `read_line_timeout`.
+    fn read_char_timeout(&mut self, _timeout: Duration) -> CharEvent {
+        self.read_char().map_or(CharEvent::End, CharEvent::Key)
+    }
+}
+
+impl fmt::Debug for dyn InputSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("InputSource")
+    }
+}
+
+/// The time source for input timeouts.
+pub trait Clock {
+    /// Time elapsed since some fixed point; it must never go backwards.
+    fn now(&self) -> Duration;
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Clock({:?})", self.now())
+    }
+}
+
+/// Wall-clock time since the clock was created.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a
+/// test can hand one to the VM and keep another.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<Duration>>,
+}
+
+impl ManualClock {
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ScriptedEvent {
+    Line(String),
+    Key(u16),
+    Wait(Duration),
+}
+
+enum NextEvent {
+    Line(String),
+    Key(u16),
+    TimedOut,
+    End,
+}
+
+/// An `InputSource` that replays a fixed list of lines, e.g. a walkthrough.
+/// Pauses between lines let it exercise timeouts: a pause advances the
+/// attached `ManualClock` as it elapses. The VM starts out with an empty
+/// one, so `sread` fails until the host installs a source.
+///
+/// A line is the keys of its characters followed by newline, so `read_char`
+/// takes it one character at a time. Single keys typed during line input
+/// edit the line: characters are added, delete removes the last one,
+/// newline and the requested terminators end it, and other keys are
+/// ignored.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedInput {
+    events: VecDeque<ScriptedEvent>,
+    clock: Option<ManualClock>,
+    // Text of an unfinished line, kept across timeouts.
+    typed: String,
+}
+
+impl ScriptedInput {
+    pub fn new<I, S>(lines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        ScriptedInput {
+            events: lines.into_iter().map(|line| ScriptedEvent::Line(line.into())).collect(),
+            clock: None,
+            typed: String::new(),
+        }
+    }
+
+    /// Advances `clock` as pauses elapse.
+    pub fn with_clock(mut self, clock: ManualClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Appends a pause of `duration` before the next line.
+    pub fn then_wait(mut self, duration: Duration) -> Self {
+        self.events.push_back(ScriptedEvent::Wait(duration));
+        self
+    }
+
+    pub fn then_line(mut self, line: impl Into<String>) -> Self {
+        self.events.push_back(ScriptedEvent::Line(line.into()));
+        self
+    }
+
+    /// Appends a single key press, given as its ZSCII input code.
+    pub fn then_key(mut self, key: u16) -> Self {
+        self.events.push_back(ScriptedEvent::Key(key));
+        self
+    }
+
+    fn elapse(&self, duration: Duration) {
+        if let Some(clock) = &self.clock {
+            clock.advance(duration);
+        }
+    }
+
+    /// The next line or key, unless `timeout` runs out first. Pauses are
+    /// used up as they elapse.
+    fn next_event(&mut self, timeout: Duration) -> NextEvent {
+        let mut left = timeout;
+        loop {
+            match self.events.pop_front() {
+                None => return NextEvent::End,
+                Some(ScriptedEvent::Line(line)) => return NextEvent::Line(line),
+                Some(ScriptedEvent::Key(key)) => return NextEvent::Key(key),
+                Some(ScriptedEvent::Wait(duration)) if duration <= left => {
+                    self.elapse(duration);
+                    left -= duration;
+                }
+                Some(ScriptedEvent::Wait(duration)) => {
+                    self.elapse(left);
+                    self.events.push_front(ScriptedEvent::Wait(duration - left));
+                    return NextEvent::TimedOut;
+                }
+            }
+        }
+    }
+
+    /// Takes the line typed so far, truncated to `max_len` characters.
+    fn take_typed(&mut self, max_len: usize) -> String {
+        std::mem::take(&mut self.typed).chars().take(max_len).collect()
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn read_line(&mut self, max_len: usize) -> Option<String> {
+        match self.read_line_timeout(max_len, Duration::MAX) {
+            LineEvent::Line(line) => Some(line),
+            _ => None,
+        }
+    }
+
+    fn read_line_timeout(&mut self, max_len: usize, timeout: Duration) -> LineEvent {
+        self.read_line_terminated(max_len, timeout, &[])
+    }
+
+    fn read_line_terminated(&mut self, max_len: usize, timeout: Duration, terminators: &[u16]) -> LineEvent {
+        loop {
+            match self.next_event(timeout) {
+                NextEvent::Line(line) => {
+                    self.typed.push_str(&line);
+                    return LineEvent::Line(self.take_typed(max_len));
+                }
+                NextEvent::Key(KEY_NEWLINE) => return LineEvent::Line(self.take_typed(max_len)),
+                NextEvent::Key(key) if terminators.contains(&key) => {
+                    return LineEvent::Terminated(self.take_typed(max_len), key);
+                }
+                NextEvent::Key(KEY_DELETE) => {
+                    self.typed.pop();
+                }
+                NextEvent::Key(key) => self.typed.extend(text::zscii_to_char(key)),
+                NextEvent::TimedOut => return LineEvent::TimedOut,
+                NextEvent::End => return LineEvent::End,
+            }
+        }
+    }
+
+    fn read_char(&mut self) -> Option<u16> {
+        match self.read_char_timeout(Duration::MAX) {
+            CharEvent::Key(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    fn read_char_timeout(&mut self, timeout: Duration) -> CharEvent {
+        match self.next_event(timeout) {
+            NextEvent::Key(key) => CharEvent::Key(key),
+            NextEvent::Line(line) => {
+                let mut chars = line.chars();
+                match chars.next() {
+                    None => CharEvent::Key(KEY_NEWLINE),
+                    Some(c) => {
+                        self.events.push_front(ScriptedEvent::Line(chars.collect()));
+                        CharEvent::Key(text::to_zscii(&c.to_string())[0])
+                    }
+                }
+            }
+            NextEvent::TimedOut => CharEvent::TimedOut,
+            NextEvent::End => CharEvent::End,
+        }
+    }
+}
+
+/// Stores `line` in the text buffer at `text_buffer`, lower-cased and cut to
+/// the buffer's maximum length. Returns the number of characters stored.
+pub fn store_line(memory: &mut Memory, text_buffer: u64, line: &str) -> Result<u64, MemoryError> {
+    let max_len = memory.read_byte(text_buffer)? as usize;
+    // Every code `to_zscii` produces fits in a byte.
+    let mut zscii: Vec<u8> = text::to_zscii(&line.to_lowercase())
+        .into_iter()
+        .take(max_len)
+        .map(|c| c as u8)
+        .collect();
+    let count = zscii.len() as u64;
+    if zscii.len() < max_len {
+        zscii.push(0);
+    }
+    memory.write_byte(text_buffer + 1, count as u8)?;
+    memory.write_slice(text_buffer + TEXT_BUFFER_HEADER, &zscii)?;
+    Ok(count)
+}
+
+/// Tokenises the text in the text buffer at `text_buffer` into the parse
+/// buffer at `parse_buffer`, looking words up in `dictionary`. Without a
+/// dictionary only spaces separate words and every word gets address 0.
+/// With `skip_unknown` set, the entries of words that are not in the
+/// dictionary are left as they were. Words past the parse buffer's maximum
+/// are dropped.
+pub fn tokenise(
+    memory: &mut Memory,
+    text_buffer: u64,
+    parse_buffer: u64,
+    dictionary: Option<&Dictionary>,
+    skip_unknown: bool,
+) -> Result<(), MemoryError> {
+    let len = memory.read_byte(text_buffer + 1)? as u64;
+    let text = memory.read_slice(text_buffer + TEXT_BUFFER_HEADER, len)?.to_vec();
+    let separators = dictionary.map_or(&[][..], |d| d.separators());
+    let max_words = memory.read_byte(parse_buffer)? as usize;
+
+    let words = split_words(&text, separators);
+    let mut entry_address = parse_buffer + PARSE_BUFFER_HEADER;
+    for &(start, len) in words.iter().take(max_words) {
+        let zscii: Vec<u16> = text[start..start + len].iter().map(|&c| c as u16).collect();
+        let address = match dictionary {
+            Some(d) => d.lookup(memory, &zscii)?,
+            None => None,
+        };
+        if address.is_some() || !skip_unknown {
+            let mut entry = address.unwrap_or(0).to_be_bytes().to_vec();
+            entry.push(len as u8);
+            entry.extend_from_slice(&((TEXT_BUFFER_HEADER as usize + start) as u32).to_be_bytes());
+            memory.write_slice(entry_address, &entry)?;
+        }
+        entry_address += PARSE_ENTRY_SIZE;
+    }
+    memory.write_byte(parse_buffer + 1, words.len().min(max_words) as u8)
+}
+
+/// Splits `text` into `(start, len)` words at spaces and separators; each
+/// separator is a one-character word.
+fn split_words(text: &[u8], separators: &[u8]) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, &c) in text.iter().enumerate() {
+        if c == b' ' || separators.contains(&c) {
+            if let Some(s) = start.take() {
+                words.push((s, i - s));
+            }
+            if c != b' ' {
+                words.push((i, 1));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, text.len() - s));
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::create_dummy_header_bytes;
+
+    // Buffers live in the dynamic section of the dummy header (3328-5375).
+    const TEXT_BUFFER: u64 = 4000;
+    const PARSE_BUFFER: u64 = 4100;
+    const DICTIONARY: u64 = 2000;
+
+    /// Memory with a dictionary of `words` (8-byte entries) at `DICTIONARY`.
+    fn memory_with_dictionary(separators: &[u8], words: &[&str], count: i64) -> Memory {
+        let mut story_data = create_dummy_header_bytes();
+        story_data.resize(3328, 0);
+        let mut dictionary = vec![separators.len() as u8];
+        dictionary.extend_from_slice(separators);
+        dictionary.push(8);
+        dictionary.extend_from_slice(&count.to_be_bytes());
+        for word in words {
+            dictionary.extend_from_slice(&text::encode_dictionary_word(&text::to_zscii(word)));
+            dictionary.extend_from_slice(&[0, 0]);
+        }
+        let start = DICTIONARY as usize;
+        story_data[start..start + dictionary.len()].copy_from_slice(&dictionary);
+        Memory::new(story_data).unwrap()
+    }
+
+    fn entry_address(index: u64, separators: u64) -> u64 {
+        DICTIONARY + 10 + separators + 8 * index
+    }
+
+    fn parse_entries(memory: &Memory) -> Vec<(u64, u8, u32)> {
+        let count = memory.read_byte(PARSE_BUFFER + 1).unwrap() as u64;
+        (0..count)
+            .map(|i| {
+                let entry = memory.read_slice(PARSE_BUFFER + 2 + 13 * i, 13).unwrap();
+                (
+                    u64::from_be_bytes(entry[0..8].try_into().unwrap()),
+                    entry[8],
+                    u32::from_be_bytes(entry[9..13].try_into().unwrap()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_store_line() {
+        let mut memory = memory_with_dictionary(&[], &[], 0);
+        memory.write_byte(TEXT_BUFFER, 10).unwrap();
+        assert_eq!(store_line(&mut memory, TEXT_BUFFER, "Take Lamp"), Ok(9));
+        assert_eq!(memory.read_slice(TEXT_BUFFER + 1, 11), Ok(&b"\x09take lamp\0"[..]));
+
+        // No room for the terminator, and the rest is cut off.
+        assert_eq!(store_line(&mut memory, TEXT_BUFFER, "open the mailbox"), Ok(10));
+        assert_eq!(memory.read_slice(TEXT_BUFFER + 1, 12), Ok(&b"\x0aopen the m\0"[..]));
+    }
+
+    #[test]
+    fn test_tokenise_with_separators() {
+        let mut memory = memory_with_dictionary(b",.", &["take", "lamp", ","], -3);
+        memory.write_byte(TEXT_BUFFER, 40).unwrap();
+        memory.write_byte(PARSE_BUFFER, 10).unwrap();
+        store_line(&mut memory, TEXT_BUFFER, "take  lamp,xyzzy.").unwrap();
+        let dictionary = Dictionary::new(&memory, DICTIONARY).unwrap();
+        tokenise(&mut memory, TEXT_BUFFER, PARSE_BUFFER, Some(&dictionary), false).unwrap();
+        assert_eq!(
+            parse_entries(&memory),
+            vec![
+                (entry_address(0, 2), 4, 2),
+                (entry_address(1, 2), 4, 8),
+                (entry_address(2, 2), 1, 12),
+                (0, 5, 13),
+                (0, 1, 18),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenise_limits_and_no_dictionary() {
+        let mut memory = memory_with_dictionary(&[], &["north", "south"], -2);
+        memory.write_byte(TEXT_BUFFER, 40).unwrap();
+        memory.write_byte(PARSE_BUFFER, 2).unwrap();
+        store_line(&mut memory, TEXT_BUFFER, "south north east").unwrap();
+        let dictionary = Dictionary::new(&memory, DICTIONARY).unwrap();
+        tokenise(&mut memory, TEXT_BUFFER, PARSE_BUFFER, Some(&dictionary), false).unwrap();
+        assert_eq!(parse_entries(&memory), vec![(entry_address(1, 0), 5, 2), (entry_address(0, 0), 5, 8)]);
+
+        memory.write_byte(PARSE_BUFFER, 5).unwrap();
+        store_line(&mut memory, TEXT_BUFFER, "a,b c").unwrap();
+        tokenise(&mut memory, TEXT_BUFFER, PARSE_BUFFER, None, false).unwrap();
+        assert_eq!(parse_entries(&memory), vec![(0, 3, 2), (0, 1, 6)]);
+    }
+
+    #[test]
+    fn test_tokenise_skip_unknown() {
+        let mut memory = memory_with_dictionary(&[], &["north", "south"], -2);
+        let dictionary = Dictionary::new(&memory, DICTIONARY).unwrap();
+        memory.write_byte(TEXT_BUFFER, 40).unwrap();
+        memory.write_byte(PARSE_BUFFER, 3).unwrap();
+        store_line(&mut memory, TEXT_BUFFER, "go north").unwrap();
+        tokenise(&mut memory, TEXT_BUFFER, PARSE_BUFFER, None, false).unwrap();
+        store_line(&mut memory, TEXT_BUFFER, "fly south").unwrap();
+        tokenise(&mut memory, TEXT_BUFFER, PARSE_BUFFER, Some(&dictionary), true).unwrap();
+        assert_eq!(parse_entries(&memory), vec![(0, 2, 2), (entry_address(1, 0), 5, 6)]);
+    }
+
+    #[test]
+    fn test_scripted_input() {
+        let mut input = ScriptedInput::new(["look", "quit"]);
+        assert_eq!(input.read_line(80).as_deref(), Some("look"));
+        assert_eq!(input.read_line(80).as_deref(), Some("quit"));
+        assert_eq!(input.read_line(80), None);
+
+        // Longer lines are truncated.
+        let mut input = ScriptedInput::new(["northeast", "café"]).then_key(b'u' as u16).then_key(b'p' as u16);
+        assert_eq!(input.read_line(5).as_deref(), Some("north"));
+        assert_eq!(input.read_line(3).as_deref(), Some("caf"));
+        assert_eq!(
+            input.read_line_terminated(1, Duration::MAX, &[b'p' as u16]),
+            LineEvent::Terminated("u".to_string(), b'p' as u16)
+        );
+    }
+
+    #[test]
+    fn test_scripted_input_timeouts() {
+        let clock = ManualClock::default();
+        let seconds = Duration::from_secs;
+        let mut input = ScriptedInput::new(["look"])
+            .then_wait(seconds(5))
+            .then_line("wait")
+            .then_wait(seconds(1))
+            .with_clock(clock.clone());
+        assert_eq!(input.read_line_timeout(80, seconds(2)), LineEvent::Line("look".to_string()));
+        assert_eq!(input.read_line_timeout(80, seconds(2)), LineEvent::TimedOut);
+        assert_eq!(input.read_line_timeout(80, seconds(2)), LineEvent::TimedOut);
+        assert_eq!(clock.now(), seconds(4));
+        assert_eq!(input.read_line_timeout(80, seconds(2)), LineEvent::Line("wait".to_string()));
+        assert_eq!(clock.now(), seconds(5));
+        // Pauses without a timeout just pass.
+        assert_eq!(input.read_line(80), None);
+        assert_eq!(clock.now(), seconds(6));
+    }
+
+    #[test]
+    fn test_scripted_keys() {
+        let mut input = ScriptedInput::new(["Ok"]).then_key(KEY_F1).then_line("");
+        assert_eq!(input.read_char(), Some(b'O' as u16));
+        assert_eq!(input.read_char(), Some(b'k' as u16));
+        assert_eq!(input.read_char(), Some(KEY_NEWLINE));
+        assert_eq!(input.read_char(), Some(KEY_F1));
+        assert_eq!(input.read_char(), Some(KEY_NEWLINE));
+        assert_eq!(input.read_char(), None);
+
+        // Keys edit a line until newline or a terminator.
+        let keys = [b'n' as u16, b'x' as u16, KEY_DELETE, KEY_CURSOR_UP, KEY_F1 + 1, b'e' as u16, KEY_CURSOR_DOWN];
+        let mut input = keys.iter().fold(ScriptedInput::default(), |i, &k| i.then_key(k)).then_line("ast");
+        assert_eq!(input.read_line_terminated(80, Duration::MAX, &[]), LineEvent::Line("neast".to_string()));
+        let mut input = keys.iter().fold(ScriptedInput::default(), |i, &k| i.then_key(k));
+        assert_eq!(
+            input.read_line_terminated(80, Duration::MAX, &[KEY_CURSOR_DOWN]),
+            LineEvent::Terminated("ne".to_string(), KEY_CURSOR_DOWN)
+        );
+
+        // Typing survives a timeout.
+        let clock = ManualClock::default();
+        let seconds = Duration::from_secs;
+        let mut input = ScriptedInput::default()
+            .then_key(b'u' as u16)
+            .then_wait(seconds(3))
+            .then_key(b'p' as u16)
+            .then_key(KEY_NEWLINE)
+            .then_wait(seconds(3))
+            .then_key(KEY_ESCAPE)
+            .with_clock(clock.clone());
+        assert_eq!(input.read_line_timeout(80, seconds(2)), LineEvent::TimedOut);
+        assert_eq!(input.read_line_timeout(80, seconds(2)), LineEvent::Line("up".to_string()));
+        assert_eq!(input.read_char_timeout(seconds(2)), CharEvent::TimedOut);
+        assert_eq!(input.read_char_timeout(seconds(2)), CharEvent::Key(KEY_ESCAPE));
+        assert_eq!(clock.now(), seconds(6));
+        assert_eq!(input.read_char_timeout(seconds(2)), CharEvent::End);
+    }
+
+    #[test]
+    fn test_terminating_characters() {
+        let mut memory = memory_with_dictionary(&[], &[], 0);
+        memory.write_slice(TEXT_BUFFER, &[KEY_F1 as u8, b'a', KEY_NEWLINE as u8, KEY_DOUBLE_CLICK as u8, 0, 133]).unwrap();
+        assert_eq!(terminating_characters(&memory, TEXT_BUFFER), Ok(vec![KEY_F1, KEY_DOUBLE_CLICK]));
+
+        memory.write_slice(TEXT_BUFFER, &[TERMINATE_ON_ANY_FUNCTION_KEY, 0]).unwrap();
+        let keys = terminating_characters(&memory, TEXT_BUFFER).unwrap();
+        assert_eq!(keys.len(), 29);
+        assert!(keys.iter().all(|&k| is_function_key(k)));
+        assert!(!is_function_key(KEY_ESCAPE) && !is_function_key(155) && !is_function_key(251));
+        assert!(is_function_key(KEY_F12) && is_function_key(KEY_MENU_CLICK));
+    }
+}
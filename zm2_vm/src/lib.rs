@@ -7,12 +7,20 @@
 pub mod header;
 pub mod memory;
 pub mod cpu;
+pub mod dictionary;
+pub mod error;
+pub mod input;
+pub mod object;
 mod opcodes;
+pub mod output;
+pub mod screen;
+pub mod text;
+
+pub use error::{MemoryError, StoryFileError, VmError};
 
-use byteorder::BigEndian; // Removed WriteBytesExt as it seems unused now
-use std::io::Cursor;
 use std::fs::File;
 use std::io::Read;
+use std::time::Duration;
 
 
 // --- Opcode Enum (Old, for reference, might be removed later if not used by old methods) ---
@@ -25,341 +33,1031 @@ pub const NOP_OPCODE_VALUE: u64 = opcodes::OP_NOP;
 
 // --- Core Data Structures ---
 
-#[derive(Debug, PartialEq)]
-pub enum StoryFileError {
-    Io(std::io::ErrorKind),
-    MemoryInitialization(String),
-    UnsupportedVersion(u16),
-    ChecksumMismatch,
-    SectionTooLarge(String),
-}
-
-impl From<std::io::Error> for StoryFileError {
-    fn from(err: std::io::Error) -> Self {
-        StoryFileError::Io(err.kind())
-    }
+/// Options controlling how a story file is loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoadOptions {
+    /// Reject the story with `StoryFileError::ChecksumMismatch` if the header
+    /// checksum does not match the file contents.
+    pub verify_checksum: bool,
+    /// Let game code write to the static data section (read-only by default).
+    pub writable_static_data: bool,
+    /// Follow the header for division by zero: halt only if the story sets
+    /// `FLAGS1_DIV_BY_ZERO_HALT`, and otherwise yield 0 as the spec says.
+    /// Off by default, so division by zero always halts.
+    pub div_by_zero_returns_zero: bool,
 }
 
-
 #[derive(Debug)]
 pub struct VirtualMachine {
     memory: memory::Memory,
     cpu: cpu::Cpu,
+    objects: object::ObjectTable,
+    output: output::OutputStreams,
+    input: Box<dyn input::InputSource>,
+    clock: Box<dyn input::Clock>,
+    // Result of the innermost routine started by `run_routine`, once it returns.
+    interrupt_result: Option<u64>,
+    // `LoadOptions::div_by_zero_returns_zero`.
+    div_by_zero_returns_zero: bool,
     running: bool,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum MemoryError {
-    OutOfBounds,
-    CpuStackError(cpu::StackError),
-    InternalError(String),
-    // StackOverflow and StackUnderflow are now part of cpu::StackError
-}
-
-impl From<String> for MemoryError {
-    fn from(s: String) -> Self {
-        if s.to_lowercase().contains("out of bounds") {
-            MemoryError::OutOfBounds
-        } else {
-            MemoryError::InternalError(s)
-        }
-    }
-}
-
-impl From<cpu::StackError> for MemoryError {
-    fn from(e: cpu::StackError) -> Self {
-        MemoryError::CpuStackError(e)
-    }
-}
-
-
 impl VirtualMachine {
-    fn push_stack(&mut self, value: u64) -> Result<(), MemoryError> {
-        self.cpu.push_value(value, &mut self.memory).map_err(MemoryError::from)
+    fn push_stack(&mut self, value: u64) -> Result<(), VmError> {
+        self.cpu.push_value(value, &mut self.memory).map_err(VmError::from)
     }
 
-    fn pop_stack(&mut self) -> Result<u64, MemoryError> {
-        self.cpu.pop_value(&self.memory).map_err(MemoryError::from)
+    fn pop_stack(&mut self) -> Result<u64, VmError> {
+        self.cpu.pop_value(&self.memory).map_err(VmError::from)
     }
 
-    fn fetch_operand_type(&mut self) -> Result<u8, MemoryError> {
+    fn fetch_operand_type(&mut self) -> Result<u8, VmError> {
         let type_byte = self.read_byte(self.cpu.pc)?;
         self.cpu.pc += 1;
         Ok(type_byte)
     }
 
-    fn read_variable_operand(&mut self) -> Result<u8, MemoryError> {
+    fn read_variable_operand(&mut self) -> Result<u8, VmError> {
         let var_specifier = self.read_byte(self.cpu.pc)?;
         self.cpu.pc += 1;
         Ok(var_specifier)
     }
 
-    fn get_variable(&mut self, var_spec: u8) -> Result<u64, String> {
+    /// Returns the address of a variable's slot in memory. Stack (0x00) has no
+    /// fixed slot and must be handled by the caller.
+    fn variable_address(&self, var_spec: u8) -> Result<u64, VmError> {
         match var_spec {
-            0x00 => self.pop_stack().map_err(|e| format!("get_variable (stack pop): {:?}", e)),
+            0x00 => unreachable!("the stack has no fixed address"),
             0x01..=0x0F => {
                 let local_num = var_spec - 0x01;
-                let num_locals_on_stack = self.read_qword(self.cpu.fp).map_err(|e| format!("get_variable (L{}: read num_locals): {:?}", local_num, e))? as u8;
-                if local_num >= num_locals_on_stack {
-                    return Err(format!("get_variable: Invalid local variable L{} (max {}). FP={:#x}", local_num, num_locals_on_stack -1, self.cpu.fp));
+                let num_locals_on_stack = self.read_qword(self.cpu.fp)?;
+                if local_num as u64 >= num_locals_on_stack {
+                    return Err(VmError::InvalidVariable { local: local_num, num_locals: num_locals_on_stack });
                 }
-                let addr = self.cpu.fp + 8 * (1 + local_num as u64);
-                self.read_qword(addr).map_err(|e| format!("get_variable (L{} at addr {:#x}): {:?}", local_num, addr, e))
+                // Locals are pushed after the frame header, so they sit below FP.
+                Ok(self.cpu.fp - 8 * (1 + local_num as u64))
             }
             0x10..=0xFF => {
                 let global_num = var_spec - 0x10;
-                let addr = self.memory.header().globals_table_start + (global_num as u64 * 8);
-                self.read_qword(addr).map_err(|e| format!("get_variable (G{} at addr {:#x}): {:?}", global_num, addr, e))
+                Ok(self.memory.globals_start() + (global_num as u64 * 8))
             }
         }
     }
 
-    fn set_variable(&mut self, var_spec: u8, value: u64) -> Result<(), String> {
-        match var_spec {
-            0x00 => self.push_stack(value).map_err(|e| format!("set_variable (stack push): {:?}", e)),
-            0x01..=0x0F => {
-                let local_num = var_spec - 0x01;
-                let num_locals_on_stack = self.read_qword(self.cpu.fp).map_err(|e| format!("set_variable (L{}: read num_locals): {:?}", local_num, e))? as u8;
-                if local_num >= num_locals_on_stack {
-                    return Err(format!("set_variable: Invalid local variable L{} (max {}). FP={:#x}", local_num, num_locals_on_stack -1, self.cpu.fp));
-                }
-                let addr = self.cpu.fp + 8 * (1 + local_num as u64);
-                self.write_qword(addr, value).map_err(|e| format!("set_variable (L{} at addr {:#x}): {:?}", local_num, addr, e))
-            }
-            0x10..=0xFF => {
-                let global_num = var_spec - 0x10;
-                let addr = self.memory.header().globals_table_start + (global_num as u64 * 8);
-                self.write_qword(addr, value).map_err(|e| format!("set_variable (G{} at addr {:#x}): {:?}", global_num, addr, e))
-            }
+    fn get_variable(&mut self, var_spec: u8) -> Result<u64, VmError> {
+        if var_spec == 0x00 {
+            return self.pop_stack();
+        }
+        let addr = self.variable_address(var_spec)?;
+        Ok(self.read_qword(addr)?)
+    }
+
+    fn set_variable(&mut self, var_spec: u8, value: u64) -> Result<(), VmError> {
+        if var_spec == 0x00 {
+            return self.push_stack(value);
+        }
+        let addr = self.variable_address(var_spec)?;
+        Ok(self.write_qword(addr, value)?)
+    }
+
+    /// Reads a variable named by an opcode's variable-number operand. Unlike
+    /// `get_variable`, variable 0 reads the top of the stack without popping it.
+    fn get_variable_indirect(&mut self, var_spec: u8) -> Result<u64, VmError> {
+        if var_spec == 0x00 {
+            return self.cpu.peek_value(&self.memory).map_err(VmError::from);
+        }
+        self.get_variable(var_spec)
+    }
+
+    /// Writes a variable named by an opcode's variable-number operand. Unlike
+    /// `set_variable`, variable 0 overwrites the top of the stack instead of pushing.
+    fn set_variable_indirect(&mut self, var_spec: u8, value: u64) -> Result<(), VmError> {
+        if var_spec == 0x00 {
+            return self.cpu.replace_top_value(value, &mut self.memory).map_err(VmError::from);
         }
+        self.set_variable(var_spec, value)
     }
 
-    fn read_operand_value(&mut self, operand_type: u8) -> Result<u64, String> {
+    /// Adds `delta` to the variable named by the next operand byte and returns the new value.
+    fn adjust_variable_operand(&mut self, delta: i64) -> Result<u64, VmError> {
+        let var_spec = self.read_variable_operand()?;
+        let value = self.get_variable_indirect(var_spec)?.wrapping_add(delta as u64);
+        self.set_variable_indirect(var_spec, value)?;
+        Ok(value)
+    }
+
+    fn read_operand_value(&mut self, operand_type: u8) -> Result<u64, VmError> {
         match operand_type {
             0x00 => {
-                let value = self.read_qword(self.cpu.pc).map_err(|e| format!("Failed to read LC: {:?}", e))?;
+                let value = self.read_qword(self.cpu.pc)?;
                 self.cpu.pc += 8;
                 Ok(value)
             }
             0x01 => {
-                let value = self.read_byte(self.cpu.pc).map_err(|e| format!("Failed to read SC: {:?}", e))?;
+                let value = self.read_byte(self.cpu.pc)?;
                 self.cpu.pc += 1;
                 Ok(value as u64)
             }
             0x02 => {
-                let var_spec = self.read_byte(self.cpu.pc).map_err(|e| format!("VAR: Failed to read var_spec byte: {:?}", e))?;
+                let var_spec = self.read_byte(self.cpu.pc)?;
                 self.cpu.pc += 1;
                 self.get_variable(var_spec)
             }
             0x03 => {
-                let value = self.read_dword(self.cpu.pc).map_err(|e| format!("Failed to read PADDR: {:?}", e))?;
+                let value = self.read_dword(self.cpu.pc)?;
                 self.cpu.pc += 4;
                 Ok(value as u64)
             }
-            _ => Err(format!("Unknown operand type: {:#04x} at PC={:#x}", operand_type, self.cpu.pc)),
+            _ => Err(VmError::InvalidOperandType(operand_type)),
+        }
+    }
+
+    /// Reads a type byte and the operand it describes.
+    fn read_typed_operand(&mut self) -> Result<u64, VmError> {
+        let operand_type = self.fetch_operand_type()?;
+        self.read_operand_value(operand_type)
+    }
+
+    /// Reads typed operands up to an `OPERAND_TYPE_OMITTED` byte, which is consumed.
+    fn read_operand_list(&mut self, max: usize) -> Result<Vec<u64>, VmError> {
+        let mut values = Vec::new();
+        loop {
+            let operand_type = self.fetch_operand_type()?;
+            if operand_type == opcodes::OPERAND_TYPE_OMITTED {
+                return Ok(values);
+            }
+            if values.len() == max {
+                return Err(VmError::TooManyOperands { max });
+            }
+            values.push(self.read_operand_value(operand_type)?);
+        }
+    }
+
+    /// Reads two typed operands, applies `op` and stores the result in the
+    /// variable that follows them.
+    fn store_binary_op(&mut self, op: impl FnOnce(u64, u64) -> Result<u64, VmError>) -> Result<(), VmError> {
+        let val1 = self.read_typed_operand()?;
+        let val2 = self.read_typed_operand()?;
+        let result = op(val1, val2)?;
+        let store_var_spec = self.read_variable_operand()?;
+        self.set_variable(store_var_spec, result)
+    }
+
+    /// Reads the array and index operands of a table opcode and returns them
+    /// with the element's address. An address past `u64::MAX` saturates, so
+    /// the access that follows fails as out of bounds.
+    fn read_table_operands(&mut self, element_size: u64) -> Result<(u64, u64, u64), VmError> {
+        let array = self.read_typed_operand()?;
+        let index = self.read_typed_operand()?;
+        let address = array.saturating_add(index.saturating_mul(element_size));
+        Ok((array, index, address))
+    }
+
+    /// Decodes the Z-encoded string at `address` and prints it. Returns the
+    /// address after the string.
+    fn print_string_at(&mut self, address: u64) -> Result<u64, VmError> {
+        let (text, end) = text::decode_string(&self.memory, address)?;
+        self.print(&text)?;
+        Ok(end)
+    }
+
+    /// Stores a line of player input in the text buffer, echoes it to the
+    /// transcript and command script, and tokenises it against the story
+    /// dictionary if `parse_buffer` is not 0.
+    fn store_input_line(&mut self, text_buffer: u64, parse_buffer: u64, line: &str) -> Result<(), VmError> {
+        self.output.record_command(line);
+        input::store_line(&mut self.memory, text_buffer, line)?;
+        if parse_buffer != 0 {
+            let dictionary = self.dictionary()?;
+            input::tokenise(&mut self.memory, text_buffer, parse_buffer, dictionary.as_ref(), false)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for input by calling `read` with the time left, until it
+    /// returns `Some`. With a non-zero `seconds`, the routine at packed
+    /// address `routine` is run each time that many seconds pass without
+    /// input; if it returns true, or quits, the input is abandoned and `None`
+    /// returned. A `routine` of 0 just keeps waiting.
+    fn wait_for_input<T>(
+        &mut self,
+        seconds: u64,
+        routine: u64,
+        mut read: impl FnMut(&mut dyn input::InputSource, Duration) -> Result<Option<T>, VmError>,
+    ) -> Result<Option<T>, VmError> {
+        let interval = Duration::from_secs(seconds);
+        let next_deadline = |clock: &dyn input::Clock| if seconds == 0 { None } else { clock.now().checked_add(interval) };
+        let mut deadline = next_deadline(self.clock.as_ref());
+        loop {
+            let left = deadline.map_or(Duration::MAX, |d| d.saturating_sub(self.clock.now()));
+            if let Some(input) = read(self.input.as_mut(), left)? {
+                return Ok(Some(input));
+            }
+            match deadline {
+                Some(d) if self.clock.now() >= d => {}
+                _ => continue,
+            }
+            if (routine != 0 && self.run_routine(routine, &[])? != 0) || !self.running {
+                return Ok(None);
+            }
+            deadline = next_deadline(self.clock.as_ref());
+        }
+    }
+
+    /// The function keys that end `aread` input, from the header's
+    /// terminating characters table.
+    fn terminating_characters(&self) -> Result<Vec<u16>, VmError> {
+        match self.memory.header().terminating_characters_table_start {
+            0 => Ok(Vec::new()),
+            address => Ok(input::terminating_characters(&self.memory, address)?),
+        }
+    }
+
+    /// Sends `text` to the selected output streams.
+    fn print(&mut self, text: &str) -> Result<(), VmError> {
+        Ok(self.output.print(&mut self.memory, text)?)
+    }
+
+    /// Moves the PC by a signed offset, refusing to go below address 0.
+    fn jump_relative(&mut self, offset: i64) -> Result<(), VmError> {
+        let new_pc_signed = self.cpu.pc as i64 + offset;
+        if new_pc_signed < 0 { return Err(VmError::InvalidJumpTarget(new_pc_signed)); }
+        self.cpu.pc = new_pc_signed as u64;
+        Ok(())
+    }
+
+    /// Reads Standard Branch Data (spec 4.A.2.1) at the PC and branches if
+    /// `condition` matches the sense bit. Offsets 0 and 1 return false/true
+    /// from the current routine; any other offset is relative to the end of
+    /// the branch data.
+    fn branch(&mut self, condition: bool) -> Result<(), VmError> {
+        let bb1 = self.read_byte(self.cpu.pc)?;
+        self.cpu.pc += 1;
+        let offset: i64 = if bb1 & 0x40 == 0 {
+            let b = self.read_byte(self.cpu.pc)?;
+            self.cpu.pc += 1;
+            b as i8 as i64
+        } else {
+            let w = self.read_word(self.cpu.pc)?;
+            self.cpu.pc += 2;
+            w as i16 as i64
+        };
+        let sense = bb1 & 0x80 != 0;
+        if condition != sense {
+            return Ok(());
+        }
+        match offset {
+            0 => self.return_from_routine(0),
+            1 => self.return_from_routine(1),
+            _ => self.jump_relative(offset),
+        }
+    }
+
+    /// Whether `div` and `mod` by zero halt: always if the story sets
+    /// `FLAGS1_DIV_BY_ZERO_HALT`, and otherwise unless the host chose
+    /// `LoadOptions::div_by_zero_returns_zero`.
+    fn halt_on_division_by_zero(&self) -> bool {
+        self.memory.header().div_by_zero_halt() || !self.div_by_zero_returns_zero
+    }
+
+    /// Fails with `VmError::UnknownOpcode` unless the story enabled the
+    /// opcodes that are not in the spec (`FLAGS2_ENABLE_EXTENDED_OPCODES`).
+    fn require_extended_opcodes(&self, opcode: u64) -> Result<(), VmError> {
+        if self.memory.header().extended_opcodes_enabled() {
+            Ok(())
+        } else {
+            Err(VmError::UnknownOpcode(opcode))
+        }
+    }
+
+    /// Reads the routine operand of a call: a PADDR, or a variable holding one.
+    fn read_routine_operand(&mut self) -> Result<u64, VmError> {
+        let p_type = self.fetch_operand_type()?;
+        if p_type != 0x03 && p_type != 0x02 {
+            return Err(VmError::UnexpectedOperandType { expected: 0x03, found: p_type });
+        }
+        self.read_operand_value(p_type)
+    }
+
+    /// Pushes a frame for the routine at packed address `packed` and jumps to
+    /// it. Arguments are copied into the first locals; extra arguments are
+    /// dropped and not counted in the frame's argument count, so
+    /// `check_arg_count` never reports an argument the routine cannot read.
+    /// `store_var` is a variable reference or `DISCARD_RESULT`.
+    /// Calling packed address 0 returns false without entering a routine.
+    ///
+    /// A routine starts with an 8-byte header whose first byte is its number
+    /// of locals (at most 15), followed by one 8-byte default value per local.
+    /// Locals not covered by an argument take their default; the code follows
+    /// the defaults.
+    fn call_routine(&mut self, packed: u64, args: &[u64], store_var: u64) -> Result<(), VmError> {
+        if packed == 0 {
+            return self.store_result(store_var, 0);
+        }
+        let header = self.memory.header();
+        let code_end = header.code_section_start + header.code_section_length;
+        let target_addr = match header.code_section_start.checked_add(packed) {
+            Some(addr) if addr < code_end => addr,
+            _ => return Err(VmError::InvalidRoutineAddress(packed)),
+        };
+
+        let num_locals_from_routine = self.read_byte(target_addr)?;
+        if num_locals_from_routine as usize > Self::MAX_LOCALS {
+            return Err(VmError::TooManyLocals { routine: packed, num_locals: num_locals_from_routine });
+        }
+        let num_locals_from_routine = num_locals_from_routine as usize;
+
+        let pc_after_call_operands = self.cpu.pc;
+        let aligned_return_pc = (pc_after_call_operands + (Self::OPCODE_SIZE - 1)) & !(Self::OPCODE_SIZE - 1);
+
+        self.push_stack(aligned_return_pc)?;
+        self.push_stack(self.cpu.fp)?;
+        self.push_stack(store_var)?;
+        self.push_stack(args.len().min(num_locals_from_routine) as u64)?;
+        self.push_stack(num_locals_from_routine as u64)?;
+        self.cpu.fp = self.cpu.sp;
+
+        let defaults_addr = target_addr + Self::OPCODE_SIZE;
+        for i in 0..num_locals_from_routine {
+            let value = match args.get(i) {
+                Some(&arg) => arg,
+                None => self.read_qword(defaults_addr + 8 * i as u64)?,
+            };
+            self.push_stack(value)?;
+        }
+        self.cpu.pc = defaults_addr + 8 * num_locals_from_routine as u64;
+        Ok(())
+    }
+
+    /// Stores a routine's result, unless the call discards it.
+    fn store_result(&mut self, store_var: u64, value: u64) -> Result<(), VmError> {
+        match store_var {
+            Self::DISCARD_RESULT => Ok(()),
+            Self::INTERRUPT_RESULT => {
+                self.interrupt_result = Some(value);
+                Ok(())
+            }
+            _ => self.set_variable(store_var as u8, value),
+        }
+    }
+
+    /// Runs the routine at packed address `packed` to completion from inside
+    /// an opcode, e.g. a timeout routine, and returns its result. The PC is
+    /// restored afterwards, so the calling opcode carries on where it was. If
+    /// the routine quits, the result is 0 and `running` is cleared.
+    fn run_routine(&mut self, packed: u64, args: &[u64]) -> Result<u64, VmError> {
+        let saved_pc = self.cpu.pc;
+        let outer_result = self.interrupt_result.take();
+        self.call_routine(packed, args, Self::INTERRUPT_RESULT)?;
+        while self.interrupt_result.is_none() && self.running {
+            self.step()?;
         }
+        self.cpu.pc = saved_pc;
+        Ok(std::mem::replace(&mut self.interrupt_result, outer_result).unwrap_or(0))
+    }
+
+    /// Unwinds the current routine frame and stores `value` into the call's store variable.
+    fn return_from_routine(&mut self, value: u64) -> Result<(), VmError> {
+        self.cpu.sp = self.cpu.fp;
+        let _num_locals_on_stack = self.pop_stack()?;
+        let _num_args_supplied_on_stack = self.pop_stack()?;
+        let store_var_ref = self.pop_stack()?;
+        self.cpu.fp = self.pop_stack()?;
+        self.cpu.pc = self.pop_stack()?;
+        self.store_result(store_var_ref, value)
     }
 
     pub fn read_byte(&self, address: u64) -> Result<u8, MemoryError> {
-        self.memory.read_byte(address).map_err(MemoryError::from)
+        self.memory.read_byte(address)
     }
 
     pub fn write_byte(&mut self, address: u64, value: u8) -> Result<(), MemoryError> {
-        self.memory.write_byte(address, value).map_err(MemoryError::from)
+        self.memory.write_byte(address, value)
     }
 
     pub fn read_word(&self, address: u64) -> Result<u16, MemoryError> {
-        self.memory.read_u16(address).map_err(MemoryError::from)
+        self.memory.read_u16(address)
     }
 
     pub fn write_word(&mut self, address: u64, value: u16) -> Result<(), MemoryError> {
-        self.memory.write_u16(address, value).map_err(MemoryError::from)
+        self.memory.write_u16(address, value)
     }
 
     pub fn read_dword(&self, address: u64) -> Result<u32, MemoryError> {
-        self.memory.read_u32(address).map_err(MemoryError::from)
+        self.memory.read_u32(address)
     }
 
     pub fn write_dword(&mut self, address: u64, value: u32) -> Result<(), MemoryError> {
-        self.memory.write_u32(address, value).map_err(MemoryError::from)
+        self.memory.write_u32(address, value)
     }
 
     pub fn read_qword(&self, address: u64) -> Result<u64, MemoryError> {
-        self.memory.read_word(address).map_err(MemoryError::from)
+        self.memory.read_word(address)
     }
 
     pub fn write_qword(&mut self, address: u64, value: u64) -> Result<(), MemoryError> {
-        self.memory.write_word(address, value).map_err(MemoryError::from)
+        self.memory.write_word(address, value)
     }
 
     const OPCODE_SIZE: u64 = 8;
+    /// Most values `je_varargs` compares its first operand against.
+    const MAX_JE_COMPARANDS: usize = 3;
+    /// Most locals a routine can declare (spec 4.A.1.d); variables 0x01-0x0F.
+    const MAX_LOCALS: usize = 15;
+    /// Most arguments a call can pass (spec 4.A.3.d).
+    const MAX_CALL_ARGS: usize = 7;
+    /// Store variable recorded in the frame of a call whose result is
+    /// discarded; outside the 0x00-0xFF range of variable references.
+    const DISCARD_RESULT: u64 = 0x100;
+    /// Store variable recorded in the frame of a routine started by
+    /// `run_routine`; its result goes to `interrupt_result`.
+    const INTERRUPT_RESULT: u64 = 0x101;
 
     pub fn load_story(file_path: &str) -> Result<Self, StoryFileError> {
+        Self::load_story_with_options(file_path, LoadOptions::default())
+    }
+
+    pub fn load_story_with_options(file_path: &str, options: LoadOptions) -> Result<Self, StoryFileError> {
         let mut file_content = Vec::new();
         File::open(file_path)?.read_to_end(&mut file_content)?;
 
-        let new_memory = memory::Memory::new(file_content)
-            .map_err(StoryFileError::MemoryInitialization)?;
+        let mut new_memory = memory::Memory::new(file_content)?;
+        new_memory.set_static_data_writable(options.writable_static_data);
+
+        if let Err(errors) = new_memory.validate_layout() {
+            let section_too_large = errors.iter().find(|e| {
+                e.field.ends_with("_section")
+                    && matches!(
+                        e.problem,
+                        header::LayoutProblem::ExceedsMemory { .. }
+                            | header::LayoutProblem::ExceedsStoryFile { .. }
+                            | header::LayoutProblem::AddressOverflow
+                    )
+            });
+            return Err(match section_too_large {
+                Some(e) => StoryFileError::SectionTooLarge(e.clone()),
+                None => StoryFileError::InvalidLayout(errors),
+            });
+        }
+
+        if options.verify_checksum && !new_memory.checksum_matches() {
+            return Err(StoryFileError::ChecksumMismatch);
+        }
 
         let new_cpu = cpu::Cpu::new(&new_memory);
+        let objects = object::ObjectTable::new(&new_memory);
 
         Ok(VirtualMachine {
             memory: new_memory,
             cpu: new_cpu,
+            objects,
+            output: output::OutputStreams::default(),
+            input: Box::new(input::ScriptedInput::default()),
+            clock: Box::new(input::SystemClock::default()),
+            interrupt_result: None,
+            div_by_zero_returns_zero: options.div_by_zero_returns_zero,
             running: true,
         })
     }
 
+    /// Enables or disables write protection of the header, code and static
+    /// sections. Debuggers can turn it off to patch a running story.
+    pub fn set_write_protection(&mut self, enabled: bool) {
+        self.memory.set_write_protection(enabled);
+    }
+
+    /// The story's object table.
+    pub fn objects(&self) -> object::ObjectTable {
+        self.objects
+    }
+
+    /// Whether `object` has attribute `attribute` (0-63).
+    pub fn object_attribute(&self, object: u64, attribute: u64) -> Result<bool, object::ObjectError> {
+        self.objects.has_attribute(&self.memory, object, attribute)
+    }
+
+    /// Sets or clears attribute `attribute` (0-63) of `object`, e.g. to set up
+    /// game state from the host.
+    pub fn set_object_attribute(&mut self, object: u64, attribute: u64, value: bool) -> Result<(), object::ObjectError> {
+        self.objects.set_attribute(&mut self.memory, object, attribute, value)
+    }
+
+    /// Installs the sink that receives everything the story prints, returning
+    /// the previous one. Until this is called, output collects in an
+    /// `output::OutputBuffer`.
+    pub fn set_output_sink(&mut self, sink: Box<dyn output::OutputSink>) -> Box<dyn output::OutputSink> {
+        self.output.set_sink(sink)
+    }
+
+    /// Installs the screen that screen output and the window opcodes draw
+    /// on, returning the previous one. Without a screen, printed text goes
+    /// to the output sink and the window opcodes do nothing.
+    pub fn set_screen(&mut self, screen: Option<screen::Screen>) -> Option<screen::Screen> {
+        self.output.set_screen(screen)
+    }
+
+    pub fn screen(&self) -> Option<&screen::Screen> {
+        self.output.screen()
+    }
+
+    /// Replaces the clock input timeouts are measured with, e.g. with an
+    /// `input::ManualClock` in tests.
+    pub fn set_clock(&mut self, clock: Box<dyn input::Clock>) {
+        self.clock = clock;
+    }
+
+    /// Installs the source `sread`, `aread` and `read_char` read player input
+    /// from, returning the previous one.
+    pub fn set_input_source(&mut self, source: Box<dyn input::InputSource>) -> Box<dyn input::InputSource> {
+        std::mem::replace(&mut self.input, source)
+    }
+
+    /// The dictionary at the header's `dictionary_table_start`, or `None` if
+    /// the story has none.
+    pub fn dictionary(&self) -> Result<Option<dictionary::Dictionary>, dictionary::DictionaryError> {
+        match self.memory.header().dictionary_table_start {
+            0 => Ok(None),
+            address => dictionary::Dictionary::new(&self.memory, address).map(Some),
+        }
+    }
+
+    /// The address of the story dictionary's entry for `word`, or `None` if
+    /// the word, or the dictionary, is missing.
+    pub fn lookup_word(&self, word: &str) -> Result<Option<u64>, dictionary::DictionaryError> {
+        match self.dictionary()? {
+            Some(dictionary) => Ok(dictionary.lookup_str(&self.memory, word)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Every word in the story dictionary with the address of its entry.
+    pub fn dictionary_words(&self) -> Result<Vec<(u64, String)>, dictionary::DictionaryError> {
+        match self.dictionary()? {
+            Some(dictionary) => dictionary.words(&self.memory),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The story's output stream selections.
+    pub fn output_streams(&self) -> &output::OutputStreams {
+        &self.output
+    }
+
+    /// Checks the story file checksum recorded at load time against the header.
+    pub fn verify(&self) -> bool {
+        self.memory.checksum_matches()
+    }
+
     pub fn fetch_opcode(&mut self) -> Result<u64, MemoryError> {
         let opcode_val = self.read_qword(self.cpu.pc)?;
         self.cpu.pc += Self::OPCODE_SIZE;
         Ok(opcode_val)
     }
 
-    pub fn decode_and_execute_opcode(&mut self, opcode: u64) -> Result<(), String> {
+    pub fn decode_and_execute_opcode(&mut self, opcode: u64) -> Result<(), VmError> {
         match opcode {
             opcodes::OP_NOP => Ok(()),
             opcodes::OP_QUIT => { self.running = false; Ok(()) }
             opcodes::OP_PUSH => {
-                let operand_type = self.fetch_operand_type().map_err(|e| format!("PUSH: Type fetch: {:?}", e))?;
+                let operand_type = self.fetch_operand_type()?;
                 let value = self.read_operand_value(operand_type)?;
-                self.push_stack(value).map_err(|e| format!("PUSH: Stack op: {:?}", e))
+                self.push_stack(value)
             }
             opcodes::OP_PULL => {
-                let value = self.pop_stack().map_err(|e| format!("PULL: Pop: {:?}", e))?;
-                let var_spec = self.read_variable_operand().map_err(|e| format!("PULL: Var spec: {:?}", e))?;
+                let value = self.pop_stack()?;
+                let var_spec = self.read_variable_operand()?;
                 if var_spec != 0x00 {
-                    self.set_variable(var_spec, value).map_err(|e| format!("PULL: Set var: {}", e))?;
+                    self.set_variable(var_spec, value)?;
                 }
                 Ok(())
             }
             opcodes::OP_STORE => {
-                let var_spec = self.read_variable_operand().map_err(|e| format!("STORE: Failed to read var_spec: {:?}", e))?;
-                let operand_type = self.fetch_operand_type().map_err(|e| format!("STORE: Failed to fetch operand type: {:?}", e))?;
-                let value = self.read_operand_value(operand_type).map_err(|e| format!("STORE: Failed to read value: {}", e))?;
-                self.set_variable(var_spec, value).map_err(|e| format!("STORE: Failed to set variable: {}", e))
+                let var_spec = self.read_variable_operand()?;
+                let operand_type = self.fetch_operand_type()?;
+                let value = self.read_operand_value(operand_type)?;
+                self.set_variable(var_spec, value)
             }
             opcodes::OP_LOAD => {
-                let source_var_spec = self.read_variable_operand().map_err(|e| format!("LOAD: Failed to read source_var_spec: {:?}", e))?;
-                let value = self.get_variable(source_var_spec).map_err(|e| format!("LOAD: Failed to get source variable: {}", e))?;
-                let dest_var_spec = self.read_variable_operand().map_err(|e| format!("LOAD: Failed to read dest_var_spec: {:?}", e))?;
-                self.set_variable(dest_var_spec, value).map_err(|e| format!("LOAD: Failed to set dest variable: {}", e))
-            }
-            opcodes::OP_ADD => {
-                let type1 = self.fetch_operand_type().map_err(|e| format!("ADD: Failed type1: {:?}", e))?;
-                let val1 = self.read_operand_value(type1).map_err(|e| format!("ADD: Failed val1: {}", e))?;
-                let type2 = self.fetch_operand_type().map_err(|e| format!("ADD: Failed type2: {:?}", e))?;
-                let val2 = self.read_operand_value(type2).map_err(|e| format!("ADD: Failed val2: {}", e))?;
-                let result = val1.wrapping_add(val2);
-                let store_var_spec = self.read_variable_operand().map_err(|e| format!("ADD: Failed store_var_spec: {:?}", e))?;
-                self.set_variable(store_var_spec, result).map_err(|e| format!("ADD: Failed set_variable: {}", e))
-            }
-            opcodes::OP_SUB => {
-                let type1 = self.fetch_operand_type().map_err(|e| format!("SUB: Failed type1: {:?}", e))?;
-                let val1 = self.read_operand_value(type1).map_err(|e| format!("SUB: Failed val1: {}", e))?;
-                let type2 = self.fetch_operand_type().map_err(|e| format!("SUB: Failed type2: {:?}", e))?;
-                let val2 = self.read_operand_value(type2).map_err(|e| format!("SUB: Failed val2: {}", e))?;
-                let result = val1.wrapping_sub(val2);
-                let store_var_spec = self.read_variable_operand().map_err(|e| format!("SUB: Failed store_var_spec: {:?}", e))?;
-                self.set_variable(store_var_spec, result).map_err(|e| format!("SUB: Failed set_variable: {}", e))
+                let source_var_spec = self.read_variable_operand()?;
+                let value = self.get_variable(source_var_spec)?;
+                let dest_var_spec = self.read_variable_operand()?;
+                self.set_variable(dest_var_spec, value)
+            }
+            opcodes::OP_ADD => self.store_binary_op(|a, b| Ok(a.wrapping_add(b))),
+            opcodes::OP_SUB => self.store_binary_op(|a, b| Ok(a.wrapping_sub(b))),
+            opcodes::OP_MUL => self.store_binary_op(|a, b| Ok(a.wrapping_mul(b))),
+            opcodes::OP_DIV => {
+                let halt = self.halt_on_division_by_zero();
+                self.store_binary_op(|a, b| signed_division(a, b, halt, i64::wrapping_div))
+            }
+            opcodes::OP_MOD => {
+                let halt = self.halt_on_division_by_zero();
+                self.store_binary_op(|a, b| signed_division(a, b, halt, i64::wrapping_rem))
+            }
+            opcodes::OP_OR => self.store_binary_op(|a, b| Ok(a | b)),
+            opcodes::OP_AND => self.store_binary_op(|a, b| Ok(a & b)),
+            opcodes::OP_TEST => {
+                // As in the original Z-machine, and unlike spec 4.A.3.ak's
+                // "non-zero" wording: every flag must be set for the branch.
+                let bitmap = self.read_typed_operand()?;
+                let flags = self.read_typed_operand()?;
+                self.branch(bitmap & flags == flags)
+            }
+            opcodes::OP_LOG_SHIFT | opcodes::OP_ART_SHIFT => {
+                self.require_extended_opcodes(opcode)?;
+                let arithmetic = opcode == opcodes::OP_ART_SHIFT;
+                self.store_binary_op(|a, b| Ok(shift(a, b as i64, arithmetic)))
+            }
+            opcodes::OP_LOADW => {
+                let (array, index, address) = self.read_table_operands(8)?;
+                let value = self.read_qword(address).map_err(|error| VmError::ArrayAccess { array, index, error })?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, value)
+            }
+            opcodes::OP_LOADB => {
+                let (array, index, address) = self.read_table_operands(1)?;
+                let value = self.read_byte(address).map_err(|error| VmError::ArrayAccess { array, index, error })?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, value as u64)
+            }
+            opcodes::OP_STOREW => {
+                let (array, index, address) = self.read_table_operands(8)?;
+                let value = self.read_typed_operand()?;
+                self.write_qword(address, value).map_err(|error| VmError::ArrayAccess { array, index, error })
+            }
+            opcodes::OP_STOREB => {
+                let (array, index, address) = self.read_table_operands(1)?;
+                let value = self.read_typed_operand()?;
+                self.write_byte(address, value as u8).map_err(|error| VmError::ArrayAccess { array, index, error })
+            }
+            opcodes::OP_INC => self.adjust_variable_operand(1).map(|_| ()),
+            opcodes::OP_DEC => self.adjust_variable_operand(-1).map(|_| ()),
+            opcodes::OP_INC_CHK => {
+                let value = self.adjust_variable_operand(1)? as i64;
+                let compare_to = self.read_typed_operand()? as i64;
+                self.branch(value > compare_to)
+            }
+            opcodes::OP_DEC_CHK => {
+                let value = self.adjust_variable_operand(-1)? as i64;
+                let compare_to = self.read_typed_operand()? as i64;
+                self.branch(value < compare_to)
+            }
+            opcodes::OP_NOT => {
+                let value = self.read_typed_operand()?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, !value)
+            }
+            opcodes::OP_GET_SIBLING => {
+                let object = self.read_typed_operand()?;
+                let sibling = self.objects.sibling(&self.memory, object)?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, sibling)?;
+                self.branch(sibling != 0)
+            }
+            opcodes::OP_GET_CHILD => {
+                let object = self.read_typed_operand()?;
+                let child = self.objects.child(&self.memory, object)?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, child)?;
+                self.branch(child != 0)
+            }
+            opcodes::OP_GET_PARENT => {
+                let object = self.read_typed_operand()?;
+                let parent = self.objects.parent(&self.memory, object)?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, parent)
+            }
+            opcodes::OP_JIN => {
+                let object = self.read_typed_operand()?;
+                let parent = self.read_typed_operand()?;
+                let condition = self.objects.is_child_of(&self.memory, object, parent)?;
+                self.branch(condition)
+            }
+            opcodes::OP_TEST_ATTR => {
+                let object = self.read_typed_operand()?;
+                let attribute = self.read_typed_operand()?;
+                let condition = self.objects.has_attribute(&self.memory, object, attribute)?;
+                self.branch(condition)
+            }
+            opcodes::OP_SET_ATTR | opcodes::OP_CLEAR_ATTR => {
+                let object = self.read_typed_operand()?;
+                let attribute = self.read_typed_operand()?;
+                let value = opcode == opcodes::OP_SET_ATTR;
+                Ok(self.objects.set_attribute(&mut self.memory, object, attribute, value)?)
+            }
+            opcodes::OP_GET_PROP => {
+                let object = self.read_typed_operand()?;
+                let property = self.read_typed_operand()?;
+                let value = self.objects.property_value(&self.memory, object, property)?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, value)
+            }
+            opcodes::OP_GET_PROP_ADDR => {
+                let object = self.read_typed_operand()?;
+                let property = self.read_typed_operand()?;
+                let found = self.objects.find_property(&self.memory, object, property)?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, found.map_or(0, |p| p.address))
+            }
+            opcodes::OP_GET_NEXT_PROP => {
+                let object = self.read_typed_operand()?;
+                let property = self.read_typed_operand()?;
+                let next = self.objects.next_property(&self.memory, object, property)?;
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, next)
+            }
+            opcodes::OP_GET_PROP_LEN => {
+                let address = self.read_typed_operand()?;
+                let len = if address == 0 { 0 } else { object::Property::at(&self.memory, address)?.len };
+                if address != 0 && len == 0 {
+                    return Err(object::ObjectError::InvalidPropertyLength { address }.into());
+                }
+                let store_var_spec = self.read_variable_operand()?;
+                self.set_variable(store_var_spec, len)
+            }
+            opcodes::OP_PUT_PROP => {
+                let object = self.read_typed_operand()?;
+                let property = self.read_typed_operand()?;
+                let value = self.read_typed_operand()?;
+                Ok(self.objects.set_property_value(&mut self.memory, object, property, value)?)
+            }
+            opcodes::OP_INSERT_OBJ => {
+                let object = self.read_typed_operand()?;
+                let destination = self.read_typed_operand()?;
+                Ok(self.objects.insert(&mut self.memory, object, destination)?)
+            }
+            opcodes::OP_REMOVE_OBJ => {
+                let object = self.read_typed_operand()?;
+                Ok(self.objects.remove(&mut self.memory, object)?)
+            }
+            opcodes::OP_PRINT => {
+                self.cpu.pc = self.print_string_at(self.cpu.pc)?;
+                Ok(())
+            }
+            opcodes::OP_PRINT_RET => {
+                self.cpu.pc = self.print_string_at(self.cpu.pc)?;
+                self.print("\n")?;
+                self.return_from_routine(1)
+            }
+            opcodes::OP_PRINT_ADDR => {
+                let address = self.read_typed_operand()?;
+                self.print_string_at(address).map(|_| ())
+            }
+            opcodes::OP_PRINT_PADDR => {
+                let p_type = self.fetch_operand_type()?;
+                if p_type != 0x03 {
+                    return Err(VmError::UnexpectedOperandType { expected: 0x03, found: p_type });
+                }
+                let packed = self.read_operand_value(p_type)?;
+                let header = self.memory.header();
+                let static_end = header.static_data_section_start + header.static_data_section_length;
+                let address = match header.static_data_section_start.checked_add(packed) {
+                    Some(addr) if addr < static_end => addr,
+                    _ => return Err(VmError::InvalidStringAddress(packed)),
+                };
+                self.print_string_at(address).map(|_| ())
+            }
+            opcodes::OP_PRINT_OBJ => {
+                let object = self.read_typed_operand()?;
+                let address = self.objects.short_name_address(&self.memory, object)?;
+                if address != 0 {
+                    self.print_string_at(address)?;
+                }
+                Ok(())
+            }
+            opcodes::OP_SREAD => {
+                let text_buffer = self.read_typed_operand()?;
+                let parse_buffer = self.read_typed_operand()?;
+                let max_len = self.memory.read_byte(text_buffer)? as usize;
+                let line = self.input.read_line(max_len).ok_or(VmError::EndOfInput)?;
+                self.store_input_line(text_buffer, parse_buffer, &line)
+            }
+            opcodes::OP_AREAD => {
+                let text_buffer = self.read_typed_operand()?;
+                let parse_buffer = self.read_typed_operand()?;
+                let timeout = self.read_operand_list(2)?;
+                let store_var = self.read_variable_operand()?;
+                let max_len = self.memory.read_byte(text_buffer)? as usize;
+                let seconds = timeout.first().copied().unwrap_or(0);
+                let routine = timeout.get(1).copied().unwrap_or(0);
+                let terminators = self.terminating_characters()?;
+                let line = self.wait_for_input(seconds, routine, |input, left| {
+                    match input.read_line_terminated(max_len, left, &terminators) {
+                        input::LineEvent::Line(line) => Ok(Some((line, input::KEY_NEWLINE))),
+                        input::LineEvent::Terminated(line, key) => Ok(Some((line, key))),
+                        input::LineEvent::TimedOut => Ok(None),
+                        input::LineEvent::End => Err(VmError::EndOfInput),
+                    }
+                })?;
+                let terminator = match line {
+                    Some((line, key)) => {
+                        self.store_input_line(text_buffer, parse_buffer, &line)?;
+                        key as u64
+                    }
+                    None => {
+                        input::store_line(&mut self.memory, text_buffer, "")?;
+                        0
+                    }
+                };
+                self.set_variable(store_var, terminator)
+            }
+            opcodes::OP_READ_CHAR => {
+                let _device = self.read_typed_operand()?;
+                let timeout = self.read_operand_list(2)?;
+                let store_var = self.read_variable_operand()?;
+                let seconds = timeout.first().copied().unwrap_or(0);
+                let routine = timeout.get(1).copied().unwrap_or(0);
+                let key = self.wait_for_input(seconds, routine, |input, left| match input.read_char_timeout(left) {
+                    input::CharEvent::Key(key) => Ok(Some(key)),
+                    input::CharEvent::TimedOut => Ok(None),
+                    input::CharEvent::End => Err(VmError::EndOfInput),
+                })?;
+                self.set_variable(store_var, key.map_or(0, u64::from))
+            }
+            opcodes::OP_TOKENISE => {
+                let text_buffer = self.read_typed_operand()?;
+                let parse_buffer = self.read_typed_operand()?;
+                let optional = self.read_operand_list(2)?;
+                let dictionary = match optional.first() {
+                    Some(&address) if address != 0 => Some(dictionary::Dictionary::new(&self.memory, address)?),
+                    _ => self.dictionary()?,
+                };
+                let skip_unknown = optional.get(1).is_some_and(|&flag| flag != 0);
+                Ok(input::tokenise(&mut self.memory, text_buffer, parse_buffer, dictionary.as_ref(), skip_unknown)?)
+            }
+            opcodes::OP_OUTPUT_STREAM => {
+                let stream = self.read_typed_operand()? as i64;
+                let table = if stream == output::STREAM_MEMORY as i64 { self.read_typed_operand()? } else { 0 };
+                Ok(self.output.select(&mut self.memory, stream, table)?)
+            }
+            opcodes::OP_SPLIT_WINDOW => {
+                let lines = self.read_typed_operand()?;
+                match self.output.screen_mut() {
+                    Some(screen) => Ok(screen.split_window(lines)?),
+                    None => Ok(()),
+                }
+            }
+            opcodes::OP_SET_WINDOW => {
+                let window = screen::Window::from_id(self.read_typed_operand()? as i64)?;
+                if let Some(screen) = self.output.screen_mut() {
+                    screen.set_window(window);
+                }
+                Ok(())
+            }
+            opcodes::OP_ERASE_WINDOW => {
+                let window = self.read_typed_operand()? as i64;
+                match self.output.screen_mut() {
+                    Some(screen) => Ok(screen.erase_window(window)?),
+                    None => Ok(()),
+                }
+            }
+            opcodes::OP_ERASE_LINE => {
+                let value = self.read_typed_operand()?;
+                if let (1, Some(screen)) = (value, self.output.screen_mut()) {
+                    screen.erase_line();
+                }
+                Ok(())
+            }
+            opcodes::OP_SET_CURSOR => {
+                let row = self.read_typed_operand()?;
+                let column = self.read_typed_operand()?;
+                let window = match self.read_operand_list(1)?.first() {
+                    Some(&id) => Some(screen::Window::from_id(id as i64)?),
+                    None => None,
+                };
+                match self.output.screen_mut() {
+                    Some(screen) => Ok(screen.set_cursor(row, column, window)?),
+                    None => Ok(()),
+                }
+            }
+            opcodes::OP_GET_CURSOR => {
+                let array = self.read_typed_operand()?;
+                // Without a screen the cursor is taken to be at the top left.
+                let (row, column) = self.output.screen().map_or((1, 1), |screen| screen.cursor());
+                self.write_qword(array, row)?;
+                self.write_qword(array.saturating_add(8), column)?;
+                Ok(())
+            }
+            opcodes::OP_BUFFER_MODE => {
+                let mode = self.read_typed_operand()?;
+                if let Some(screen) = self.output.screen_mut() {
+                    screen.set_buffered(mode != 0);
+                }
+                Ok(())
+            }
+            opcodes::OP_ENCODE_TEXT => {
+                let text_addr = self.read_typed_operand()?;
+                let length = self.read_typed_operand()?;
+                let from = self.read_typed_operand()?;
+                let buffer = self.read_typed_operand()?;
+                let zscii: Vec<u16> = self.memory.read_slice(text_addr.saturating_add(from), length)?
+                    .iter()
+                    .take_while(|&&b| b != 0)
+                    .map(|&b| b as u16)
+                    .collect();
+                let encoded = text::encode_dictionary_word(&zscii);
+                Ok(self.memory.write_slice(buffer, &encoded)?)
             }
             opcodes::OP_JUMP => {
-                let offset_val = self.read_word(self.cpu.pc).map_err(|e| format!("JUMP: Offset read: {:?}", e))? as i16;
+                let offset_val = self.read_word(self.cpu.pc)? as i16;
                 self.cpu.pc += 2;
-                let new_pc_signed = self.cpu.pc as i64 + offset_val as i64;
-                if new_pc_signed < 0 { return Err(format!("JUMP: Negative PC target: {}", new_pc_signed)); }
-                self.cpu.pc = new_pc_signed as u64;
-                Ok(())
+                self.jump_relative(offset_val as i64)
+            }
+            opcodes::OP_JZ => {
+                let value = self.read_typed_operand()?;
+                self.branch(value == 0)
+            }
+            opcodes::OP_JE => {
+                let val1 = self.read_typed_operand()?;
+                let val2 = self.read_typed_operand()?;
+                self.branch(val1 == val2)
+            }
+            opcodes::OP_JE_VARARGS => {
+                self.require_extended_opcodes(opcode)?;
+                let value1 = self.read_typed_operand()?;
+                let comparands = self.read_operand_list(Self::MAX_JE_COMPARANDS)?;
+                if comparands.is_empty() {
+                    return Err(VmError::TooFewOperands { min: 1 });
+                }
+                self.branch(comparands.contains(&value1))
+            }
+            opcodes::OP_JL => {
+                let val1 = self.read_typed_operand()? as i64;
+                let val2 = self.read_typed_operand()? as i64;
+                self.branch(val1 < val2)
+            }
+            opcodes::OP_JG => {
+                let val1 = self.read_typed_operand()? as i64;
+                let val2 = self.read_typed_operand()? as i64;
+                self.branch(val1 > val2)
             }
             opcodes::OP_CALL => {
-                let p_type = self.fetch_operand_type().map_err(|e| format!("CALL: PADDR Type: {:?}", e))?;
-                if p_type != 0x03 { return Err(format!("CALL: Routine address must be PADDR type (0x03), got {:#04x}", p_type));}
-                let p_packed = self.read_operand_value(p_type)?;
-
-                let target_addr = self.memory.header().code_section_start + p_packed;
-                let num_args_supplied: u8 = 0;
-                let store_var = self.read_variable_operand().map_err(|e| format!("CALL: Store var: {:?}", e))?;
-
-                let pc_after_call_operands = self.cpu.pc;
-                let aligned_return_pc = (pc_after_call_operands + (Self::OPCODE_SIZE - 1)) & !(Self::OPCODE_SIZE - 1);
-
-                self.push_stack(aligned_return_pc).map_err(|e| format!("CALL: Push PC: {:?}",e))?;
-                self.push_stack(self.cpu.fp).map_err(|e| format!("CALL: Push FP: {:?}",e))?;
-                self.push_stack(store_var as u64).map_err(|e| format!("CALL: Push store_var_ref: {:?}",e))?;
-                self.push_stack(num_args_supplied as u64).map_err(|e| format!("CALL: Push num_args: {:?}",e))?;
-
-                let num_locals_from_routine = self.read_byte(target_addr).map_err(|e| format!("CALL: Read num_locals from routine header: {:?}", e))? as usize;
-                self.push_stack(num_locals_from_routine as u64).map_err(|e| format!("CALL: Push num_locals_count: {:?}",e))?;
-                self.cpu.fp = self.cpu.sp;
-
-                self.cpu.pc = target_addr + Self::OPCODE_SIZE;
-                for _ in 0..num_locals_from_routine {
-                    self.push_stack(0).map_err(|e| format!("CALL: Push initial local value: {:?}",e))?;
+                let packed = self.read_routine_operand()?;
+                let args = self.read_operand_list(Self::MAX_CALL_ARGS)?;
+                let store_var = self.read_variable_operand()?;
+                self.call_routine(packed, &args, store_var as u64)
+            }
+            opcodes::OP_CALL_VN => {
+                self.require_extended_opcodes(opcode)?;
+                let packed = self.read_routine_operand()?;
+                let args = self.read_operand_list(Self::MAX_CALL_ARGS)?;
+                self.call_routine(packed, &args, Self::DISCARD_RESULT)
+            }
+            opcodes::OP_CALL_1N => {
+                self.require_extended_opcodes(opcode)?;
+                let packed = self.read_routine_operand()?;
+                self.call_routine(packed, &[], Self::DISCARD_RESULT)
+            }
+            opcodes::OP_CHECK_ARG_COUNT => {
+                let argument_number = self.read_typed_operand()?;
+                if argument_number == 0 {
+                    return Err(VmError::InvalidArgumentNumber(argument_number));
                 }
-                Ok(())
+                let num_args = self.read_qword(self.cpu.fp + 8)?;
+                self.branch(num_args >= argument_number)
             }
             opcodes::OP_RET => {
-                let val_type = self.fetch_operand_type().map_err(|e| format!("RET: Val type: {:?}", e))?;
+                let val_type = self.fetch_operand_type()?;
                 let value = self.read_operand_value(val_type)?;
-
-                self.cpu.sp = self.cpu.fp;
-                let _num_locals_on_stack = self.pop_stack().map_err(|e| format!("RET: pop num_locals_count failed: {:?}",e))? as usize;
-
-                let _num_args_supplied_on_stack = self.pop_stack().map_err(|e| format!("RET: pop num_args failed: {:?}",e))? as usize;
-                let store_var_ref = self.pop_stack().map_err(|e| format!("RET: pop store_var_ref failed: {:?}",e))? as u8;
-                self.cpu.fp = self.pop_stack().map_err(|e| format!("RET: pop old FP failed: {:?}",e))?;
-                self.cpu.pc = self.pop_stack().map_err(|e| format!("RET: pop return PC failed: {:?}",e))?;
-
-                self.set_variable(store_var_ref, value).map_err(|e| format!("RET: set_variable for return value: {}", e))
+                self.return_from_routine(value)
             }
-            opcodes::OP_RTRUE | opcodes::OP_RFALSE => {
-                let return_value = if opcode == opcodes::OP_RTRUE { 1 } else { 0 };
-                self.cpu.sp = self.cpu.fp;
-                let _num_locals_on_stack = self.pop_stack().map_err(|e| format!("RVAL: pop num_locals failed: {:?}",e))? as usize;
-                let _num_args_supplied_on_stack = self.pop_stack().map_err(|e| format!("RVAL: pop num_args failed: {:?}",e))? as usize;
-                let store_var_ref = self.pop_stack().map_err(|e| format!("RVAL: pop store_var_ref failed: {:?}",e))? as u8;
-                self.cpu.fp = self.pop_stack().map_err(|e| format!("RVAL: pop old FP failed: {:?}",e))?;
-                self.cpu.pc = self.pop_stack().map_err(|e| format!("RVAL: pop return PC failed: {:?}",e))?;
-                self.set_variable(store_var_ref, return_value).map_err(|e| format!("RVAL: set_variable for return: {}", e))
+            opcodes::OP_RTRUE => self.return_from_routine(1),
+            opcodes::OP_RFALSE => self.return_from_routine(0),
+            opcodes::OP_VERIFY => {
+                let ok = self.verify();
+                self.branch(ok)
             }
             _ => {
                 self.running = false;
-                Err(format!("Unknown opcode: {:#018x} at PC={:#010x}", opcode, self.cpu.pc - Self::OPCODE_SIZE ))
+                Err(VmError::UnknownOpcode(opcode))
             }
         }
     }
 
-    pub fn run(&mut self) -> Result<(), String> {
+    /// Runs until QUIT. Errors are returned as `VmError::Execution`, carrying
+    /// the PC the faulting opcode was fetched from. An error inside a routine
+    /// run from an opcode, such as a timeout routine, is wrapped again with
+    /// the PC of that opcode.
+    pub fn run(&mut self) -> Result<(), VmError> {
         self.running = true;
         while self.running {
-            let current_pc_before_fetch = self.cpu.pc;
-            match self.fetch_opcode() {
-                Ok(opcode) => {
-                    if let Err(e) = self.decode_and_execute_opcode(opcode) {
-                        self.running = false;
-                        return Err(format!("Execution error: {} for opcode {:#018x} fetched from PC={:#010x}", e, opcode, current_pc_before_fetch));
-                    }
-                }
-                Err(MemoryError::OutOfBounds) => {
-                    self.running = false;
-                    return Err(format!("MemoryError::OutOfBounds at PC={:#010x}", current_pc_before_fetch));
-                }
-                Err(MemoryError::CpuStackError(ref err)) => {
-                    self.running = false;
-                    return Err(format!("MemoryError::CpuStackError({:?}) at PC={:#010x}", err, current_pc_before_fetch));
-                }
-                Err(MemoryError::InternalError(s)) => {
-                    self.running = false;
-                    return Err(format!("MemoryError::InternalError: {} at PC={:#010x}", s, current_pc_before_fetch));
-                }
-                // StackOverflow and StackUnderflow are now caught by CpuStackError
-                // Err(MemoryError::StackOverflow) => { ... }
-                // Err(MemoryError::StackUnderflow) => { ... }
+            if let Err(e) = self.step() {
+                self.running = false;
+                return Err(e);
             }
         }
         Ok(())
     }
 
+    /// Fetches and executes one instruction.
+    fn step(&mut self) -> Result<(), VmError> {
+        let current_pc_before_fetch = self.cpu.pc;
+        let opcode = self.fetch_opcode()
+            .map_err(|e| VmError::from(e).at(current_pc_before_fetch, None))?;
+        self.decode_and_execute_opcode(opcode)
+            .map_err(|e| e.at(current_pc_before_fetch, Some(opcode)))
+    }
+
     // --- Old methods ---
     pub fn old_load_header(&mut self, _story_data: &[u8]) -> Result<(), String> { Err("deprecated".to_string()) }
     pub fn old_load_story(&mut self, _story_data: &[u8]) -> Result<(), String> { Err("deprecated".to_string()) }
@@ -368,14 +1066,42 @@ impl VirtualMachine {
     pub fn old_run_cycle_decoded(&mut self) -> Result<(), String> { Err("deprecated".to_string()) }
 }
 
+/// Applies a signed division (`div` or `mod`) to two 64-bit values. Division
+/// by zero is `VmError::DivisionByZero` if `halt_on_zero` is set, and yields 0
+/// otherwise. `i64::MIN / -1` wraps to `i64::MIN` (remainder 0).
+fn signed_division(dividend: u64, divisor: u64, halt_on_zero: bool, op: fn(i64, i64) -> i64) -> Result<u64, VmError> {
+    if divisor == 0 {
+        return if halt_on_zero { Err(VmError::DivisionByZero) } else { Ok(0) };
+    }
+    Ok(op(dividend as i64, divisor as i64) as u64)
+}
+
+/// Shifts `value` left by `places`, or right by `-places` if negative. Right
+/// shifts are arithmetic (sign-filling) when `arithmetic` is set. Shifting by
+/// 64 or more places shifts every bit out.
+fn shift(value: u64, places: i64, arithmetic: bool) -> u64 {
+    let distance = places.unsigned_abs().min(64) as u32;
+    if places >= 0 {
+        value.checked_shl(distance).unwrap_or(0)
+    } else if arithmetic {
+        ((value as i64) >> distance.min(63)) as u64
+    } else {
+        value.checked_shr(distance).unwrap_or(0)
+    }
+}
+
 // --- Tests ---
 #[cfg(test)]
 mod tests {
     use super::*;
-    use byteorder::WriteBytesExt;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
     use tempfile::NamedTempFile;
     use std::io::Write;
     use crate::header::create_dummy_header_bytes;
+    use crate::input::Clock;
 
     #[test]
     fn vm_instantiation_new_is_gone() { }
@@ -390,6 +1116,12 @@ mod tests {
         assert_eq!(1,1);
     }
 
+    /// Static section length used by tests; large enough for the dummy header's tables.
+    const TEST_STATIC_LEN: u64 = 2048;
+    /// Dynamic section length used by tests: 1920 bytes of globals plus a 128-byte stack.
+    const TEST_DYNAMIC_LEN: u64 = 2048;
+
+    #[allow(clippy::too_many_arguments)]
     fn create_test_story_file_bytes(
         version: u16, code_start: u64, code_len: u64,
         static_start: u64, static_len: u64,
@@ -397,22 +1129,24 @@ mod tests {
         opcodes_to_embed: Option<&[u64]>
     ) -> Vec<u8> {
         let mut story_bytes = create_dummy_header_bytes();
-        let mut cursor = Cursor::new(&mut story_bytes);
-
-        // Corrected Offsets based on StoryHeader struct:
-        cursor.set_position(0); cursor.write_u16::<BigEndian>(version).unwrap(); // version: 0
-        cursor.set_position(20); cursor.write_u64::<BigEndian>(code_start).unwrap(); // code_section_start: 20
-        cursor.set_position(28); cursor.write_u64::<BigEndian>(code_len).unwrap(); // code_section_length: 28
-        cursor.set_position(36); cursor.write_u64::<BigEndian>(static_start).unwrap(); // static_data_section_start: 36
-        cursor.set_position(44); cursor.write_u64::<BigEndian>(static_len).unwrap(); // static_data_section_length: 44
-        cursor.set_position(52); cursor.write_u64::<BigEndian>(dynamic_start).unwrap(); // dynamic_data_section_start: 52
-        cursor.set_position(60); cursor.write_u64::<BigEndian>(dynamic_len).unwrap(); // dynamic_data_section_length: 60
-
-        drop(cursor);
-        let required_len_after_header = std::cmp::max(
-            code_start + code_len,
-            std::cmp::max(static_start + static_len, dynamic_start + dynamic_len)
-        );
+        let mut header = header::StoryHeader::from_bytes(&story_bytes).unwrap();
+        // Keep the dummy header's tables at the same offsets within the static section.
+        let relocate = |addr: u64| static_start + (addr - header.static_data_section_start);
+        header.globals_table_start = relocate(header.globals_table_start);
+        header.objects_table_start = relocate(header.objects_table_start);
+        header.dictionary_table_start = relocate(header.dictionary_table_start);
+        header.abbreviations_table_start = relocate(header.abbreviations_table_start);
+        header.version = version;
+        header.code_section_start = code_start;
+        header.code_section_length = code_len;
+        header.static_data_section_start = static_start;
+        header.static_data_section_length = static_len;
+        header.dynamic_data_section_start = dynamic_start;
+        header.dynamic_data_section_length = dynamic_len;
+        header.patch_into(&mut story_bytes).unwrap();
+
+        // The VM allocates the dynamic section, so the file stops after static data.
+        let required_len_after_header = std::cmp::max(code_start + code_len, static_start + static_len);
         let total_required_story_size = std::cmp::max(1024, required_len_after_header) as usize;
         if story_bytes.len() < total_required_story_size {
             story_bytes.resize(total_required_story_size, 0xDA);
@@ -451,6 +1185,46 @@ mod tests {
         story_bytes
     }
 
+    /// Builds a story whose code section holds exactly `code`, followed by the
+    /// test static and dynamic sections.
+    fn build_story_with_code(code: &[u8]) -> Vec<u8> {
+        let code_start = 1024u64;
+        let code_len = code.len() as u64;
+        let static_start = code_start + code_len;
+        let dynamic_start = static_start + TEST_STATIC_LEN;
+        let mut story_bytes = create_test_story_file_bytes(
+            memory::SUPPORTED_VERSION,
+            code_start, code_len,
+            static_start, TEST_STATIC_LEN,
+            dynamic_start, TEST_DYNAMIC_LEN,
+            None
+        );
+        story_bytes[code_start as usize .. (code_start + code_len) as usize].copy_from_slice(code);
+        story_bytes
+    }
+
+    /// Sets `FLAGS2_ENABLE_EXTENDED_OPCODES` in a story's header.
+    fn enable_extended_opcodes(story_bytes: &mut [u8]) {
+        let mut header = header::StoryHeader::from_bytes(story_bytes).unwrap();
+        header.set_extended_opcodes_enabled(true);
+        header.patch_into(story_bytes).unwrap();
+    }
+
+    fn load_vm_with_options(story_bytes: &[u8], options: LoadOptions) -> Result<VirtualMachine, StoryFileError> {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(story_bytes).unwrap();
+        VirtualMachine::load_story_with_options(temp_file.path().to_str().unwrap(), options)
+    }
+
+    fn load_vm(story_bytes: &[u8]) -> VirtualMachine {
+        load_vm_with_options(story_bytes, LoadOptions::default()).unwrap()
+    }
+
+    fn fix_checksum(story_bytes: &mut [u8]) {
+        let checksum = header::compute_checksum(story_bytes);
+        story_bytes[12..20].copy_from_slice(&checksum.to_be_bytes());
+    }
+
     #[test]
     fn test_load_story_valid_minimal() { /* ... */ }
     #[test]
@@ -459,7 +1233,7 @@ mod tests {
         let mut temp_file = NamedTempFile::new().unwrap();
         temp_file.write_all(&story_data).unwrap();
         let result = VirtualMachine::load_story(temp_file.path().to_str().unwrap());
-        assert!(matches!(result, Err(StoryFileError::MemoryInitialization(_))));
+        assert_eq!(result.unwrap_err(), StoryFileError::InvalidHeader(header::HeaderError::TooShort { len: 512 }));
     }
     #[test]
     fn test_load_story_unsupported_version() {
@@ -467,11 +1241,1603 @@ mod tests {
         let mut temp_file = NamedTempFile::new().unwrap();
         temp_file.write_all(&story_bytes).unwrap();
         let result = VirtualMachine::load_story(temp_file.path().to_str().unwrap());
-        assert!(matches!(result, Err(StoryFileError::MemoryInitialization(_))));
-        if let Err(StoryFileError::MemoryInitialization(s)) = result {
-            assert!(s.contains("Unsupported Z-machine version"));
+        assert_eq!(result.unwrap_err(), StoryFileError::UnsupportedVersion(0x0100));
+    }
+    #[test]
+    fn test_load_story_allocates_dynamic_section_and_globals() {
+        // LOAD G05 -> stack; STORE G06 <- SC 9; QUIT
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_LOAD.to_be_bytes());
+        code.push(0x15);
+        code.push(0x00);
+        code.extend_from_slice(&opcodes::OP_STORE.to_be_bytes());
+        code.push(0x16);
+        code.push(0x01);
+        code.push(9);
+        code.extend_from_slice(&opcodes::OP_QUIT.to_be_bytes());
+        let mut story_bytes = build_story_with_code(&code);
+
+        let static_start = 1024 + code.len();
+        let dynamic_start = (static_start as u64) + TEST_STATIC_LEN;
+        assert_eq!(story_bytes.len() as u64, dynamic_start, "file should end with the static section");
+        let g05 = static_start + 5 * 8; // globals table is at the start of static data
+        story_bytes[g05..g05 + 8].copy_from_slice(&1234u64.to_be_bytes());
+
+        let mut vm = load_vm(&story_bytes);
+        assert_eq!(vm.memory.size(), dynamic_start + TEST_DYNAMIC_LEN);
+        vm.run().unwrap();
+        assert_eq!(vm.read_qword(vm.cpu.sp).unwrap(), 1234);
+        // Live globals are in dynamic memory; the static table keeps its initial values.
+        assert_eq!(vm.read_qword(dynamic_start + 6 * 8).unwrap(), 9);
+        assert_eq!(vm.read_qword(static_start as u64 + 6 * 8).unwrap(), 0x5A5A5A5A5A5A5A5A);
+    }
+
+    #[test]
+    fn test_store_to_protected_region() {
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_QUIT.to_be_bytes());
+        let story_bytes = build_story_with_code(&code);
+        let static_start = 1024 + code.len() as u64;
+        let dynamic_start = static_start + TEST_STATIC_LEN;
+
+        let mut vm = load_vm(&story_bytes);
+        assert_eq!(vm.write_byte(0x10, 1), Err(MemoryError::WriteProtected { address: 0x10, region: memory::Region::Header }));
+        assert_eq!(vm.write_qword(1024, 1), Err(MemoryError::WriteProtected { address: 1024, region: memory::Region::Code }));
+        assert_eq!(vm.write_word(static_start, 1), Err(MemoryError::WriteProtected { address: static_start, region: memory::Region::StaticData }));
+        // A word straddling the static/dynamic boundary is rejected at its first protected byte.
+        assert_eq!(vm.write_qword(dynamic_start - 4, 1), Err(MemoryError::WriteProtected { address: dynamic_start - 4, region: memory::Region::StaticData }));
+        assert!(vm.write_qword(dynamic_start, 1).is_ok());
+
+        vm.set_write_protection(false);
+        assert!(vm.write_qword(1024, opcodes::OP_NOP).is_ok());
+        assert_eq!(vm.read_qword(1024).unwrap(), opcodes::OP_NOP);
+
+        let options = LoadOptions { writable_static_data: true, ..LoadOptions::default() };
+        let mut vm = load_vm_with_options(&story_bytes, options).unwrap();
+        assert!(vm.write_word(static_start, 1).is_ok());
+        assert!(vm.write_byte(1024, 1).is_err());
+    }
+
+    #[test]
+    fn test_load_story_rejects_invalid_layout() {
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_QUIT.to_be_bytes());
+        let story_bytes = build_story_with_code(&code);
+
+        // Truncate the file so the static section is no longer complete.
+        let truncated = &story_bytes[..story_bytes.len() - 16];
+        match load_vm_with_options(truncated, LoadOptions::default()) {
+            Err(StoryFileError::SectionTooLarge(e)) => {
+                assert_eq!(e.field, "static_data_section");
+                assert!(matches!(e.problem, header::LayoutProblem::ExceedsStoryFile { .. }));
+            }
+            other => panic!("Expected SectionTooLarge, got {:?}", other.map(|_| ())),
+        }
+
+        // Point the objects table into the code section.
+        let mut bad_pointer = story_bytes.clone();
+        bad_pointer[76..84].copy_from_slice(&1024u64.to_be_bytes());
+        match load_vm_with_options(&bad_pointer, LoadOptions::default()) {
+            Err(StoryFileError::InvalidLayout(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].field, "objects_table");
+                assert_eq!(errors[0].start, 1024);
+            }
+            other => panic!("Expected InvalidLayout, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_load_story_checksum_option() {
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_QUIT.to_be_bytes());
+        let mut story_bytes = build_story_with_code(&code);
+        let strict = LoadOptions { verify_checksum: true, ..LoadOptions::default() };
+
+        // The dummy header carries a bogus checksum: only the strict load rejects it.
+        assert!(load_vm_with_options(&story_bytes, LoadOptions::default()).is_ok());
+        assert!(matches!(load_vm_with_options(&story_bytes, strict), Err(StoryFileError::ChecksumMismatch)));
+
+        fix_checksum(&mut story_bytes);
+        let vm = load_vm_with_options(&story_bytes, strict).unwrap();
+        assert!(vm.verify());
+
+        // Corrupt a code byte after the checksum was computed.
+        story_bytes[1030] ^= 0xFF;
+        assert!(matches!(load_vm_with_options(&story_bytes, strict), Err(StoryFileError::ChecksumMismatch)));
+        assert!(!load_vm(&story_bytes).verify());
+    }
+
+    #[test]
+    fn test_op_verify() {
+        // VERIFY [branch on true, skip the PUSH]; PUSH SC 7; QUIT
+        assert_eq!(opcodes::OP_VERIFY, 0x0008, "the spec's 0OP verify");
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_VERIFY.to_be_bytes());
+        code.push(0x80); // sense = true, 8-bit offset
+        code.push(10); // skip PUSH (8 + 1 + 1 bytes)
+        code.extend_from_slice(&opcodes::OP_PUSH.to_be_bytes());
+        code.push(0x01);
+        code.push(7);
+        code.extend_from_slice(&opcodes::OP_QUIT.to_be_bytes());
+
+        let mut story_bytes = build_story_with_code(&code);
+        let mut vm = load_vm(&story_bytes);
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        assert_eq!(vm.cpu.sp, initial_sp - 8, "Bad checksum must not branch");
+        assert_eq!(vm.read_qword(vm.cpu.sp).unwrap(), 7);
+
+        fix_checksum(&mut story_bytes);
+        let mut vm = load_vm(&story_bytes);
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        assert_eq!(vm.cpu.sp, initial_sp, "Good checksum must branch over the PUSH");
+    }
+
+    /// Appends an opcode followed by its operand bytes to `code`.
+    fn emit(code: &mut Vec<u8>, opcode: u64, operands: &[u8]) {
+        code.extend_from_slice(&opcode.to_be_bytes());
+        code.extend_from_slice(operands);
+    }
+
+    /// Runs `code` to QUIT and returns the values left on the stack, bottom first.
+    fn run_and_collect_stack(code: &[u8]) -> Vec<u64> {
+        run_story_and_collect_stack(&build_story_with_code(code))
+    }
+
+    /// As `run_and_collect_stack`, for a story with extended opcodes enabled.
+    fn run_extended_and_collect_stack(code: &[u8]) -> Vec<u64> {
+        let mut story_bytes = build_story_with_code(code);
+        enable_extended_opcodes(&mut story_bytes);
+        run_story_and_collect_stack(&story_bytes)
+    }
+
+    fn run_story_and_collect_stack(story_bytes: &[u8]) -> Vec<u64> {
+        let mut vm = load_vm(story_bytes);
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        let mut stack: Vec<u64> = (vm.cpu.sp..initial_sp).step_by(8).map(|a| vm.read_qword(a).unwrap()).collect();
+        stack.reverse();
+        stack
+    }
+
+    /// Lays out `main`, padded to the opcode size, followed by a routine with
+    /// one local per entry of `defaults` and body `body`. Returns the code and
+    /// the routine's packed address.
+    fn code_with_routine(main: &[u8], defaults: &[u64], body: &[u8]) -> (Vec<u8>, u32) {
+        let mut code = main.to_vec();
+        code.resize(main.len().next_multiple_of(8), 0);
+        let paddr = code.len() as u32;
+        code.push(defaults.len() as u8);
+        code.resize(code.len() + 7, 0); // routine header is padded to the opcode size
+        for default in defaults {
+            code.extend_from_slice(&default.to_be_bytes());
+        }
+        code.extend_from_slice(body);
+        (code, paddr)
+    }
+
+    /// Calls a routine with `args` (type/value pairs), local defaults
+    /// `defaults` and body `body`, and returns the value it returned.
+    fn call_routine_returning(args: &[u8], defaults: &[u64], body: &[u8]) -> u64 {
+        let call_len = 8 + 5 + args.len() + 2;
+        let main_len = call_len.next_multiple_of(8) + 8; // CALL, padding, QUIT
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_CALL, &[0x03]);
+        main.extend_from_slice(&(main_len as u32).to_be_bytes());
+        main.extend_from_slice(args);
+        main.extend_from_slice(&[opcodes::OPERAND_TYPE_OMITTED, 0x00]);
+        main.resize(call_len.next_multiple_of(8), 0);
+        emit(&mut main, opcodes::OP_QUIT, &[]);
+        let (code, paddr) = code_with_routine(&main, defaults, body);
+        assert_eq!(paddr as usize, main_len);
+        let stack = run_and_collect_stack(&code);
+        assert_eq!(stack.len(), 1);
+        stack[0]
+    }
+
+    #[test]
+    fn test_op_jz() {
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_JZ, &[0x01, 0, 0x80, 10]); // taken: skip PUSH 1
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        emit(&mut code, opcodes::OP_JZ, &[0x01, 5, 0xC0, 0x00, 10]); // 16-bit offset, not taken
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 2]);
+        emit(&mut code, opcodes::OP_JZ, &[0x01, 5, 0x00, 10]); // sense false: taken, skip PUSH 3
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 3]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![2]);
+
+        // Backward 16-bit branch: count G00 down from 3 to 0, pushing each value.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_STORE, &[0x10, 0x01, 3]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x02, 0x10]); // loop start, offset 11
+        emit(&mut code, opcodes::OP_SUB, &[0x02, 0x10, 0x01, 1, 0x10]);
+        emit(&mut code, opcodes::OP_JZ, &[0x02, 0x10, 0x40]); // branch while G00 != 0
+        code.extend_from_slice(&(11i16 - (code.len() as i16 + 2)).to_be_bytes());
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_op_je() {
+        // The spec's 2OP layout: type1 value1 type2 value2, then branch data.
+        // A branch byte of 0x00 (sense false, short offset) must not be taken
+        // for another operand type.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_JE, &[0x01, 4, 0x01, 4, 0x80, 10]); // JE 4, 4 - taken: skip PUSH 1
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        emit(&mut code, opcodes::OP_JE, &[0x01, 4, 0x01, 5, 0x00, 10]); // JE 4, 5 [sense false] - taken: skip PUSH 2
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 2]);
+        emit(&mut code, opcodes::OP_JE, &[0x01, 4, 0x01, 5, 0x80, 10]); // JE 4, 5 - not taken
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 3]);
+        // JE LC, VAR - compares full 64-bit values
+        emit(&mut code, opcodes::OP_STORE, &[0x11, 0x00]);
+        code.extend_from_slice(&u64::MAX.to_be_bytes());
+        emit(&mut code, opcodes::OP_JE, &[0x00]);
+        code.extend_from_slice(&u64::MAX.to_be_bytes());
+        code.extend_from_slice(&[0x02, 0x11, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 4]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![3]);
+    }
+
+    #[test]
+    fn test_op_je_varargs() {
+        let mut code = Vec::new();
+        // JE_VARARGS 4, [1, 2, 4] - taken: skip PUSH 1
+        emit(&mut code, opcodes::OP_JE_VARARGS, &[0x01, 4, 0x01, 1, 0x01, 2, 0x01, 4, 0xFF, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        // JE_VARARGS 4, [5] - not taken
+        emit(&mut code, opcodes::OP_JE_VARARGS, &[0x01, 4, 0x01, 5, 0xFF, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 2]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_extended_and_collect_stack(&code), vec![2]);
+
+        // It is an extension, unknown unless the story enables it.
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::UnknownOpcode(opcodes::OP_JE_VARARGS));
+
+        let extended_error = |code: &[u8]| {
+            let mut story_bytes = build_story_with_code(code);
+            enable_extended_opcodes(&mut story_bytes);
+            load_vm(&story_bytes).run().unwrap_err()
+        };
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_JE_VARARGS, &[0x01, 1, 0x01, 1, 0x01, 2, 0x01, 3, 0x01, 4, 0xFF, 0x80, 10]);
+        assert_eq!(extended_error(&code).kind(), &VmError::TooManyOperands { max: 3 });
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_JE_VARARGS, &[0x01, 1, 0xFF, 0x80, 10]);
+        assert_eq!(extended_error(&code).kind(), &VmError::TooFewOperands { min: 1 });
+    }
+
+    #[test]
+    fn test_op_jl_jg_signed() {
+        let minus_one = (-1i64 as u64).to_be_bytes();
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_JL, &[0x00]); // -1 < 1: taken
+        code.extend_from_slice(&minus_one);
+        code.extend_from_slice(&[0x01, 1, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        emit(&mut code, opcodes::OP_JG, &[0x00]); // -1 > 1: not taken
+        code.extend_from_slice(&minus_one);
+        code.extend_from_slice(&[0x01, 1, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 2]);
+        emit(&mut code, opcodes::OP_JG, &[0x01, 1, 0x00]); // 1 > -1: taken
+        code.extend_from_slice(&minus_one);
+        code.extend_from_slice(&[0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 3]);
+        emit(&mut code, opcodes::OP_JL, &[0x01, 1, 0x01, 1, 0x80, 10]); // 1 < 1: not taken
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 4]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_branch_offsets_return_from_routine() {
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_JZ, &[0x01, 0, 0x80, 1]); // offset 1: rtrue
+        assert_eq!(call_routine_returning(&[], &[], &body), 1);
+
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_JZ, &[0x01, 7, 0x00, 0]); // offset 0: rfalse
+        assert_eq!(call_routine_returning(&[], &[], &body), 0);
+
+        // Offsets 0 and 1 in the 16-bit form also return.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_JZ, &[0x01, 0, 0xC0, 0, 1]);
+        assert_eq!(call_routine_returning(&[], &[], &body), 1);
+    }
+
+    #[test]
+    fn test_op_call_passes_arguments() {
+        // ADD L00 L01 -> L02; RET L02
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_ADD, &[0x02, 0x01, 0x02, 0x02, 0x03]);
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x03]);
+        assert_eq!(call_routine_returning(&[0x01, 20, 0x01, 22], &[0; 3], &body), 42);
+
+        // Extra arguments are dropped; locals without an argument take their default.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x02]); // RET L01
+        assert_eq!(call_routine_returning(&[0x01, 1, 0x01, 2, 0x01, 3], &[0; 2], &body), 2);
+        assert_eq!(call_routine_returning(&[0x01, 1], &[0; 2], &body), 0);
+
+        // Arguments may be large constants.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_SUB, &[0x02, 0x01, 0x02, 0x02, 0x00]);
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x00]);
+        let mut args = vec![0x00];
+        args.extend_from_slice(&1000u64.to_be_bytes());
+        args.extend_from_slice(&[0x01, 0]);
+        assert_eq!(call_routine_returning(&args, &[0; 2], &body), 1000);
+    }
+
+    #[test]
+    fn test_routine_local_defaults() {
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x03]); // RET L02
+        assert_eq!(call_routine_returning(&[], &[10, 20, 30], &body), 30);
+        assert_eq!(call_routine_returning(&[0x01, 1], &[10, 20, 30], &body), 30);
+        assert_eq!(call_routine_returning(&[0x01, 1, 0x01, 2, 0x01, 3], &[10, 20, 30], &body), 3);
+
+        // The body starts after the defaults, so code can follow any number of locals.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_ADD, &[0x02, 0x01, 0x02, 0x0A, 0x00]); // L00 + L09
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x00]);
+        let defaults: Vec<u64> = (1..=10).collect();
+        assert_eq!(call_routine_returning(&[], &defaults, &body), 11);
+        assert_eq!(call_routine_returning(&[0x01, 100], &defaults, &body), 110);
+    }
+
+    #[test]
+    fn test_op_check_arg_count() {
+        // CHECK_ARG_COUNT 2 [rfalse if not]; RET L01
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_CHECK_ARG_COUNT, &[0x01, 2, 0x00, 0]);
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x02]);
+        assert_eq!(call_routine_returning(&[], &[5, 6], &body), 0);
+        assert_eq!(call_routine_returning(&[0x01, 1], &[5, 6], &body), 0);
+        assert_eq!(call_routine_returning(&[0x01, 1, 0x01, 9], &[5, 6], &body), 9);
+        assert_eq!(call_routine_returning(&[0x01, 1, 0x01, 9, 0x01, 3], &[5, 6], &body), 9);
+
+        // Arguments the routine has no local for are dropped, not counted.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_CHECK_ARG_COUNT, &[0x01, 1, 0x80, 1]); // rtrue
+        emit(&mut body, opcodes::OP_RFALSE, &[]);
+        assert_eq!(call_routine_returning(&[0x01, 1, 0x01, 2, 0x01, 3], &[0], &body), 1);
+        assert_eq!(call_routine_returning(&[0x01, 1, 0x01, 2, 0x01, 3], &[], &body), 0);
+
+        // Argument numbers are 1-based.
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_CALL, &[0x03, 0, 0, 0, 16, 0xFF, 0x00]);
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_CHECK_ARG_COUNT, &[0x01, 0, 0x80, 1]);
+        let (code, _) = code_with_routine(&main, &[], &body);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::InvalidArgumentNumber(0));
+    }
+
+    #[test]
+    fn test_op_call_records_frame() {
+        // A routine with 1 local called with 3 arguments: stop inside it and inspect the frame.
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_CALL, &[0x03, 0, 0, 0, 24, 0x01, 7, 0x01, 8, 0x01, 9, 0xFF, 0x10]);
+        let (code, paddr) = code_with_routine(&main, &[0], &opcodes::OP_QUIT.to_be_bytes());
+        assert_eq!(paddr, 24);
+        let mut vm = load_vm(&build_story_with_code(&code));
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        let fp = vm.cpu.fp;
+        assert_eq!(fp, initial_sp - 5 * 8);
+        assert_eq!(vm.read_qword(fp + 32).unwrap(), 1024 + 24, "return PC (aligned)");
+        assert_eq!(vm.read_qword(fp + 24).unwrap(), initial_sp, "old FP");
+        assert_eq!(vm.read_qword(fp + 16).unwrap(), 0x10, "store variable");
+        assert_eq!(vm.read_qword(fp + 8).unwrap(), 1, "argument count, clamped to the locals");
+        assert_eq!(vm.read_qword(fp).unwrap(), 1, "local count");
+        assert_eq!(vm.read_qword(fp - 8).unwrap(), 7, "L00");
+        assert_eq!(vm.cpu.sp, fp - 8);
+    }
+
+    #[test]
+    fn test_op_call_through_variable() {
+        // STORE G01 <- PADDR; CALL G01 -> stack; QUIT; routine: RET 5
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_STORE, &[0x11, 0x03, 0, 0, 0, 40]);
+        emit(&mut main, opcodes::OP_CALL, &[0x02, 0x11, 0xFF, 0x00]);
+        main.resize(32, 0);
+        emit(&mut main, opcodes::OP_QUIT, &[]);
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_RET, &[0x01, 5]);
+        let (code, paddr) = code_with_routine(&main, &[], &body);
+        assert_eq!(paddr, 40);
+        assert_eq!(run_and_collect_stack(&code), vec![5]);
+    }
+
+    #[test]
+    fn test_op_call_vn_and_call_1n_discard_result() {
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x01]); // RET L00
+        for (opcode, operands) in [
+            (opcodes::OP_CALL_VN, vec![0x03, 0, 0, 0, 24, 0x01, 99, 0xFF]),
+            (opcodes::OP_CALL_1N, vec![0x03, 0, 0, 0, 24]),
+        ] {
+            let mut main = Vec::new();
+            emit(&mut main, opcode, &operands);
+            main.resize(16, 0);
+            emit(&mut main, opcodes::OP_QUIT, &[]);
+            let (code, paddr) = code_with_routine(&main, &[0], &body);
+            assert_eq!(paddr, 24);
+            assert_eq!(run_extended_and_collect_stack(&code), Vec::<u64>::new());
+
+            // Both are extensions, unknown unless the story enables them.
+            let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+            assert_eq!(err.kind(), &VmError::UnknownOpcode(opcode));
+        }
+    }
+
+    #[test]
+    fn test_op_call_packed_address_zero_returns_false() {
+        // STORE G00 <- 5; CALL 0 -> G00; CALL_VN 0; CALL_1N 0; PUSH G00; QUIT
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_STORE, &[0x10, 0x01, 5]);
+        emit(&mut code, opcodes::OP_CALL, &[0x03, 0, 0, 0, 0, 0x01, 1, 0xFF, 0x10]);
+        emit(&mut code, opcodes::OP_CALL_VN, &[0x03, 0, 0, 0, 0, 0xFF]);
+        emit(&mut code, opcodes::OP_CALL_1N, &[0x03, 0, 0, 0, 0]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x02, 0x10]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_extended_and_collect_stack(&code), vec![0]);
+    }
+
+    #[test]
+    fn test_op_call_errors() {
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_CALL, &[0x03, 0, 0, 0xFF, 0xFF, 0xFF, 0x00]);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::InvalidRoutineAddress(0xFFFF));
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_CALL, &[0x03, 0, 0, 0, 8]);
+        for i in 0..8 {
+            code.extend_from_slice(&[0x01, i]);
+        }
+        code.extend_from_slice(&[0xFF, 0x00]);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::TooManyOperands { max: 7 });
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_CALL, &[0x01, 8, 0xFF, 0x00]);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::UnexpectedOperandType { expected: 0x03, found: 0x01 });
+
+        // A routine has at most 15 locals, L00-L14. The test stack is too
+        // small for a frame that size, so grow the dynamic section.
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_CALL, &[0x03, 0, 0, 0, 24, 0xFF, 0x00]);
+        main.resize(16, 0);
+        emit(&mut main, opcodes::OP_QUIT, &[]);
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x0F]); // RET L14
+        let story_with_locals = |count: u64| {
+            let defaults: Vec<u64> = (1..=count).collect();
+            let (code, _) = code_with_routine(&main, &defaults, &body);
+            let mut story_bytes = build_story_with_code(&code);
+            let mut header = header::StoryHeader::from_bytes(&story_bytes).unwrap();
+            header.dynamic_data_section_length += 1024;
+            header.patch_into(&mut story_bytes).unwrap();
+            story_bytes
+        };
+        assert_eq!(run_story_and_collect_stack(&story_with_locals(15)), vec![15]);
+        let err = load_vm(&story_with_locals(16)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::TooManyLocals { routine: 24, num_locals: 16 });
+    }
+
+    /// Code for a 2OP store opcode on two large constants, storing to the stack.
+    fn binary_op_code(opcode: u64, a: i64, b: i64) -> Vec<u8> {
+        let mut code = Vec::new();
+        emit(&mut code, opcode, &[0x00]);
+        code.extend_from_slice(&a.to_be_bytes());
+        code.push(0x00);
+        code.extend_from_slice(&b.to_be_bytes());
+        code.push(0x00);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        code
+    }
+
+    fn eval_binary_op(opcode: u64, a: i64, b: i64) -> i64 {
+        let stack = run_and_collect_stack(&binary_op_code(opcode, a, b));
+        assert_eq!(stack.len(), 1);
+        stack[0] as i64
+    }
+
+    #[test]
+    fn test_op_mul() {
+        assert_eq!(eval_binary_op(opcodes::OP_MUL, 6, 7), 42);
+        assert_eq!(eval_binary_op(opcodes::OP_MUL, -3, 7), -21);
+        assert_eq!(eval_binary_op(opcodes::OP_MUL, -3, -7), 21);
+        assert_eq!(eval_binary_op(opcodes::OP_MUL, i64::MAX, 2), -2);
+        assert_eq!(eval_binary_op(opcodes::OP_MUL, i64::MIN, -1), i64::MIN);
+    }
+
+    #[test]
+    fn test_op_div_mod_signed() {
+        assert_eq!(eval_binary_op(opcodes::OP_DIV, 7, 2), 3);
+        assert_eq!(eval_binary_op(opcodes::OP_DIV, -7, 2), -3);
+        assert_eq!(eval_binary_op(opcodes::OP_DIV, 7, -2), -3);
+        assert_eq!(eval_binary_op(opcodes::OP_DIV, -7, -2), 3);
+        assert_eq!(eval_binary_op(opcodes::OP_MOD, 7, 2), 1);
+        assert_eq!(eval_binary_op(opcodes::OP_MOD, -7, 2), -1);
+        assert_eq!(eval_binary_op(opcodes::OP_MOD, 7, -2), 1);
+        assert_eq!(eval_binary_op(opcodes::OP_MOD, -7, -2), -1);
+
+        assert_eq!(eval_binary_op(opcodes::OP_DIV, i64::MIN, -1), i64::MIN);
+        assert_eq!(eval_binary_op(opcodes::OP_MOD, i64::MIN, -1), 0);
+        assert_eq!(eval_binary_op(opcodes::OP_DIV, i64::MIN, 1), i64::MIN);
+        assert_eq!(eval_binary_op(opcodes::OP_DIV, i64::MAX, -1), -i64::MAX);
+    }
+
+    #[test]
+    fn test_op_div_mod_by_zero() {
+        for opcode in [opcodes::OP_DIV, opcodes::OP_MOD] {
+            // By default the VM halts, even with a zeroed header.
+            let mut story_bytes = build_story_with_code(&binary_op_code(opcode, 5, 0));
+            let mut header = header::StoryHeader::from_bytes(&story_bytes[..1024]).unwrap();
+            assert_eq!(header.flags1, 0);
+            let mut vm = load_vm(&story_bytes);
+            let err = vm.run().unwrap_err();
+            assert_eq!(err.kind(), &VmError::DivisionByZero);
+            assert_eq!(err.opcode(), Some(opcode));
+            assert!(!vm.running);
+
+            // A host that follows the header gets 0 while DivByZeroHalt is clear...
+            let options = LoadOptions { div_by_zero_returns_zero: true, ..LoadOptions::default() };
+            let mut vm = load_vm_with_options(&story_bytes, options).unwrap();
+            let initial_sp = vm.cpu.sp;
+            vm.run().unwrap();
+            assert_eq!(vm.read_qword(initial_sp - 8), Ok(0));
+
+            // ...and halts once the story sets it.
+            header.set_div_by_zero_halt(true);
+            header.patch_into(&mut story_bytes).unwrap();
+            let err = load_vm_with_options(&story_bytes, options).unwrap().run().unwrap_err();
+            assert_eq!(err.kind(), &VmError::DivisionByZero);
+        }
+    }
+
+    #[test]
+    fn test_op_not() {
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_NOT, &[0x01, 0x0F, 0x00]);
+        emit(&mut code, opcodes::OP_NOT, &[0x00]);
+        code.extend_from_slice(&u64::MAX.to_be_bytes());
+        code.push(0x00);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![!0x0Fu64, 0]);
+    }
+
+    #[test]
+    fn test_op_add_sub_wrap() {
+        assert_eq!(eval_binary_op(opcodes::OP_ADD, i64::MAX, 1), i64::MIN);
+        assert_eq!(eval_binary_op(opcodes::OP_SUB, i64::MIN, 1), i64::MAX);
+        assert_eq!(eval_binary_op(opcodes::OP_SUB, 3, 5), -2);
+        assert_eq!(eval_binary_op(opcodes::OP_ADD, -3, 5), 2);
+    }
+
+    #[test]
+    fn test_op_or_and() {
+        assert_eq!(eval_binary_op(opcodes::OP_OR, 0b1010, 0b0110), 0b1110);
+        assert_eq!(eval_binary_op(opcodes::OP_AND, 0b1010, 0b0110), 0b0010);
+        assert_eq!(eval_binary_op(opcodes::OP_OR, i64::MIN, 1), i64::MIN | 1);
+        assert_eq!(eval_binary_op(opcodes::OP_AND, -1, i64::MIN), i64::MIN);
+    }
+
+    #[test]
+    fn test_op_test() {
+        let mut code = Vec::new();
+        // TEST 0b1110, 0b0110: all set, taken
+        emit(&mut code, opcodes::OP_TEST, &[0x01, 0b1110, 0x01, 0b0110, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        // TEST 0b1010, 0b0110: only some set, not taken
+        emit(&mut code, opcodes::OP_TEST, &[0x01, 0b1010, 0x01, 0b0110, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 2]);
+        // TEST x, 0: no flags to check, taken
+        emit(&mut code, opcodes::OP_TEST, &[0x01, 0, 0x01, 0, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 3]);
+        // TEST on the top bit of a 64-bit value
+        emit(&mut code, opcodes::OP_TEST, &[0x00]);
+        code.extend_from_slice(&u64::MAX.to_be_bytes());
+        code.push(0x00);
+        code.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        code.extend_from_slice(&[0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 4]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![2]);
+    }
+
+    #[test]
+    fn test_op_shifts() {
+        let eval_shift = |opcode, a, b| {
+            let stack = run_extended_and_collect_stack(&binary_op_code(opcode, a, b));
+            assert_eq!(stack.len(), 1);
+            stack[0] as i64
+        };
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, 1, 4), 16);
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, 16, -4), 1);
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, 1, 63), i64::MIN);
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, -1, -60), 0xF);
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, i64::MIN, -63), 1);
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, -1, 64), 0);
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, -1, -64), 0);
+        assert_eq!(eval_shift(opcodes::OP_LOG_SHIFT, 5, 0), 5);
+
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, 1, 4), 16);
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, -16, -2), -4);
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, 16, -2), 4);
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, i64::MIN, -63), -1);
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, -5, -100), -1);
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, 5, -100), 0);
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, -1, 100), 0);
+        assert_eq!(eval_shift(opcodes::OP_ART_SHIFT, 1, i64::MIN), 0);
+
+        // The shifts are extensions, unknown unless the story enables them.
+        for opcode in [opcodes::OP_LOG_SHIFT, opcodes::OP_ART_SHIFT] {
+            let err = load_vm(&build_story_with_code(&binary_op_code(opcode, 1, 4))).run().unwrap_err();
+            assert_eq!(err.kind(), &VmError::UnknownOpcode(opcode));
         }
     }
+
+    #[test]
+    fn test_op_inc_dec() {
+        // G00 = 5; INC G00; INC G00; DEC G00; PUSH G00
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_STORE, &[0x10, 0x01, 5]);
+        emit(&mut code, opcodes::OP_INC, &[0x10]);
+        emit(&mut code, opcodes::OP_INC, &[0x10]);
+        emit(&mut code, opcodes::OP_DEC, &[0x10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x02, 0x10]);
+        // Wrap-around: G01 = i64::MAX; INC G01; PUSH G01; DEC G01; PUSH G01
+        emit(&mut code, opcodes::OP_STORE, &[0x11, 0x00]);
+        code.extend_from_slice(&i64::MAX.to_be_bytes());
+        emit(&mut code, opcodes::OP_INC, &[0x11]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x02, 0x11]);
+        emit(&mut code, opcodes::OP_DEC, &[0x11]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x02, 0x11]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![6, i64::MIN as u64, i64::MAX as u64]);
+
+        // Locals work too: L00 starts at its default.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_DEC, &[0x01]);
+        emit(&mut body, opcodes::OP_RET, &[0x02, 0x01]);
+        assert_eq!(call_routine_returning(&[], &[0], &body), u64::MAX);
+    }
+
+    #[test]
+    fn test_op_inc_dec_stack_in_place() {
+        // PUSH 1; PUSH 10; INC sp; INC sp; DEC sp; DEC sp; DEC sp: the stack stays two deep.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 10]);
+        emit(&mut code, opcodes::OP_INC, &[0x00]);
+        emit(&mut code, opcodes::OP_INC, &[0x00]);
+        for _ in 0..3 {
+            emit(&mut code, opcodes::OP_DEC, &[0x00]);
+        }
+        // INC_CHK sp > 9: 10 > 9, taken - skip PUSH 2
+        emit(&mut code, opcodes::OP_INC_CHK, &[0x00, 0x01, 9, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 2]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![1, 10]);
+
+        // An empty stack underflows instead of reading below the stack base.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_INC, &[0x00]);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::Stack(cpu::StackError::Underflow));
+    }
+
+    #[test]
+    fn test_op_inc_chk_dec_chk() {
+        // Count G00 from 0 while INC_CHK G00 > 3 is false, pushing each value.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_STORE, &[0x10, 0x01, 0]);
+        let loop_start = code.len() as i64;
+        emit(&mut code, opcodes::OP_INC_CHK, &[0x10, 0x01, 3, 0x80, 20]); // exit past PUSH and JUMP
+        emit(&mut code, opcodes::OP_PUSH, &[0x02, 0x10]);
+        emit(&mut code, opcodes::OP_JUMP, &[]);
+        let offset = loop_start - (code.len() as i64 + 2);
+        code.extend_from_slice(&(offset as i16).to_be_bytes());
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![1, 2, 3]);
+
+        // DEC_CHK compares signed values: 0 - 1 = -1 < 0
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_STORE, &[0x10, 0x01, 0]);
+        emit(&mut code, opcodes::OP_DEC_CHK, &[0x10, 0x01, 0, 0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        emit(&mut code, opcodes::OP_DEC_CHK, &[0x10, 0x00]); // -2 < -5 is false
+        code.extend_from_slice(&(-5i64).to_be_bytes());
+        code.extend_from_slice(&[0x80, 10]);
+        emit(&mut code, opcodes::OP_PUSH, &[0x02, 0x10]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(run_and_collect_stack(&code), vec![-2i64 as u64]);
+    }
+
+    /// Appends a typed large-constant operand.
+    fn emit_lc(code: &mut Vec<u8>, value: u64) {
+        code.push(0x00);
+        code.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Appends a table opcode with a large-constant array address and small-constant index.
+    fn emit_table_op(code: &mut Vec<u8>, opcode: u64, array: u64, index: u8) {
+        emit(code, opcode, &[]);
+        emit_lc(code, array);
+        code.extend_from_slice(&[0x01, index]);
+    }
+
+    /// Builds code with `build(array)`, where `array` is the first free byte of
+    /// dynamic memory after the globals. The code length must not depend on `array`.
+    fn code_with_dynamic_array(build: impl Fn(u64) -> Vec<u8>) -> Vec<u8> {
+        let code_len = build(0).len() as u64;
+        let dynamic_start = 1024 + code_len + TEST_STATIC_LEN;
+        build(dynamic_start + header::GLOBALS_TABLE_SIZE)
+    }
+
+    #[test]
+    fn test_op_storew_loadw_storeb_loadb() {
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit_table_op(&mut code, opcodes::OP_STOREW, array, 2);
+            emit_lc(&mut code, -7i64 as u64);
+            emit_table_op(&mut code, opcodes::OP_STOREW, array, 0);
+            emit_lc(&mut code, 0x0102030405060708);
+            emit_table_op(&mut code, opcodes::OP_STOREB, array, 9);
+            emit_lc(&mut code, 0x1FF); // only the low byte is stored
+            emit_table_op(&mut code, opcodes::OP_LOADW, array, 2);
+            code.push(0x00);
+            emit_table_op(&mut code, opcodes::OP_LOADB, array, 7);
+            code.push(0x00);
+            emit_table_op(&mut code, opcodes::OP_LOADW, array, 1);
+            code.push(0x00);
+            emit_table_op(&mut code, opcodes::OP_LOADB, array, 9);
+            code.push(0x00);
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+        assert_eq!(run_and_collect_stack(&code), vec![-7i64 as u64, 0x08, 0x00FF_0000_0000_0000, 0xFF]);
+    }
+
+    #[test]
+    fn test_op_loadw_reads_static_data() {
+        // The globals table at the start of static data holds 0x5A bytes in the test story.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_LOADW, &[0x02, 0x10]); // array from G00, to prove VAR operands work
+        code.extend_from_slice(&[0x01, 0, 0x00]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        let mut story_bytes = build_story_with_code(&code);
+        let static_start = 1024 + code.len();
+        story_bytes[static_start..static_start + 8].copy_from_slice(&(static_start as u64 + 16).to_be_bytes());
+        let mut vm = load_vm(&story_bytes);
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        assert_eq!(vm.cpu.sp, initial_sp - 8);
+        assert_eq!(vm.read_qword(vm.cpu.sp).unwrap(), 0x5A5A5A5A5A5A5A5A);
+    }
+
+    #[test]
+    fn test_table_opcode_errors() {
+        // Reading past the end of memory reports the array and index.
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit_table_op(&mut code, opcodes::OP_LOADW, array, 16); // stack area ends at array + 128
+            code.push(0x00);
+            code
+        });
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        match err.kind() {
+            VmError::ArrayAccess { index: 16, error: MemoryError::OutOfBounds { len: 8, .. }, .. } => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        // An index whose address overflows a u64 is out of bounds, not wrapped.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_LOADB, &[0x01, 1]);
+        emit_lc(&mut code, u64::MAX);
+        code.push(0x00);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &VmError::ArrayAccess { array: 1, index: u64::MAX, error: MemoryError::OutOfBounds { address: u64::MAX, len: 1 } }
+        );
+
+        // Writes respect section permissions.
+        let mut code = Vec::new();
+        emit_table_op(&mut code, opcodes::OP_STOREB, 1024, 3);
+        code.extend_from_slice(&[0x01, 0]);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &VmError::ArrayAccess {
+                array: 1024,
+                index: 3,
+                error: MemoryError::WriteProtected { address: 1027, region: memory::Region::Code },
+            }
+        );
+        assert_eq!(std::error::Error::source(err.kind()).unwrap().to_string(), "write to 0x403 in protected region Code");
+    }
+
+    /// Builds a story like `build_story_with_code` with one object per
+    /// `(parent, sibling, child)` entry, in a table placed after the dummy
+    /// header's tables. No object has properties.
+    fn build_story_with_objects(code: &[u8], tree: &[(u64, u64, u64)]) -> Vec<u8> {
+        build_story_with_properties(code, tree, &[], &[])
+    }
+
+    /// Like `build_story_with_objects`, but object `i + 1` gets the property
+    /// list `properties[i]` (without its terminator), and the property
+    /// defaults table holds `defaults` if it is not empty. The lists and the
+    /// defaults follow the object table, at the end of static data.
+    fn build_story_with_properties(code: &[u8], tree: &[(u64, u64, u64)], properties: &[&[u8]], defaults: &[u64]) -> Vec<u8> {
+        let code_start = 1024u64;
+        let code_len = code.len() as u64;
+        let static_start = code_start + code_len;
+        let table_start = static_start + TEST_STATIC_LEN;
+        let table_end = table_start + object::OBJECT_ENTRY_SIZE * tree.len() as u64;
+
+        let mut tail = Vec::new();
+        let mut property_tables = Vec::new();
+        for i in 0..tree.len() {
+            property_tables.push(table_end + tail.len() as u64);
+            tail.extend_from_slice(properties.get(i).copied().unwrap_or(&[]));
+            tail.push(0);
+        }
+        let defaults_start = table_end + tail.len() as u64;
+        for default in defaults {
+            tail.extend_from_slice(&default.to_be_bytes());
+        }
+        let static_end = table_end + tail.len() as u64;
+
+        let mut story_bytes = create_test_story_file_bytes(
+            memory::SUPPORTED_VERSION,
+            code_start, code_len,
+            static_start, static_end - static_start,
+            static_end, TEST_DYNAMIC_LEN,
+            None
+        );
+        let mut header = header::StoryHeader::from_bytes(&story_bytes).unwrap();
+        header.objects_table_start = table_start;
+        if !defaults.is_empty() {
+            header.property_defaults_table_start = defaults_start;
+        }
+        header.patch_into(&mut story_bytes).unwrap();
+        story_bytes[code_start as usize .. static_start as usize].copy_from_slice(code);
+        for (i, &(parent, sibling, child)) in tree.iter().enumerate() {
+            let entry = (table_start + object::OBJECT_ENTRY_SIZE * i as u64) as usize;
+            for (j, field) in [0, parent, sibling, child, property_tables[i], 0].iter().enumerate() {
+                story_bytes[entry + 8 * j..entry + 8 * j + 8].copy_from_slice(&field.to_be_bytes());
+            }
+        }
+        story_bytes[table_end as usize .. static_end as usize].copy_from_slice(&tail);
+        story_bytes
+    }
+
+    /// Object 1 holds 2 and 3, in that order; 4 is loose.
+    const SAMPLE_TREE: [(u64, u64, u64); 4] = [(0, 0, 2), (1, 3, 0), (1, 0, 0), (0, 0, 0)];
+
+    #[test]
+    fn test_object_tree_read_opcodes() {
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_GET_CHILD, &[0x01, 1, 0x00, 0x80, 10]); // has a child: skip PUSH 99
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 99]);
+        emit(&mut code, opcodes::OP_GET_SIBLING, &[0x01, 2, 0x00, 0x80, 10]); // has a sibling: skip PUSH 98
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 98]);
+        emit(&mut code, opcodes::OP_GET_SIBLING, &[0x01, 3, 0x00, 0x80, 10]); // last child: no branch
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 97]);
+        emit(&mut code, opcodes::OP_GET_PARENT, &[0x01, 3, 0x00]);
+        emit(&mut code, opcodes::OP_JIN, &[0x01, 3, 0x01, 1, 0x80, 10]); // 3 is in 1: skip PUSH 96
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 96]);
+        emit(&mut code, opcodes::OP_JIN, &[0x01, 4, 0x01, 1, 0x80, 10]); // 4 is not: no branch
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 95]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+
+        let mut vm = load_vm(&build_story_with_objects(&code, &SAMPLE_TREE));
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        let mut stack: Vec<u64> = (vm.cpu.sp..initial_sp).step_by(8).map(|a| vm.read_qword(a).unwrap()).collect();
+        stack.reverse();
+        assert_eq!(stack, vec![2, 3, 0, 97, 1, 95]);
+    }
+
+    #[test]
+    fn test_op_insert_obj_remove_obj() {
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_INSERT_OBJ, &[0x01, 4, 0x01, 1]);
+        emit(&mut code, opcodes::OP_REMOVE_OBJ, &[0x01, 2]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+
+        let mut vm = load_vm(&build_story_with_objects(&code, &SAMPLE_TREE));
+        vm.run().unwrap();
+        let objects = vm.objects;
+        assert_eq!(objects.child(&vm.memory, 1), Ok(4));
+        assert_eq!(objects.sibling(&vm.memory, 4), Ok(3));
+        assert_eq!(objects.sibling(&vm.memory, 3), Ok(0));
+        assert_eq!(objects.parent(&vm.memory, 4), Ok(1));
+        assert_eq!(objects.parent(&vm.memory, 2), Ok(0));
+        assert_eq!(objects.sibling(&vm.memory, 2), Ok(0));
+    }
+
+    #[test]
+    fn test_op_test_attr_set_attr_clear_attr() {
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_SET_ATTR, &[0x01, 2, 0x01, 63]);
+        emit(&mut code, opcodes::OP_SET_ATTR, &[0x01, 2, 0x01, 7]);
+        emit(&mut code, opcodes::OP_CLEAR_ATTR, &[0x01, 2, 0x01, 7]);
+        emit(&mut code, opcodes::OP_TEST_ATTR, &[0x01, 2, 0x01, 63, 0x80, 10]); // set: skip PUSH 1
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 1]);
+        emit(&mut code, opcodes::OP_TEST_ATTR, &[0x01, 2, 0x01, 7, 0x80, 10]); // cleared: no branch
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 2]);
+        emit(&mut code, opcodes::OP_TEST_ATTR, &[0x01, 3, 0x01, 0, 0x00, 10]); // unset, sense false: skip PUSH 3
+        emit(&mut code, opcodes::OP_PUSH, &[0x01, 3]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+
+        let mut vm = load_vm(&build_story_with_objects(&code, &SAMPLE_TREE));
+        vm.set_object_attribute(3, 0, true).unwrap();
+        vm.set_object_attribute(3, 0, false).unwrap();
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        assert_eq!(vm.cpu.sp, initial_sp - 8);
+        assert_eq!(vm.read_qword(vm.cpu.sp), Ok(2));
+        assert_eq!(vm.objects().attributes(&vm.memory, 2), Ok(1 << 63));
+        assert_eq!(vm.object_attribute(2, 63), Ok(true));
+        assert_eq!(vm.object_attribute(2, 64), Err(object::ObjectError::InvalidAttribute(64)));
+    }
+
+    #[test]
+    fn test_property_opcodes() {
+        // Object 1: property 9 (2 bytes), then property 4 (1 byte). Object 2 has none.
+        let list: &[u8] = &[0x80 | 9, 2, 0x01, 0x02, 4, 0x7F];
+        let defaults: Vec<u64> = (1..=63).map(|id| 100 + id).collect();
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_GET_PROP, &[0x01, 1, 0x01, 9, 0x00]);
+        emit(&mut code, opcodes::OP_GET_PROP, &[0x01, 2, 0x01, 9, 0x00]); // default
+        emit(&mut code, opcodes::OP_PUT_PROP, &[0x01, 1, 0x01, 4, 0x01, 0x33]);
+        emit(&mut code, opcodes::OP_GET_PROP, &[0x01, 1, 0x01, 4, 0x00]);
+        emit(&mut code, opcodes::OP_GET_PROP_ADDR, &[0x01, 1, 0x01, 4, 0x10]);
+        emit(&mut code, opcodes::OP_GET_PROP_LEN, &[0x02, 0x10, 0x00]);
+        emit(&mut code, opcodes::OP_GET_PROP_ADDR, &[0x01, 1, 0x01, 9, 0x00]);
+        emit(&mut code, opcodes::OP_GET_PROP_LEN, &[0x02, 0x00, 0x00]); // pops the address just pushed
+        emit(&mut code, opcodes::OP_GET_PROP_ADDR, &[0x01, 2, 0x01, 9, 0x11]); // missing: 0
+        emit(&mut code, opcodes::OP_GET_PROP_LEN, &[0x02, 0x11, 0x00]);
+        emit(&mut code, opcodes::OP_GET_NEXT_PROP, &[0x01, 1, 0x01, 0, 0x00]);
+        emit(&mut code, opcodes::OP_GET_NEXT_PROP, &[0x01, 1, 0x01, 9, 0x00]);
+        emit(&mut code, opcodes::OP_GET_NEXT_PROP, &[0x01, 1, 0x01, 4, 0x00]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+
+        let story = build_story_with_properties(&code, &[(0, 0, 0), (0, 0, 0)], &[list], &defaults);
+        let mut vm = load_vm(&story);
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        let mut stack: Vec<u64> = (vm.cpu.sp..initial_sp).step_by(8).map(|a| vm.read_qword(a).unwrap()).collect();
+        stack.reverse();
+        assert_eq!(stack, vec![0x0102, 109, 0x33, 1, 2, 0, 9, 4, 0]);
+
+        let list_start = vm.objects().property_table_address(&vm.memory, 1).unwrap();
+        assert_eq!(vm.get_variable(0x10), Ok(list_start + 4));
+        assert_eq!(vm.get_variable(0x11), Ok(0));
+    }
+
+    #[test]
+    fn test_property_opcode_errors() {
+        let list: &[u8] = &[0x80 | 9, 3, 1, 2, 3];
+        let run_err = |code: &[u8]| {
+            let story = build_story_with_properties(code, &[(0, 0, 0)], &[list], &[]);
+            load_vm(&story).run().unwrap_err()
+        };
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PUT_PROP, &[0x01, 1, 0x01, 8, 0x01, 0]);
+        let err = run_err(&code);
+        assert_eq!(err.kind(), &VmError::Object(object::ObjectError::NoSuchProperty { object: 1, property: 8 }));
+        assert_eq!(err.to_string(), "opcode 0x0304 at PC 0x400: object 1 has no property 8");
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PUT_PROP, &[0x01, 1, 0x01, 9, 0x01, 0]);
+        assert_eq!(
+            run_err(&code).kind(),
+            &VmError::Object(object::ObjectError::PropertySizeMismatch { object: 1, property: 9, len: 3 })
+        );
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_GET_PROP, &[0x01, 1, 0x01, 64, 0x00]);
+        assert_eq!(run_err(&code).kind(), &VmError::Object(object::ObjectError::InvalidProperty(64)));
+
+        // A two-byte header with a length byte of 0 is malformed (spec 4.A.3.t).
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_GET_PROP_ADDR, &[0x01, 1, 0x01, 9, 0x00]);
+        emit(&mut code, opcodes::OP_GET_PROP_LEN, &[0x02, 0x00, 0x00]);
+        let story = build_story_with_properties(&code, &[(0, 0, 0)], &[&[0x80 | 9, 0, 0]], &[]);
+        let mut vm = load_vm(&story);
+        let address = vm.objects().property_table_address(&vm.memory, 1).unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::Object(object::ObjectError::InvalidPropertyLength { address }));
+        assert_eq!(err.opcode(), Some(opcodes::OP_GET_PROP_LEN));
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_GET_NEXT_PROP, &[0x01, 1, 0x01, 5, 0x00]);
+        assert_eq!(
+            run_err(&code).kind(),
+            &VmError::Object(object::ObjectError::NoSuchProperty { object: 1, property: 5 })
+        );
+    }
+
+    #[test]
+    fn test_object_opcode_errors() {
+        let run_err = |code: &[u8]| load_vm(&build_story_with_objects(code, &SAMPLE_TREE)).run().unwrap_err();
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_GET_PARENT, &[0x01, 0, 0x00]);
+        let err = run_err(&code);
+        assert_eq!(err.kind(), &VmError::Object(object::ObjectError::InvalidObject(0)));
+        assert_eq!(err.to_string(), "opcode 0x0103 at PC 0x400: invalid object 0");
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_JIN, &[0x01, 2, 0x01, 5, 0x80, 10]);
+        assert_eq!(run_err(&code).kind(), &VmError::Object(object::ObjectError::InvalidObject(5)));
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_INSERT_OBJ, &[0x01, 1, 0x01, 3]);
+        assert_eq!(
+            run_err(&code).kind(),
+            &VmError::Object(object::ObjectError::InsertIntoDescendant { object: 1, destination: 3 })
+        );
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_SET_ATTR, &[0x01, 1, 0x01, 64]);
+        assert_eq!(run_err(&code).kind(), &VmError::Object(object::ObjectError::InvalidAttribute(64)));
+    }
+
+    /// Offset into static data where tests put strings: the initial values
+    /// of globals G200 onwards, which no test reads.
+    const TEST_STRING_OFFSET: u64 = 1600;
+
+    /// Installs an `OutputBuffer` sink the test can read after running.
+    fn capture_output(vm: &mut VirtualMachine) -> Rc<RefCell<output::OutputBuffer>> {
+        let buffer = Rc::new(RefCell::new(output::OutputBuffer::default()));
+        vm.set_output_sink(Box::new(buffer.clone()));
+        buffer
+    }
+
+    #[test]
+    fn test_op_print_and_print_ret() {
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_PRINT, &text::pack_zchars(&[13, 14])); // "hi"
+        emit(&mut main, opcodes::OP_CALL, &[0x03]);
+        main.extend_from_slice(&40u32.to_be_bytes());
+        main.extend_from_slice(&[opcodes::OPERAND_TYPE_OMITTED, 0x00]);
+        main.resize(main.len().next_multiple_of(8), 0); // the call returns to an aligned PC
+        emit(&mut main, opcodes::OP_QUIT, &[]);
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_PRINT_RET, &text::pack_zchars(&[0, 20, 16])); // " ok"
+        let (code, paddr) = code_with_routine(&main, &[], &body);
+        assert_eq!(paddr, 40);
+
+        let mut vm = load_vm(&build_story_with_code(&code));
+        let output = capture_output(&mut vm);
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        assert_eq!(output.borrow().screen, "hi ok\n");
+        assert_eq!(vm.cpu.sp, initial_sp - 8);
+        assert_eq!(vm.read_qword(vm.cpu.sp), Ok(1), "print_ret returns true");
+    }
+
+    #[test]
+    fn test_op_print_addr_paddr_obj() {
+        let lamp = text::pack_zchars(&[4, 17, 6, 18, 21]); // "Lamp"
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PRINT_ADDR, &[]);
+        let code_len = code.len() + 9 + 8 + 5 + 8 + 2 + 8;
+        let static_start = 1024 + code_len as u64;
+        emit_lc(&mut code, static_start + TEST_STRING_OFFSET);
+        emit(&mut code, opcodes::OP_PRINT_PADDR, &[0x03]);
+        code.extend_from_slice(&(TEST_STRING_OFFSET as u32).to_be_bytes());
+        emit(&mut code, opcodes::OP_PRINT_OBJ, &[0x01, 1]);
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        assert_eq!(code.len(), code_len);
+
+        let mut story = build_story_with_objects(&code, &[(0, 0, 0)]);
+        let string_at = (static_start + TEST_STRING_OFFSET) as usize;
+        story[string_at..string_at + lamp.len()].copy_from_slice(&lamp);
+        let short_name_field = (static_start + TEST_STATIC_LEN + 40) as usize;
+        story[short_name_field..short_name_field + 8].copy_from_slice(&(string_at as u64).to_be_bytes());
+
+        let mut vm = load_vm(&story);
+        let output = capture_output(&mut vm);
+        vm.run().unwrap();
+        assert_eq!(output.borrow().screen, "LampLampLamp");
+    }
+
+    #[test]
+    fn test_op_encode_text() {
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit(&mut code, opcodes::OP_ENCODE_TEXT, &[]);
+            emit_lc(&mut code, array);
+            code.extend_from_slice(&[0x01, 10, 0x01, 2]); // up to 10 characters from offset 2
+            emit_lc(&mut code, array + 16);
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+        let mut vm = load_vm(&build_story_with_code(&code));
+        let array = vm.memory.stack_limit();
+        vm.memory.write_slice(array, b"> lamp\0junk").unwrap();
+        vm.run().unwrap();
+        let expected = text::encode_dictionary_word(&text::to_zscii("lamp"));
+        assert_eq!(vm.memory.read_slice(array + 16, 6), Ok(&expected[..]));
+    }
+
+    #[test]
+    fn test_op_output_stream() {
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit(&mut code, opcodes::OP_OUTPUT_STREAM, &[0x01, 3]);
+            emit_lc(&mut code, array);
+            emit(&mut code, opcodes::OP_PRINT, &text::pack_zchars(&[13, 14])); // "hi"
+            emit(&mut code, opcodes::OP_OUTPUT_STREAM, &[0x01, 3]);
+            emit_lc(&mut code, array + 64);
+            emit(&mut code, opcodes::OP_PRINT, &text::pack_zchars(&[20, 16])); // "ok"
+            emit(&mut code, opcodes::OP_OUTPUT_STREAM, &[]);
+            emit_lc(&mut code, -3i64 as u64);
+            emit(&mut code, opcodes::OP_PRINT, &text::pack_zchars(&[5, 20])); // "!"
+            emit(&mut code, opcodes::OP_OUTPUT_STREAM, &[]);
+            emit_lc(&mut code, -3i64 as u64);
+            emit(&mut code, opcodes::OP_PRINT, &text::pack_zchars(&[13, 14])); // "hi"
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+        let mut vm = load_vm(&build_story_with_code(&code));
+        let output = capture_output(&mut vm);
+        let array = vm.memory.stack_limit();
+        vm.memory.write_word(array, 32).unwrap();
+        vm.memory.write_word(array + 64, 32).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.memory.read_word(array), Ok(3));
+        assert_eq!(vm.memory.read_slice(array + 8, 3), Ok(&b"hi!"[..]));
+        assert_eq!(vm.memory.read_word(array + 64), Ok(2));
+        assert_eq!(vm.memory.read_slice(array + 72, 2), Ok(&b"ok"[..]));
+        assert_eq!(output.borrow().screen, "hi");
+        assert_eq!(vm.output_streams().memory_table_depth(), 0);
+    }
+
+    /// Writes a dictionary of `words` (8-byte entries) where tests put
+    /// strings, points the header at it, and returns the entry addresses.
+    /// The dictionary is sorted, so `words` must be in dictionary order.
+    fn install_dictionary(story: &mut [u8], separators: &[u8], words: &[&str]) -> Vec<u64> {
+        let mut header = header::StoryHeader::from_bytes(story).unwrap();
+        let start = header.static_data_section_start + TEST_STRING_OFFSET;
+        header.dictionary_table_start = start;
+        header.patch_into(story).unwrap();
+        let entries_start = start + 10 + separators.len() as u64;
+        let mut dictionary = vec![separators.len() as u8];
+        dictionary.extend_from_slice(separators);
+        dictionary.push(8);
+        dictionary.extend_from_slice(&(words.len() as u64).to_be_bytes());
+        for word in words {
+            dictionary.extend_from_slice(&text::encode_dictionary_word(&text::to_zscii(word)));
+            dictionary.extend_from_slice(&[0, 0]);
+        }
+        story[start as usize..start as usize + dictionary.len()].copy_from_slice(&dictionary);
+        (0..words.len() as u64).map(|i| entries_start + 8 * i).collect()
+    }
+
+    #[test]
+    fn test_op_sread() {
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit(&mut code, opcodes::OP_OUTPUT_STREAM, &[0x01, 4]);
+            emit(&mut code, opcodes::OP_SREAD, &[]);
+            emit_lc(&mut code, array);
+            emit_lc(&mut code, array + 64);
+            emit(&mut code, opcodes::OP_SREAD, &[]);
+            emit_lc(&mut code, array);
+            code.extend_from_slice(&[0x01, 0]); // no parse buffer
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+        let mut story = build_story_with_code(&code);
+        let entries = install_dictionary(&mut story, b",", &["lamp", "take"]);
+        let mut vm = load_vm(&story);
+        let output = capture_output(&mut vm);
+        vm.set_input_source(Box::new(input::ScriptedInput::new(["Take lamp, Troll", "wait"])));
+        let array = vm.memory.stack_limit();
+        vm.memory.write_byte(array, 30).unwrap();
+        vm.memory.write_byte(array + 64, 4).unwrap();
+        vm.memory.write_byte(array + 65, 0xEE).unwrap();
+        vm.run().unwrap();
+
+        assert_eq!(vm.memory.read_slice(array + 1, 6), Ok(&b"\x04wait\0"[..]));
+        assert_eq!(vm.memory.read_byte(array + 65), Ok(4), "the second sread leaves the parse buffer alone");
+        let entry = |i: u64| {
+            let e = vm.memory.read_slice(array + 66 + 13 * i, 13).unwrap();
+            (u64::from_be_bytes(e[0..8].try_into().unwrap()), e[8], u32::from_be_bytes(e[9..13].try_into().unwrap()))
+        };
+        assert_eq!(entry(0), (entries[1], 4, 2));
+        assert_eq!(entry(1), (entries[0], 4, 7));
+        assert_eq!(entry(2), (0, 1, 11));
+        assert_eq!(entry(3), (0, 5, 13));
+        assert_eq!(output.borrow().command_script, "Take lamp, Troll\nwait\n");
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_SREAD, &[0x01, 0, 0x01, 0]);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::EndOfInput);
+    }
+
+    #[test]
+    fn test_op_tokenise_and_dictionary_api() {
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit(&mut code, opcodes::OP_TOKENISE, &[]);
+            emit_lc(&mut code, array);
+            emit_lc(&mut code, array + 16);
+            code.push(opcodes::OPERAND_TYPE_OMITTED);
+            emit(&mut code, opcodes::OP_TOKENISE, &[]);
+            emit_lc(&mut code, array);
+            emit_lc(&mut code, array + 97);
+            emit_lc(&mut code, array + 70);
+            code.extend_from_slice(&[0x01, 1, opcodes::OPERAND_TYPE_OMITTED]);
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+        let mut story = build_story_with_code(&code);
+        let entries = install_dictionary(&mut story, b",", &["lamp", "take"]);
+        let mut vm = load_vm(&story);
+        // The text buffer, two parse buffers and a custom dictionary share
+        // the 128 bytes between the globals and the top of the stack.
+        let array = vm.memory.stack_limit();
+        vm.memory.write_byte(array, 14).unwrap();
+        input::store_line(&mut vm.memory, array, "take lamp, go").unwrap();
+        vm.memory.write_byte(array + 16, 4).unwrap();
+        let mut custom = vec![1, b',', 8];
+        custom.extend_from_slice(&(-1i64).to_be_bytes());
+        custom.extend_from_slice(&text::encode_dictionary_word(&text::to_zscii("lamp")));
+        custom.extend_from_slice(&[0, 0]);
+        vm.memory.write_slice(array + 70, &custom).unwrap();
+        vm.memory.write_byte(array + 97, 2).unwrap();
+        vm.memory.write_slice(array + 99, &[0xEE; 13]).unwrap();
+        vm.run().unwrap();
+
+        let entry = |buffer: u64, i: u64| {
+            let e = vm.memory.read_slice(buffer + 2 + 13 * i, 13).unwrap();
+            (u64::from_be_bytes(e[0..8].try_into().unwrap()), e[8], u32::from_be_bytes(e[9..13].try_into().unwrap()))
+        };
+        assert_eq!(vm.memory.read_byte(array + 17), Ok(4));
+        assert_eq!(entry(array + 16, 0), (entries[1], 4, 2));
+        assert_eq!(entry(array + 16, 1), (entries[0], 4, 7));
+        assert_eq!(entry(array + 16, 2), (0, 1, 11));
+        assert_eq!(entry(array + 16, 3), (0, 2, 13));
+        // The custom dictionary has no "take", so its slot is left alone.
+        assert_eq!(vm.memory.read_byte(array + 98), Ok(2));
+        assert_eq!(vm.memory.read_slice(array + 99, 13), Ok(&[0xEE; 13][..]));
+        assert_eq!(entry(array + 97, 1), (array + 81, 4, 7));
+
+        assert_eq!(vm.lookup_word("Take"), Ok(Some(entries[1])));
+        assert_eq!(vm.lookup_word("troll"), Ok(None));
+        assert_eq!(
+            vm.dictionary_words(),
+            Ok(vec![(entries[0], "lamp".to_string()), (entries[1], "take".to_string())])
+        );
+        assert_eq!(vm.dictionary().unwrap().unwrap().separators(), b",");
+    }
+
+    /// A story whose main routine does `aread` into the text buffer in G02,
+    /// with a 2-second timeout calling a routine with body `body`, stores the
+    /// terminator in G01 and quits. G00 starts at 0. Input comes from
+    /// `input`, paced by `clock`.
+    fn vm_with_timed_aread(body: &[u8], input: input::ScriptedInput, clock: &input::ManualClock) -> VirtualMachine {
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_AREAD, &[0x02, 0x12, 0x01, 0, 0x01, 2, 0x03, 0, 0, 0, 32, 0xFF, 0x11]);
+        emit(&mut main, opcodes::OP_QUIT, &[]);
+        let (code, paddr) = code_with_routine(&main, &[], body);
+        assert_eq!(paddr, 32);
+        let mut vm = load_vm(&build_story_with_code(&code));
+        let text_buffer = vm.memory.stack_limit();
+        vm.memory.write_byte(text_buffer, 20).unwrap();
+        vm.set_variable(0x12, text_buffer).unwrap();
+        vm.set_variable(0x10, 0).unwrap();
+        vm.set_input_source(Box::new(input.with_clock(clock.clone())));
+        vm.set_clock(Box::new(clock.clone()));
+        vm
+    }
+
+    #[test]
+    fn test_op_aread_timeout_routine() {
+        // Prints "hi", counts calls in G00 and returns true on the third.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_PRINT, &text::pack_zchars(&[13, 14]));
+        emit(&mut body, opcodes::OP_INC, &[0x10]);
+        emit(&mut body, opcodes::OP_JE, &[0x02, 0x10, 0x01, 3, 0x80, 1]);
+        emit(&mut body, opcodes::OP_RFALSE, &[]);
+        let seconds = Duration::from_secs;
+
+        // The line arrives after 3 seconds: one timeout, then the line.
+        let clock = input::ManualClock::default();
+        let input = input::ScriptedInput::default().then_wait(seconds(3)).then_line("North");
+        let mut vm = vm_with_timed_aread(&body, input, &clock);
+        let output = capture_output(&mut vm);
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        let text_buffer = vm.get_variable(0x12).unwrap();
+        assert_eq!(vm.get_variable(0x10), Ok(1));
+        assert_eq!(vm.get_variable(0x11), Ok(13), "a line ends with a newline");
+        assert_eq!(vm.memory.read_slice(text_buffer + 1, 6), Ok(&b"\x05north"[..]));
+        assert_eq!(output.borrow().screen, "hi");
+        assert_eq!(vm.cpu.sp, initial_sp, "the routine's frame is gone");
+        assert_eq!(clock.now(), seconds(3));
+
+        // The line would take 10 seconds; the third call gives up at 6.
+        let clock = input::ManualClock::default();
+        let input = input::ScriptedInput::default().then_wait(seconds(10)).then_line("north");
+        let mut vm = vm_with_timed_aread(&body, input, &clock);
+        let output = capture_output(&mut vm);
+        vm.run().unwrap();
+        let text_buffer = vm.get_variable(0x12).unwrap();
+        assert_eq!(vm.get_variable(0x10), Ok(3));
+        assert_eq!(vm.get_variable(0x11), Ok(0), "abandoned input has terminator 0");
+        assert_eq!(vm.memory.read_byte(text_buffer + 1), Ok(0));
+        assert_eq!(output.borrow().screen, "hihihi");
+        assert_eq!(clock.now(), seconds(6));
+    }
+
+    #[test]
+    fn test_op_aread_without_timeout_and_nested_errors() {
+        // No timeout operands: a plain line read that stores the terminator.
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit(&mut code, opcodes::OP_AREAD, &[]);
+            emit_lc(&mut code, array);
+            code.extend_from_slice(&[0x01, 0, opcodes::OPERAND_TYPE_OMITTED, 0x00]);
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+        let mut vm = load_vm(&build_story_with_code(&code));
+        vm.memory.write_byte(vm.memory.stack_limit(), 20).unwrap();
+        vm.set_input_source(Box::new(input::ScriptedInput::new(["look"])));
+        let initial_sp = vm.cpu.sp;
+        vm.run().unwrap();
+        assert_eq!(vm.cpu.sp, initial_sp - 8);
+        assert_eq!(vm.read_qword(vm.cpu.sp), Ok(13));
+
+        // A routine that quits ends the story from inside aread.
+        let clock = input::ManualClock::default();
+        let input = input::ScriptedInput::default().then_wait(Duration::from_secs(5)).then_line("north");
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_QUIT, &[]);
+        let mut vm = vm_with_timed_aread(&body, input, &clock);
+        vm.run().unwrap();
+        assert_eq!(vm.get_variable(0x11), Ok(0));
+        assert_eq!(clock.now(), Duration::from_secs(2));
+
+        // An error in the routine reports both the routine's PC and aread's.
+        let clock = input::ManualClock::default();
+        let input = input::ScriptedInput::default().then_wait(Duration::from_secs(5)).then_line("north");
+        let mut body = Vec::new();
+        emit(&mut body, 0xBEEF, &[]);
+        let mut vm = vm_with_timed_aread(&body, input, &clock);
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::UnknownOpcode(0xBEEF));
+        assert_eq!(err.pc(), Some(0x400));
+        assert_eq!(err.opcode(), Some(opcodes::OP_AREAD));
+        assert_eq!(
+            err.to_string(),
+            "opcode 0xEE05 at PC 0x400: opcode 0xBEEF at PC 0x428: unknown opcode 0xBEEF"
+        );
+    }
+
+    #[test]
+    fn test_op_aread_terminating_characters() {
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit(&mut code, opcodes::OP_AREAD, &[]);
+            emit_lc(&mut code, array);
+            code.extend_from_slice(&[0x01, 0, opcodes::OPERAND_TYPE_OMITTED, 0x00]);
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+        // Runs the story with `table` as the terminating characters table and
+        // returns the terminator and the text stored.
+        let run = |table: &[u8], input: input::ScriptedInput| {
+            let mut story = build_story_with_code(&code);
+            let mut header = header::StoryHeader::from_bytes(&story).unwrap();
+            let start = header.static_data_section_start + TEST_STRING_OFFSET;
+            header.terminating_characters_table_start = start;
+            header.patch_into(&mut story).unwrap();
+            story[start as usize..start as usize + table.len()].copy_from_slice(table);
+            let mut vm = load_vm(&story);
+            let text_buffer = vm.memory.stack_limit();
+            vm.memory.write_byte(text_buffer, 20).unwrap();
+            vm.set_input_source(Box::new(input));
+            vm.run().unwrap();
+            let len = vm.memory.read_byte(text_buffer + 1).unwrap() as u64;
+            let text = vm.memory.read_slice(text_buffer + 2, len).unwrap().to_vec();
+            (vm.read_qword(vm.cpu.sp).unwrap(), String::from_utf8(text).unwrap())
+        };
+        let keys = |keys: &[u16]| keys.iter().fold(input::ScriptedInput::default(), |i, &k| i.then_key(k));
+
+        // F1 is listed; 'A' is not a function key and is ignored, so the
+        // cursor key is just another key that does nothing.
+        let table = [input::KEY_F1 as u8, b'A', 0];
+        let typed = keys(&[b'g' as u16, b'x' as u16, input::KEY_DELETE, b'o' as u16, input::KEY_CURSOR_UP, input::KEY_F1]);
+        assert_eq!(run(&table, typed), (133, "go".to_string()));
+        assert_eq!(run(&table, input::ScriptedInput::new(["Look"])), (13, "look".to_string()));
+
+        // 255 lets any function key end the line.
+        let table = [input::TERMINATE_ON_ANY_FUNCTION_KEY, 0];
+        assert_eq!(run(&table, keys(&[b'n' as u16, input::KEY_KEYPAD_0 + 8])), (153, "n".to_string()));
+        assert_eq!(run(&table, keys(&[input::KEY_SINGLE_CLICK])), (254, String::new()));
+    }
+
+    /// A story whose main routine does `read_char` with a 2-second timeout
+    /// calling a routine with body `body`, stores the key in G01 and quits.
+    /// G00 starts at 0.
+    fn vm_with_timed_read_char(body: &[u8], input: input::ScriptedInput, clock: &input::ManualClock) -> VirtualMachine {
+        let mut main = Vec::new();
+        emit(&mut main, opcodes::OP_READ_CHAR, &[0x01, 1, 0x01, 2, 0x03, 0, 0, 0, 32, 0xFF, 0x11]);
+        emit(&mut main, opcodes::OP_QUIT, &[]);
+        let (code, paddr) = code_with_routine(&main, &[], body);
+        assert_eq!(paddr, 32);
+        let mut vm = load_vm(&build_story_with_code(&code));
+        vm.set_variable(0x10, 0).unwrap();
+        vm.set_input_source(Box::new(input.with_clock(clock.clone())));
+        vm.set_clock(Box::new(clock.clone()));
+        vm
+    }
+
+    #[test]
+    fn test_op_read_char() {
+        // Without a timeout: function keys, then a line one key at a time.
+        let mut code = Vec::new();
+        for var in 0x11..=0x14 {
+            emit(&mut code, opcodes::OP_READ_CHAR, &[0x01, 1, 0xFF, var]);
+        }
+        emit(&mut code, opcodes::OP_QUIT, &[]);
+        let mut vm = load_vm(&build_story_with_code(&code));
+        vm.set_input_source(Box::new(
+            input::ScriptedInput::default().then_key(input::KEY_F12).then_key(input::KEY_CURSOR_LEFT).then_line("y"),
+        ));
+        vm.run().unwrap();
+        let keys: Vec<u64> = (0x11..=0x14).map(|var| vm.get_variable(var).unwrap()).collect();
+        assert_eq!(keys, [144, 131, b'y' as u64, 13]);
+        vm.cpu.pc = 0x400;
+        vm.running = true;
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::EndOfInput);
+        assert_eq!(err.opcode(), Some(opcodes::OP_READ_CHAR));
+
+        // Counts calls in G00 and returns true on the third.
+        let mut body = Vec::new();
+        emit(&mut body, opcodes::OP_INC, &[0x10]);
+        emit(&mut body, opcodes::OP_JE, &[0x02, 0x10, 0x01, 3, 0x80, 1]);
+        emit(&mut body, opcodes::OP_RFALSE, &[]);
+        let seconds = Duration::from_secs;
+
+        let clock = input::ManualClock::default();
+        let input = input::ScriptedInput::default().then_wait(seconds(3)).then_key(input::KEY_ESCAPE);
+        let mut vm = vm_with_timed_read_char(&body, input, &clock);
+        vm.run().unwrap();
+        assert_eq!(vm.get_variable(0x10), Ok(1));
+        assert_eq!(vm.get_variable(0x11), Ok(27));
+        assert_eq!(clock.now(), seconds(3));
+
+        let clock = input::ManualClock::default();
+        let input = input::ScriptedInput::default().then_wait(seconds(10)).then_key(input::KEY_ESCAPE);
+        let mut vm = vm_with_timed_read_char(&body, input, &clock);
+        vm.run().unwrap();
+        assert_eq!(vm.get_variable(0x10), Ok(3));
+        assert_eq!(vm.get_variable(0x11), Ok(0), "a timeout stores 0");
+        assert_eq!(clock.now(), seconds(6));
+    }
+
+    #[test]
+    fn test_window_opcodes() {
+        let code = code_with_dynamic_array(|array| {
+            let mut code = Vec::new();
+            emit(&mut code, opcodes::OP_SPLIT_WINDOW, &[0x01, 1]);
+            emit(&mut code, opcodes::OP_SET_WINDOW, &[0x01, 1]);
+            emit(&mut code, opcodes::OP_PRINT, &text::encode_string("Kitchen"));
+            emit(&mut code, opcodes::OP_SET_CURSOR, &[0x01, 1, 0x01, 14, 0xFF]);
+            emit(&mut code, opcodes::OP_PRINT, &text::encode_string("3/7"));
+            emit(&mut code, opcodes::OP_GET_CURSOR, &[]);
+            emit_lc(&mut code, array);
+            emit(&mut code, opcodes::OP_SET_WINDOW, &[0x01, 0]);
+            emit(&mut code, opcodes::OP_BUFFER_MODE, &[0x01, 0]);
+            emit(&mut code, opcodes::OP_PRINT, &text::encode_string("A table.\nA door."));
+            emit(&mut code, opcodes::OP_SET_CURSOR, &[0x01, 1, 0x01, 3, 0x01, 0, 0xFF]);
+            emit(&mut code, opcodes::OP_ERASE_LINE, &[0x01, 1]);
+            emit(&mut code, opcodes::OP_ERASE_LINE, &[0x01, 0]);
+            emit(&mut code, opcodes::OP_ERASE_WINDOW, &[0x01, 1]);
+            emit(&mut code, opcodes::OP_QUIT, &[]);
+            code
+        });
+
+        let mut vm = load_vm(&build_story_with_code(&code));
+        let grid = Rc::new(RefCell::new(screen::CharGrid::new(4, 16)));
+        assert!(vm.set_screen(Some(screen::Screen::new(Box::new(grid.clone())))).is_none());
+        let output = capture_output(&mut vm);
+        vm.run().unwrap();
+        assert_eq!(grid.borrow().rows(), ["", "A", "A door.", ""]);
+        let array = vm.memory.stack_limit();
+        assert_eq!((vm.read_qword(array), vm.read_qword(array + 8)), (Ok(1), Ok(17)));
+        let screen = vm.screen().unwrap();
+        assert_eq!(screen.current_window(), screen::Window::Lower);
+        assert!(!screen.buffered());
+        assert_eq!(output.borrow().screen, "", "screen text goes to the screen, not the sink");
+
+        // Without a screen the window opcodes do nothing, text goes to the
+        // sink and the cursor is reported at the top left.
+        let mut vm = load_vm(&build_story_with_code(&code));
+        let output = capture_output(&mut vm);
+        vm.run().unwrap();
+        assert_eq!(output.borrow().screen, "Kitchen3/7A table.\nA door.");
+        let array = vm.memory.stack_limit();
+        assert_eq!((vm.read_qword(array), vm.read_qword(array + 8)), (Ok(1), Ok(1)));
+
+        // Bad windows and positions are errors.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_SET_WINDOW, &[0x01, 2]);
+        let mut vm = load_vm(&build_story_with_code(&code));
+        assert_eq!(vm.run().unwrap_err().kind(), &VmError::Screen(screen::ScreenError::InvalidWindow(2)));
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_SET_CURSOR, &[0x01, 5, 0x01, 1, 0xFF]);
+        let mut vm = load_vm(&build_story_with_code(&code));
+        vm.set_screen(Some(screen::Screen::new(Box::new(screen::CharGrid::new(4, 16)))));
+        assert_eq!(
+            vm.run().unwrap_err().to_string(),
+            "opcode 0x030E at PC 0x400: cursor position (5, 1) is outside the Lower window"
+        );
+    }
+
+    #[test]
+    fn test_print_opcode_errors() {
+        let run_err = |code: &[u8]| load_vm(&build_story_with_objects(code, &[(0, 0, 0)])).run().unwrap_err();
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PRINT_PADDR, &[0x01, 0]);
+        assert_eq!(run_err(&code).kind(), &VmError::UnexpectedOperandType { expected: 0x03, found: 0x01 });
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PRINT_PADDR, &[0x03, 0x00, 0x01, 0x00, 0x00]);
+        let err = run_err(&code);
+        assert_eq!(err.kind(), &VmError::InvalidStringAddress(0x10000));
+        assert_eq!(
+            err.to_string(),
+            "opcode 0x010C at PC 0x400: packed string address 0x10000 is outside the static data section"
+        );
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PRINT_OBJ, &[0x01, 0]);
+        assert_eq!(run_err(&code).kind(), &VmError::Object(object::ObjectError::InvalidObject(0)));
+
+        // Abbreviation 0 of the dummy header's table points at 0x5A5A..., far out of memory.
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_PRINT, &text::pack_zchars(&[1, 0]));
+        match run_err(&code).kind() {
+            VmError::Text(text::TextError::Memory(MemoryError::OutOfBounds { .. })) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_OUTPUT_STREAM, &[0x01, 5]);
+        assert_eq!(run_err(&code).kind(), &VmError::Output(output::OutputError::InvalidStream(5)));
+
+        let mut code = Vec::new();
+        emit(&mut code, opcodes::OP_OUTPUT_STREAM, &[]);
+        emit_lc(&mut code, -1i64 as u64);
+        assert_eq!(run_err(&code).kind(), &VmError::Output(output::OutputError::CannotDeselectScreen));
+    }
+
     #[test]
     fn test_op_nop_quit() { /* ... */ }
     #[test]
@@ -479,7 +2845,65 @@ mod tests {
     #[test]
     fn test_op_quit_after_nop() { /* ... */ }
     #[test]
-    fn test_unknown_opcode() { /* ... */ }
+    fn test_unknown_opcode() {
+        // NOP; <unknown>
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_NOP.to_be_bytes());
+        code.extend_from_slice(&0x0BADu64.to_be_bytes());
+        let mut vm = load_vm(&build_story_with_code(&code));
+
+        let err = vm.run().unwrap_err();
+        assert!(!vm.running);
+        assert_eq!(err.pc(), Some(1024 + 8));
+        assert_eq!(err.opcode(), Some(0x0BAD));
+        assert_eq!(err.kind(), &VmError::UnknownOpcode(0x0BAD));
+        assert_eq!(err.to_string(), "opcode 0x0BAD at PC 0x408: unknown opcode 0x0BAD");
+    }
+
+    #[test]
+    fn test_run_error_kinds_and_source_chain() {
+        use std::error::Error;
+
+        // PULL with an empty stack
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_PULL.to_be_bytes());
+        code.push(0x10);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::Stack(cpu::StackError::Underflow));
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "stack underflow");
+
+        // PUSH with an invalid operand type
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_PUSH.to_be_bytes());
+        code.push(0x07);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::InvalidOperandType(0x07));
+
+        // STORE to a local outside any routine frame reads past the stack top
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_STORE.to_be_bytes());
+        code.extend_from_slice(&[0x01, 0x01, 5]);
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert!(matches!(err.kind(), VmError::Memory(MemoryError::OutOfBounds { len: 8, .. })));
+
+        // JUMP back past address 0
+        let mut code = Vec::new();
+        code.extend_from_slice(&opcodes::OP_JUMP.to_be_bytes());
+        code.extend_from_slice(&(-2000i16).to_be_bytes());
+        let err = load_vm(&build_story_with_code(&code)).run().unwrap_err();
+        assert_eq!(err.kind(), &VmError::InvalidJumpTarget(1034 - 2000));
+        assert_eq!(err.pc(), Some(1024));
+
+        // Running off the end of memory fails while fetching, without an opcode
+        let mut vm = load_vm(&build_story_with_code(&[]));
+        let end = vm.memory.size();
+        vm.cpu.pc = end - 4;
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.opcode(), None);
+        assert_eq!(err.kind(), &VmError::Memory(MemoryError::OutOfBounds { address: end - 4, len: 8 }));
+        assert!(err.to_string().starts_with("fetching opcode at PC"));
+    }
 
     #[test]
     fn test_stack_operations() { /* ... */ }
@@ -517,9 +2941,9 @@ mod tests {
 
         let actual_code_len = instruction_stream.len() as u64;
         let static_start = code_start + actual_code_len;
-        let static_len = 0;
+        let static_len = TEST_STATIC_LEN;
         let dynamic_start = static_start + static_len;
-        let dynamic_len = 256;
+        let dynamic_len = TEST_DYNAMIC_LEN;
 
         let mut story_bytes = create_test_story_file_bytes(
             memory::SUPPORTED_VERSION,
@@ -574,8 +2998,9 @@ mod tests {
 
 
         main_code_stream.extend_from_slice(&p_addr_for_call.to_be_bytes());
+        main_code_stream.push(opcodes::OPERAND_TYPE_OMITTED); // no arguments
         main_code_stream.push(0x00);
-        while main_code_stream.len() % op_size as usize != 0 { main_code_stream.push(0); }
+        while !main_code_stream.len().is_multiple_of(op_size as usize) { main_code_stream.push(0); }
         // main_code_stream is now 16 bytes.
 
         main_code_stream.extend_from_slice(&opcodes::OP_QUIT.to_be_bytes());
@@ -587,9 +3012,9 @@ mod tests {
         let total_code_len = full_code_section_bytes.len() as u64;
 
         let static_start = code_start + total_code_len;
-        let static_len = 0;
+        let static_len = TEST_STATIC_LEN;
         let dynamic_start = static_start + static_len;
-        let dynamic_len = 256;
+        let dynamic_len = TEST_DYNAMIC_LEN;
 
         let mut story_bytes = create_test_story_file_bytes(
             memory::SUPPORTED_VERSION,
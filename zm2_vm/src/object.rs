@@ -0,0 +1,706 @@
+// zm2_vm/src/object.rs
+
+//! The object table (spec section 3, Static Data Section).
+//!
+//! Objects are 48-byte entries starting at the header's `objects_table_start`;
+//! object `N` lives at `objects_table_start + (N - 1) * 48`. Each entry holds
+//! the attribute flags, the parent, sibling and child object IDs, and pointers
+//! to the property table and short name. ID 0 means "no object".
+//!
+//! A property table is a list of entries, each a header followed by the
+//! property's data. The first header byte holds the property ID (1-63) in
+//! bits 0-5; if bit 7 is clear the data is 1 byte long, otherwise a second
+//! header byte holds the length (0-255). A header byte with ID 0 ends the list.
+//! The short name is reached through the entry's `short_name_ptr`, so the
+//! list starts with the first property.
+//!
+//! The table sits in static data, but the spec treats the tree links,
+//! attributes and property values as game state, so `ObjectTable` writes them
+//! regardless of write protection.
+
+use crate::memory::Memory;
+use crate::MemoryError;
+use std::fmt;
+
+/// Size in bytes of one object table entry.
+pub const OBJECT_ENTRY_SIZE: u64 = 48;
+
+/// Number of attributes per object; valid attribute numbers are `0..64`.
+pub const ATTRIBUTE_COUNT: u64 = 64;
+
+/// Highest property ID that fits the 6-bit ID field of a property header.
+pub const MAX_PROPERTY_ID: u64 = 63;
+
+const ATTRIBUTES_OFFSET: u64 = 0;
+const PARENT_OFFSET: u64 = 8;
+const SIBLING_OFFSET: u64 = 16;
+const CHILD_OFFSET: u64 = 24;
+const PROPERTY_TABLE_OFFSET: u64 = 32;
+const SHORT_NAME_OFFSET: u64 = 40;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectError {
+    /// Object 0, or an ID past the end of the object table.
+    InvalidObject(u64),
+    /// An attribute number of 64 or more.
+    InvalidAttribute(u64),
+    /// A property ID outside 1-63.
+    InvalidProperty(u64),
+    /// `object` has no property `property`.
+    NoSuchProperty { object: u64, property: u64 },
+    /// The two-byte property header at `address` gives a length of 0.
+    InvalidPropertyLength { address: u64 },
+    /// `put_prop` on a property whose length is not 1, 2, 4 or 8 bytes.
+    PropertySizeMismatch { object: u64, property: u64, len: u64 },
+    /// `insert_obj` would put `object` inside itself or one of its descendants.
+    InsertIntoDescendant { object: u64, destination: u64 },
+    /// `object` names `parent` as its parent but is not in its child list.
+    NotInParent { object: u64, parent: u64 },
+    /// Reading or writing an entry failed.
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for ObjectError {
+    fn from(e: MemoryError) -> Self {
+        ObjectError::Memory(e)
+    }
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjectError::InvalidObject(id) => write!(f, "invalid object {}", id),
+            ObjectError::InvalidAttribute(n) => write!(f, "invalid attribute {}", n),
+            ObjectError::InvalidProperty(id) => write!(f, "invalid property {}", id),
+            ObjectError::NoSuchProperty { object, property } => {
+                write!(f, "object {} has no property {}", object, property)
+            }
+            ObjectError::InvalidPropertyLength { address } => {
+                write!(f, "invalid property length 0 at 0x{:X}", address)
+            }
+            ObjectError::PropertySizeMismatch { object, property, len } => write!(
+                f,
+                "property {} of object {} is {} bytes long; only 1, 2, 4 and 8-byte properties can be written",
+                property, object, len
+            ),
+            ObjectError::InsertIntoDescendant { object, destination } => {
+                write!(f, "cannot insert object {} into object {}, which it contains", object, destination)
+            }
+            ObjectError::NotInParent { object, parent } => {
+                write!(f, "object {} is missing from the children of its parent {}", object, parent)
+            }
+            ObjectError::Memory(e) => write!(f, "object table access failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ObjectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjectError::Memory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded property table entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Property {
+    /// Address of the entry's first header byte (the `get_prop_addr` result).
+    pub address: u64,
+    /// Property ID; 0 for the entry that ends the list.
+    pub id: u64,
+    /// Address of the first data byte.
+    pub data_address: u64,
+    /// Length of the data in bytes.
+    pub len: u64,
+}
+
+impl Property {
+    /// Decodes the property header at `address`.
+    pub fn at(memory: &Memory, address: u64) -> Result<Property, MemoryError> {
+        let b1 = memory.read_byte(address)?;
+        let (data_address, len) = if b1 & 0x80 == 0 {
+            (address + 1, 1)
+        } else {
+            (address + 2, memory.read_byte(address + 1)? as u64)
+        };
+        Ok(Property { address, id: (b1 & 0x3F) as u64, data_address, len })
+    }
+
+    /// Address of the entry that follows this one.
+    fn next_address(&self) -> u64 {
+        self.data_address + self.len
+    }
+}
+
+/// A view of the object table. It only records where the table is and how
+/// many objects it holds; the entries are read from and written to the
+/// `Memory` passed to each method.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjectTable {
+    start: u64,
+    count: u64,
+}
+
+impl ObjectTable {
+    /// Locates the object table described by `memory`'s header.
+    ///
+    /// The header has no object count, so the table is taken to run until the
+    /// next table the header points at, the end of static data, or the first
+    /// property table, whichever comes first: property tables follow the
+    /// object entries.
+    pub fn new(memory: &Memory) -> Self {
+        let h = memory.header();
+        let start = h.objects_table_start;
+        let static_end = h.static_data_section_start.saturating_add(h.static_data_section_length);
+        if start == 0 || start >= static_end {
+            return ObjectTable { start, count: 0 };
+        }
+
+        let mut end = [
+            h.globals_table_start,
+            h.dictionary_table_start,
+            h.abbreviations_table_start,
+            h.property_defaults_table_start,
+        ]
+        .into_iter()
+        .filter(|&addr| addr > start)
+        .fold(static_end, u64::min);
+
+        let mut count = 0;
+        while let Some(entry_end) = start.checked_add((count + 1) * OBJECT_ENTRY_SIZE).filter(|&e| e <= end) {
+            let entry = entry_end - OBJECT_ENTRY_SIZE;
+            if let Ok(properties) = memory.read_word(entry + PROPERTY_TABLE_OFFSET) {
+                if properties >= entry_end && properties < end {
+                    end = properties;
+                }
+            }
+            count += 1;
+        }
+        ObjectTable { start, count }
+    }
+
+    /// Address of the first entry (object 1).
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Number of objects; valid IDs are `1..=count`.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Address of `object`'s entry.
+    pub fn entry_address(&self, object: u64) -> Result<u64, ObjectError> {
+        if object == 0 || object > self.count {
+            return Err(ObjectError::InvalidObject(object));
+        }
+        Ok(self.start + (object - 1) * OBJECT_ENTRY_SIZE)
+    }
+
+    fn read_field(&self, memory: &Memory, object: u64, offset: u64) -> Result<u64, ObjectError> {
+        Ok(memory.read_word(self.entry_address(object)? + offset)?)
+    }
+
+    fn write_field(&self, memory: &mut Memory, object: u64, offset: u64, value: u64) -> Result<(), ObjectError> {
+        Ok(memory.write_privileged(self.entry_address(object)? + offset, &value.to_be_bytes())?)
+    }
+
+    /// The 64 attribute flags of `object`; attribute `n` is bit `n`, counting
+    /// from the least significant bit.
+    pub fn attributes(&self, memory: &Memory, object: u64) -> Result<u64, ObjectError> {
+        self.read_field(memory, object, ATTRIBUTES_OFFSET)
+    }
+
+    /// Whether `object` has attribute `attribute` (the `test_attr` test).
+    pub fn has_attribute(&self, memory: &Memory, object: u64, attribute: u64) -> Result<bool, ObjectError> {
+        let mask = attribute_mask(attribute)?;
+        Ok(self.attributes(memory, object)? & mask != 0)
+    }
+
+    /// Sets (`set_attr`) or clears (`clear_attr`) attribute `attribute` of `object`.
+    pub fn set_attribute(&self, memory: &mut Memory, object: u64, attribute: u64, value: bool) -> Result<(), ObjectError> {
+        let mask = attribute_mask(attribute)?;
+        let attributes = self.attributes(memory, object)?;
+        let attributes = if value { attributes | mask } else { attributes & !mask };
+        self.write_field(memory, object, ATTRIBUTES_OFFSET, attributes)
+    }
+
+    pub fn parent(&self, memory: &Memory, object: u64) -> Result<u64, ObjectError> {
+        self.read_field(memory, object, PARENT_OFFSET)
+    }
+
+    pub fn sibling(&self, memory: &Memory, object: u64) -> Result<u64, ObjectError> {
+        self.read_field(memory, object, SIBLING_OFFSET)
+    }
+
+    pub fn child(&self, memory: &Memory, object: u64) -> Result<u64, ObjectError> {
+        self.read_field(memory, object, CHILD_OFFSET)
+    }
+
+    pub fn property_table_address(&self, memory: &Memory, object: u64) -> Result<u64, ObjectError> {
+        self.read_field(memory, object, PROPERTY_TABLE_OFFSET)
+    }
+
+    pub fn short_name_address(&self, memory: &Memory, object: u64) -> Result<u64, ObjectError> {
+        self.read_field(memory, object, SHORT_NAME_OFFSET)
+    }
+
+    /// Finds property `property` (1-63) of `object`, or `None` if the object
+    /// does not have it.
+    pub fn find_property(&self, memory: &Memory, object: u64, property: u64) -> Result<Option<Property>, ObjectError> {
+        check_property_id(property)?;
+        let mut address = self.property_table_address(memory, object)?;
+        if address == 0 {
+            return Ok(None);
+        }
+        loop {
+            let entry = Property::at(memory, address)?;
+            if entry.id == 0 {
+                return Ok(None);
+            }
+            if entry.id == property {
+                return Ok(Some(entry));
+            }
+            address = entry.next_address();
+        }
+    }
+
+    /// The value of property `property` of `object` (the `get_prop` result).
+    /// The first 8 data bytes are read as a big-endian number, so shorter
+    /// properties are zero-extended. A missing or empty property reads as its
+    /// default.
+    pub fn property_value(&self, memory: &Memory, object: u64, property: u64) -> Result<u64, ObjectError> {
+        match self.find_property(memory, object, property)? {
+            Some(entry) if entry.len > 0 => {
+                let data = memory.read_slice(entry.data_address, entry.len.min(8))?;
+                Ok(data.iter().fold(0, |value, &b| (value << 8) | b as u64))
+            }
+            _ => self.property_default(memory, property),
+        }
+    }
+
+    /// Writes `value` to property `property` of `object` (`put_prop`). The
+    /// property must exist and be 1, 2, 4 or 8 bytes long; shorter properties
+    /// receive the low bytes of `value`.
+    pub fn set_property_value(&self, memory: &mut Memory, object: u64, property: u64, value: u64) -> Result<(), ObjectError> {
+        let entry = self
+            .find_property(memory, object, property)?
+            .ok_or(ObjectError::NoSuchProperty { object, property })?;
+        if !matches!(entry.len, 1 | 2 | 4 | 8) {
+            return Err(ObjectError::PropertySizeMismatch { object, property, len: entry.len });
+        }
+        let bytes = value.to_be_bytes();
+        Ok(memory.write_privileged(entry.data_address, &bytes[8 - entry.len as usize..])?)
+    }
+
+    /// The default value of property `property` from the property defaults
+    /// table. It is 0 if the header has no defaults table, or the entry lies
+    /// outside static data.
+    pub fn property_default(&self, memory: &Memory, property: u64) -> Result<u64, ObjectError> {
+        check_property_id(property)?;
+        let h = memory.header();
+        if h.property_defaults_table_start == 0 {
+            return Ok(0);
+        }
+        let address = h.property_defaults_table_start.saturating_add((property - 1) * 8);
+        let static_end = h.static_data_section_start.saturating_add(h.static_data_section_length);
+        if address < h.static_data_section_start || address.saturating_add(8) > static_end {
+            return Ok(0);
+        }
+        Ok(memory.read_word(address)?)
+    }
+
+    /// The ID of the property after `property` in `object`'s list, or of the
+    /// first property if `property` is 0 (the `get_next_prop` result). 0 means
+    /// there are no more properties.
+    pub fn next_property(&self, memory: &Memory, object: u64, property: u64) -> Result<u64, ObjectError> {
+        let address = if property == 0 {
+            self.property_table_address(memory, object)?
+        } else {
+            self.find_property(memory, object, property)?
+                .ok_or(ObjectError::NoSuchProperty { object, property })?
+                .next_address()
+        };
+        if address == 0 {
+            return Ok(0);
+        }
+        Ok(Property::at(memory, address)?.id)
+    }
+
+    /// Whether `object`'s parent is `parent` (the `jin` test). Both IDs must be valid.
+    pub fn is_child_of(&self, memory: &Memory, object: u64, parent: u64) -> Result<bool, ObjectError> {
+        self.entry_address(parent)?;
+        Ok(self.parent(memory, object)? == parent)
+    }
+
+    /// Detaches `object` from its parent, unlinking it from the parent's
+    /// sibling chain and clearing its own parent and sibling. An object
+    /// without a parent is left alone; its children stay with it.
+    pub fn remove(&self, memory: &mut Memory, object: u64) -> Result<(), ObjectError> {
+        let parent = self.parent(memory, object)?;
+        if parent == 0 {
+            return Ok(());
+        }
+        let next = self.sibling(memory, object)?;
+
+        let first = self.child(memory, parent)?;
+        if first == object {
+            self.write_field(memory, parent, CHILD_OFFSET, next)?;
+        } else {
+            // Walk the chain at most `count` steps so a corrupt, looping
+            // chain ends in an error rather than hanging the VM.
+            let mut prev = first;
+            let mut steps = 0;
+            loop {
+                if prev == 0 || steps == self.count {
+                    return Err(ObjectError::NotInParent { object, parent });
+                }
+                let following = self.sibling(memory, prev)?;
+                if following == object {
+                    break;
+                }
+                prev = following;
+                steps += 1;
+            }
+            self.write_field(memory, prev, SIBLING_OFFSET, next)?;
+        }
+
+        self.write_field(memory, object, PARENT_OFFSET, 0)?;
+        self.write_field(memory, object, SIBLING_OFFSET, 0)
+    }
+
+    /// Moves `object` to become the first child of `destination`. Moving an
+    /// object into itself or into one of its own descendants is an error, as
+    /// it would cut the subtree off from the rest of the tree in a loop.
+    pub fn insert(&self, memory: &mut Memory, object: u64, destination: u64) -> Result<(), ObjectError> {
+        self.entry_address(object)?;
+        self.entry_address(destination)?;
+        let mut ancestor = destination;
+        let mut steps = 0;
+        while ancestor != 0 && steps <= self.count {
+            if ancestor == object {
+                return Err(ObjectError::InsertIntoDescendant { object, destination });
+            }
+            ancestor = self.parent(memory, ancestor)?;
+            steps += 1;
+        }
+
+        self.remove(memory, object)?;
+        let first = self.child(memory, destination)?;
+        self.write_field(memory, object, PARENT_OFFSET, destination)?;
+        self.write_field(memory, object, SIBLING_OFFSET, first)?;
+        self.write_field(memory, destination, CHILD_OFFSET, object)
+    }
+}
+
+fn check_property_id(property: u64) -> Result<(), ObjectError> {
+    if property == 0 || property > MAX_PROPERTY_ID {
+        return Err(ObjectError::InvalidProperty(property));
+    }
+    Ok(())
+}
+
+/// The bit of the attributes field that holds `attribute`.
+fn attribute_mask(attribute: u64) -> Result<u64, ObjectError> {
+    if attribute >= ATTRIBUTE_COUNT {
+        return Err(ObjectError::InvalidAttribute(attribute));
+    }
+    Ok(1 << attribute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{create_dummy_header_bytes, StoryHeader};
+
+    /// Address the test object table starts at: just past the dummy
+    /// header's dictionary, in a static section enlarged to 4096 bytes.
+    const TABLE_START: u64 = 3328;
+
+    /// Builds memory with one object per `(parent, sibling, child)` entry.
+    /// Every property table pointer points just past the table.
+    fn memory_with_objects(tree: &[(u64, u64, u64)]) -> Memory {
+        memory_with_properties(tree, &[], &[])
+    }
+
+    /// Like `memory_with_objects`, but object `i + 1` gets the property list
+    /// `properties[i]` (without its terminator), and the property defaults
+    /// table holds `defaults` if it is not empty. The lists and the defaults
+    /// follow the object table.
+    fn memory_with_properties(tree: &[(u64, u64, u64)], properties: &[&[u8]], defaults: &[u64]) -> Memory {
+        let mut story_data = create_dummy_header_bytes();
+        let mut header = StoryHeader::from_bytes(&story_data).unwrap();
+        header.static_data_section_length = 4096;
+        header.dynamic_data_section_start = 1280 + 4096;
+        header.objects_table_start = TABLE_START;
+
+        let mut tail = Vec::new();
+        let table_end = TABLE_START + OBJECT_ENTRY_SIZE * tree.len() as u64;
+        let mut property_tables = Vec::new();
+        for i in 0..tree.len() {
+            property_tables.push(table_end + tail.len() as u64);
+            tail.extend_from_slice(properties.get(i).copied().unwrap_or(&[]));
+            tail.push(0);
+        }
+        if !defaults.is_empty() {
+            header.property_defaults_table_start = table_end + tail.len() as u64;
+            for default in defaults {
+                tail.extend_from_slice(&default.to_be_bytes());
+            }
+        }
+        header.patch_into(&mut story_data).unwrap();
+        story_data.resize(header.dynamic_data_section_start as usize, 0);
+
+        for (i, &(parent, sibling, child)) in tree.iter().enumerate() {
+            let entry = (TABLE_START + OBJECT_ENTRY_SIZE * i as u64) as usize;
+            let fields = [0, parent, sibling, child, property_tables[i], 0];
+            for (j, field) in fields.iter().enumerate() {
+                story_data[entry + 8 * j..entry + 8 * j + 8].copy_from_slice(&field.to_be_bytes());
+            }
+        }
+        let tail_start = table_end as usize;
+        story_data[tail_start..tail_start + tail.len()].copy_from_slice(&tail);
+        Memory::new(story_data).unwrap()
+    }
+
+    /// Room 1 holding 2, 3 and 4 in that order; 3 holds 5; 6 is loose.
+    fn sample_tree() -> Memory {
+        memory_with_objects(&[(0, 0, 2), (1, 3, 0), (1, 4, 5), (1, 0, 0), (3, 0, 0), (0, 0, 0)])
+    }
+
+    /// Returns `parent`'s children in sibling order.
+    fn children(table: &ObjectTable, memory: &Memory, parent: u64) -> Vec<u64> {
+        let mut result = Vec::new();
+        let mut child = table.child(memory, parent).unwrap();
+        while child != 0 {
+            assert_eq!(table.parent(memory, child).unwrap(), parent);
+            result.push(child);
+            child = table.sibling(memory, child).unwrap();
+        }
+        result
+    }
+
+    #[test]
+    fn test_object_count_stops_at_first_property_table() {
+        let memory = sample_tree();
+        let table = ObjectTable::new(&memory);
+        assert_eq!(table.start(), TABLE_START);
+        assert_eq!(table.count(), 6);
+        assert_eq!(table.entry_address(1), Ok(TABLE_START));
+        assert_eq!(table.entry_address(6), Ok(TABLE_START + 5 * 48));
+        assert_eq!(table.entry_address(0), Err(ObjectError::InvalidObject(0)));
+        assert_eq!(table.entry_address(7), Err(ObjectError::InvalidObject(7)));
+    }
+
+    #[test]
+    fn test_object_count_bounded_by_next_table() {
+        // The dummy header's objects table runs into its dictionary 96 bytes later.
+        let mut story_data = create_dummy_header_bytes();
+        story_data.resize(3328, 0);
+        let memory = Memory::new(story_data).unwrap();
+        let table = ObjectTable::new(&memory);
+        assert_eq!(table.start(), 3216);
+        assert_eq!(table.count(), 2);
+    }
+
+    #[test]
+    fn test_tree_accessors() {
+        let memory = sample_tree();
+        let table = ObjectTable::new(&memory);
+        assert_eq!(children(&table, &memory, 1), vec![2, 3, 4]);
+        assert_eq!(children(&table, &memory, 3), vec![5]);
+        assert_eq!(table.parent(&memory, 1), Ok(0));
+        assert_eq!(table.is_child_of(&memory, 5, 3), Ok(true));
+        assert_eq!(table.is_child_of(&memory, 5, 1), Ok(false));
+        assert_eq!(table.is_child_of(&memory, 5, 0), Err(ObjectError::InvalidObject(0)));
+        assert_eq!(table.property_table_address(&memory, 1), Ok(TABLE_START + 6 * 48));
+        assert_eq!(table.property_table_address(&memory, 2), Ok(TABLE_START + 6 * 48 + 1));
+        assert_eq!(table.sibling(&memory, 9), Err(ObjectError::InvalidObject(9)));
+    }
+
+    #[test]
+    fn test_remove_first_middle_and_last_child() {
+        let mut memory = sample_tree();
+        let table = ObjectTable::new(&memory);
+
+        table.remove(&mut memory, 3).unwrap();
+        assert_eq!(children(&table, &memory, 1), vec![2, 4]);
+        assert_eq!(table.parent(&memory, 3), Ok(0));
+        assert_eq!(table.sibling(&memory, 3), Ok(0));
+        assert_eq!(children(&table, &memory, 3), vec![5], "children move with the object");
+
+        table.remove(&mut memory, 2).unwrap();
+        assert_eq!(children(&table, &memory, 1), vec![4]);
+        table.remove(&mut memory, 4).unwrap();
+        assert_eq!(children(&table, &memory, 1), Vec::<u64>::new());
+
+        // Removing an object with no parent does nothing.
+        table.remove(&mut memory, 6).unwrap();
+        assert_eq!(table.parent(&memory, 6), Ok(0));
+    }
+
+    #[test]
+    fn test_insert_becomes_first_child() {
+        let mut memory = sample_tree();
+        let table = ObjectTable::new(&memory);
+
+        table.insert(&mut memory, 6, 1).unwrap();
+        assert_eq!(children(&table, &memory, 1), vec![6, 2, 3, 4]);
+
+        table.insert(&mut memory, 4, 3).unwrap();
+        assert_eq!(children(&table, &memory, 1), vec![6, 2, 3]);
+        assert_eq!(children(&table, &memory, 3), vec![4, 5]);
+
+        // Re-inserting into the same parent moves the object to the front.
+        table.insert(&mut memory, 3, 1).unwrap();
+        assert_eq!(children(&table, &memory, 1), vec![3, 6, 2]);
+    }
+
+    #[test]
+    fn test_insert_errors() {
+        let mut memory = sample_tree();
+        let table = ObjectTable::new(&memory);
+        assert_eq!(
+            table.insert(&mut memory, 3, 3),
+            Err(ObjectError::InsertIntoDescendant { object: 3, destination: 3 })
+        );
+        assert_eq!(
+            table.insert(&mut memory, 1, 5),
+            Err(ObjectError::InsertIntoDescendant { object: 1, destination: 5 })
+        );
+        assert_eq!(table.insert(&mut memory, 2, 0), Err(ObjectError::InvalidObject(0)));
+        assert_eq!(table.insert(&mut memory, 0, 1), Err(ObjectError::InvalidObject(0)));
+        assert_eq!(table.insert(&mut memory, 2, 7), Err(ObjectError::InvalidObject(7)));
+        assert_eq!(children(&table, &memory, 1), vec![2, 3, 4], "failed inserts leave the tree alone");
+    }
+
+    #[test]
+    fn test_attributes() {
+        let mut memory = sample_tree();
+        let table = ObjectTable::new(&memory);
+
+        table.set_attribute(&mut memory, 2, 0, true).unwrap();
+        table.set_attribute(&mut memory, 2, 63, true).unwrap();
+        table.set_attribute(&mut memory, 2, 5, true).unwrap();
+        table.set_attribute(&mut memory, 2, 5, false).unwrap();
+        assert_eq!(table.attributes(&memory, 2), Ok(0x8000_0000_0000_0001));
+        assert_eq!(table.has_attribute(&memory, 2, 63), Ok(true));
+        assert_eq!(table.has_attribute(&memory, 2, 5), Ok(false));
+        assert_eq!(table.attributes(&memory, 1), Ok(0), "neighbouring objects are untouched");
+        assert_eq!(table.attributes(&memory, 3), Ok(0), "neighbouring objects are untouched");
+
+        assert_eq!(table.has_attribute(&memory, 2, 64), Err(ObjectError::InvalidAttribute(64)));
+        assert_eq!(table.set_attribute(&mut memory, 2, u64::MAX, true), Err(ObjectError::InvalidAttribute(u64::MAX)));
+        assert_eq!(table.set_attribute(&mut memory, 7, 1, true), Err(ObjectError::InvalidObject(7)));
+        assert_eq!(table.has_attribute(&memory, 0, 1), Err(ObjectError::InvalidObject(0)));
+    }
+
+    /// Object 1's properties: 12 (1 byte), 7 (2 bytes), 5 (10 bytes), 3 (3 bytes),
+    /// 2 (empty) and 1 (8 bytes), in that order. Object 2 has none.
+    fn memory_with_sample_properties() -> Memory {
+        let mut list = vec![12, 0xAB];
+        list.extend_from_slice(&[0x80 | 7, 2, 0x12, 0x34]);
+        list.extend_from_slice(&[0x80 | 5, 10, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        list.extend_from_slice(&[0x80 | 3, 3, 0x0A, 0x0B, 0x0C]);
+        list.extend_from_slice(&[0x80 | 2, 0]);
+        list.extend_from_slice(&[0x80 | 1, 8]);
+        list.extend_from_slice(&(-2i64 as u64).to_be_bytes());
+        let defaults: Vec<u64> = (1..=63).map(|id| 1000 + id).collect();
+        memory_with_properties(&[(0, 0, 0), (0, 0, 0)], &[&list], &defaults)
+    }
+
+    #[test]
+    fn test_property_decoding() {
+        let memory = memory_with_sample_properties();
+        let table = ObjectTable::new(&memory);
+        assert_eq!(table.count(), 2);
+        let list = table.property_table_address(&memory, 1).unwrap();
+
+        let short = table.find_property(&memory, 1, 12).unwrap().unwrap();
+        assert_eq!(short, Property { address: list, id: 12, data_address: list + 1, len: 1 });
+        let long = table.find_property(&memory, 1, 7).unwrap().unwrap();
+        assert_eq!(long, Property { address: list + 2, id: 7, data_address: list + 4, len: 2 });
+        assert_eq!(Property::at(&memory, long.address), Ok(long));
+        assert_eq!(table.find_property(&memory, 1, 9), Ok(None));
+        assert_eq!(table.find_property(&memory, 2, 12), Ok(None));
+        assert_eq!(table.find_property(&memory, 1, 0), Err(ObjectError::InvalidProperty(0)));
+        assert_eq!(table.find_property(&memory, 1, 64), Err(ObjectError::InvalidProperty(64)));
+        assert_eq!(table.find_property(&memory, 3, 1), Err(ObjectError::InvalidObject(3)));
+    }
+
+    #[test]
+    fn test_property_values_and_defaults() {
+        let memory = memory_with_sample_properties();
+        let table = ObjectTable::new(&memory);
+        assert_eq!(table.property_value(&memory, 1, 12), Ok(0xAB));
+        assert_eq!(table.property_value(&memory, 1, 7), Ok(0x1234));
+        assert_eq!(table.property_value(&memory, 1, 5), Ok(0x0102030405060708), "first 8 bytes");
+        assert_eq!(table.property_value(&memory, 1, 3), Ok(0x0A0B0C));
+        assert_eq!(table.property_value(&memory, 1, 1), Ok(-2i64 as u64));
+        assert_eq!(table.property_value(&memory, 1, 2), Ok(1002), "empty property reads its default");
+        assert_eq!(table.property_value(&memory, 1, 40), Ok(1040));
+        assert_eq!(table.property_value(&memory, 2, 63), Ok(1063));
+
+        // Without a defaults table, every default is 0.
+        let memory = memory_with_properties(&[(0, 0, 0)], &[], &[]);
+        assert_eq!(ObjectTable::new(&memory).property_value(&memory, 1, 5), Ok(0));
+    }
+
+    #[test]
+    fn test_set_property_value() {
+        let mut memory = memory_with_sample_properties();
+        let table = ObjectTable::new(&memory);
+        table.set_property_value(&mut memory, 1, 12, 0x1FF).unwrap();
+        table.set_property_value(&mut memory, 1, 7, 0xFFFF_5678).unwrap();
+        table.set_property_value(&mut memory, 1, 1, 42).unwrap();
+        assert_eq!(table.property_value(&memory, 1, 12), Ok(0xFF), "low byte only");
+        assert_eq!(table.property_value(&memory, 1, 7), Ok(0x5678));
+        assert_eq!(table.property_value(&memory, 1, 1), Ok(42));
+        assert_eq!(table.property_value(&memory, 1, 5), Ok(0x0102030405060708), "neighbours untouched");
+
+        assert_eq!(
+            table.set_property_value(&mut memory, 1, 9, 0),
+            Err(ObjectError::NoSuchProperty { object: 1, property: 9 })
+        );
+        assert_eq!(
+            table.set_property_value(&mut memory, 1, 3, 0),
+            Err(ObjectError::PropertySizeMismatch { object: 1, property: 3, len: 3 })
+        );
+        assert_eq!(
+            table.set_property_value(&mut memory, 1, 5, 0),
+            Err(ObjectError::PropertySizeMismatch { object: 1, property: 5, len: 10 })
+        );
+        assert_eq!(
+            table.set_property_value(&mut memory, 1, 2, 0),
+            Err(ObjectError::PropertySizeMismatch { object: 1, property: 2, len: 0 })
+        );
+    }
+
+    #[test]
+    fn test_next_property() {
+        let memory = memory_with_sample_properties();
+        let table = ObjectTable::new(&memory);
+        let mut ids = Vec::new();
+        let mut id = table.next_property(&memory, 1, 0).unwrap();
+        while id != 0 {
+            ids.push(id);
+            id = table.next_property(&memory, 1, id).unwrap();
+        }
+        assert_eq!(ids, vec![12, 7, 5, 3, 2, 1]);
+        assert_eq!(table.next_property(&memory, 2, 0), Ok(0));
+        assert_eq!(
+            table.next_property(&memory, 2, 4),
+            Err(ObjectError::NoSuchProperty { object: 2, property: 4 })
+        );
+    }
+
+    #[test]
+    fn test_remove_detects_broken_chain() {
+        // Object 2 claims parent 1, but 1's only child is 3.
+        let mut memory = memory_with_objects(&[(0, 0, 3), (1, 0, 0), (1, 0, 0)]);
+        let table = ObjectTable::new(&memory);
+        assert_eq!(table.remove(&mut memory, 2), Err(ObjectError::NotInParent { object: 2, parent: 1 }));
+    }
+}
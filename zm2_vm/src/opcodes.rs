@@ -2,39 +2,100 @@
 
 #![allow(dead_code)] // Allow dead code for now as not all opcodes are used yet
 
+// Opcodes marked as extensions are not in the spec. They only run when the
+// story sets `FLAGS2_ENABLE_EXTENDED_OPCODES`; otherwise they are unknown.
+
 // 0OP Opcodes
 pub const OP_RTRUE: u64 = 0x0000;
 pub const OP_RFALSE: u64 = 0x0001;
-// OP_PRINT (0x0002) - Not implemented yet
-// OP_PRINT_RET (0x0003) - Not implemented yet
+pub const OP_PRINT: u64 = 0x0002; // the Z-encoded string follows the opcode
+pub const OP_PRINT_RET: u64 = 0x0003; // print, then a newline, then return true
 // OP_SAVE (0x0004) - Not implemented yet
 // OP_RESTORE (0x0005) - Not implemented yet
 pub const OP_QUIT: u64 = 0x0006;
 pub const OP_NOP: u64 = 0x0007;
-// OP_RESTART (0x0008) - Not implemented yet
 // OP_RET_POPPED (0x0009) - Not implemented yet
 // OP_POP (0x000A) - Not implemented yet
 // OP_CATCH (0x000B) - Not implemented yet
 // OP_THROW (0x000C) - Not implemented yet
+pub const OP_VERIFY: u64 = 0x0008;
+
+/// Operand type byte that ends a variable-length operand list
+/// (the "omitted" operand type of the original Z-machine).
+pub const OPERAND_TYPE_OMITTED: u8 = 0xFF;
 
 // 1OP Opcodes
+pub const OP_JZ: u64 = 0x0100;
+pub const OP_GET_SIBLING: u64 = 0x0101; // stores the sibling, branches if there is one
+pub const OP_GET_CHILD: u64 = 0x0102; // stores the first child, branches if there is one
+pub const OP_GET_PARENT: u64 = 0x0103;
+pub const OP_GET_PROP_LEN: u64 = 0x0104; // takes a get_prop_addr result; 0 gives 0
+// inc, dec, inc_chk and dec_chk take a variable number; variable 0 is the
+// top of the stack, read and written in place.
+pub const OP_INC: u64 = 0x0105;
+pub const OP_DEC: u64 = 0x0106;
+pub const OP_PRINT_ADDR: u64 = 0x0107;
+pub const OP_REMOVE_OBJ: u64 = 0x0108;
+pub const OP_PRINT_OBJ: u64 = 0x0109; // prints the string at the object's short_name_ptr
 pub const OP_RET: u64 = 0x010A;
 pub const OP_JUMP: u64 = 0x010B;
+pub const OP_PRINT_PADDR: u64 = 0x010C; // StringPADDR operand, relative to static_data_section_start
 
 // VAROP Opcodes
-pub const OP_CALL: u64 = 0x0300;
+pub const OP_CALL: u64 = 0x0300; // routine, up to 7 arguments ended by OPERAND_TYPE_OMITTED, store
+pub const OP_SREAD: u64 = 0x0301; // text buffer, parse buffer (0 to skip tokenising)
+pub const OP_STOREW: u64 = 0x0302;
+pub const OP_STOREB: u64 = 0x0303;
+pub const OP_PUT_PROP: u64 = 0x0304; // object, property, value
 pub const OP_PUSH: u64 = 0x0308;
 pub const OP_PULL: u64 = 0x0309;
+pub const OP_SPLIT_WINDOW: u64 = 0x030A; // lines for the upper window
+pub const OP_SET_WINDOW: u64 = 0x030B; // 0 = lower, 1 = upper
+pub const OP_ERASE_WINDOW: u64 = 0x030C; // window, or -1 for both
+pub const OP_ERASE_LINE: u64 = 0x030D; // 1 erases to the end of the line; other values do nothing
+pub const OP_SET_CURSOR: u64 = 0x030E; // row, column, then optionally the window
+pub const OP_GET_CURSOR: u64 = 0x030F; // array receiving the row and column as 64-bit words
+pub const OP_BUFFER_MODE: u64 = 0x0311; // 0 = off, 1 = on
+pub const OP_OUTPUT_STREAM: u64 = 0x0312; // stream; stream 3 also takes a table address
+pub const OP_READ_CHAR: u64 = 0x0315; // device (ignored), then optional timeout (seconds) and routine; stores the key
 pub const OP_STORE: u64 = 0x0319; // ZM2 VAROP list
+pub const OP_TOKENISE: u64 = 0x031A; // text, parse, then optionally dictionary (0 = header's) and skip-unknown flag
+pub const OP_ENCODE_TEXT: u64 = 0x031B; // text, length, from, buffer; writes a 6-byte dictionary word
+pub const OP_CHECK_ARG_COUNT: u64 = 0x031E;
+pub const OP_CALL_VN: u64 = 0x031F; // extension, not in the spec; call without a store variable, discarding the result
+pub const OP_JE_VARARGS: u64 = 0x0320; // extension, not in the spec; value1, then 1 to 3 comparands ended by OPERAND_TYPE_OMITTED
 
 // 1OP Opcodes (continued)
 pub const OP_LOAD: u64 = 0x010D;
+pub const OP_NOT: u64 = 0x010E;
+pub const OP_CALL_1N: u64 = 0x010F; // extension, not in the spec; call_vn with no arguments
 
 // 2OP Opcodes
-pub const OP_ADD: u64 = 0x0203;
-pub const OP_SUB: u64 = 0x0204;
-
+pub const OP_ADD: u64 = 0x0200;
+pub const OP_JE: u64 = 0x0201;
+pub const OP_JL: u64 = 0x0202;
+pub const OP_JG: u64 = 0x0203;
+pub const OP_DEC_CHK: u64 = 0x0204;
+pub const OP_INC_CHK: u64 = 0x0205;
+pub const OP_JIN: u64 = 0x0206; // branches if object1's parent is object2
+pub const OP_TEST: u64 = 0x0207; // branches if every bit of `flags` is set in `bitmap`
+pub const OP_OR: u64 = 0x0208;
+pub const OP_AND: u64 = 0x0209;
+pub const OP_TEST_ATTR: u64 = 0x020A; // attribute n is bit n of the object's attributes; branches if set
+pub const OP_SET_ATTR: u64 = 0x020B;
+pub const OP_CLEAR_ATTR: u64 = 0x020C;
+pub const OP_INSERT_OBJ: u64 = 0x020D; // object becomes the first child of destination
+pub const OP_LOADW: u64 = 0x020E; // array + 8 * index
+pub const OP_LOADB: u64 = 0x020F; // array + index
+pub const OP_GET_PROP: u64 = 0x0210; // falls back to the property defaults table
+pub const OP_GET_PROP_ADDR: u64 = 0x0211; // address of the property header, or 0
+pub const OP_GET_NEXT_PROP: u64 = 0x0212; // property 0 gives the first property
+pub const OP_SUB: u64 = 0x0213;
+pub const OP_MUL: u64 = 0x0214;
+pub const OP_DIV: u64 = 0x0215; // signed, truncating towards zero
+pub const OP_MOD: u64 = 0x0216; // signed; the result takes the sign of the dividend
 
-// TODO: Add other opcode constants as they are implemented
-// Example:
-// pub const OP_JE: u64 = 0x0100; // Example for a 2OP/VAR opcode
+// EXT Opcodes
+pub const OP_AREAD: u64 = 0xEE05; // as sread, then optional timeout (seconds) and routine; stores the terminator
+pub const OP_LOG_SHIFT: u64 = 0xEE06; // extension, not in the spec; places > 0 shifts left, < 0 shifts right filling with 0
+pub const OP_ART_SHIFT: u64 = 0xEE07; // extension, not in the spec; as log_shift, but right shifts copy the sign bit
@@ -0,0 +1,420 @@
+// zm2_vm/src/output.rs
+
+//! Output streams (spec section bs, `output_stream`).
+//!
+//! The VM does no I/O of its own: everything a story prints goes to an
+//! `OutputSink` supplied by the host. `OutputStreams` tracks which of the
+//! classic streams are selected and routes text between them:
+//!
+//! *   Stream 1, the screen, is always selected. Its text goes to the
+//!     host's `screen::Screen`, which places it in the current window, or
+//!     to the sink if the host has not installed one.
+//! *   Stream 2, the transcript, is only selected if the story header has the
+//!     `Transcripting` flag set; otherwise selecting it does nothing.
+//! *   Stream 3 redirects output into a table in memory. The table's first
+//!     word holds its capacity in bytes when the stream is selected; from then
+//!     on the VM keeps the number of bytes written in that word, followed by
+//!     the text as ZSCII. Selections nest up to `MAX_MEMORY_TABLE_DEPTH`
+//!     deep, only the innermost table receives text, and while any table is
+//!     selected nothing reaches the other streams.
+//! *   Stream 4, the command script, records the player's input lines.
+//!
+//! Input lines are also echoed to the transcript; the host shows them on the
+//! screen as the player types.
+
+use crate::header::FLAGS1_TRANSCRIPTING;
+use crate::memory::Memory;
+use crate::screen::Screen;
+use crate::text;
+use crate::MemoryError;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+pub const STREAM_SCREEN: u64 = 1;
+pub const STREAM_TRANSCRIPT: u64 = 2;
+pub const STREAM_MEMORY: u64 = 3;
+pub const STREAM_COMMAND_SCRIPT: u64 = 4;
+
+/// How many memory tables can be selected at once (Z-Machine Standard 7.1.2.1.1).
+pub const MAX_MEMORY_TABLE_DEPTH: usize = 16;
+
+/// Size in bytes of the length word at the start of a memory table.
+const TABLE_LENGTH_SIZE: u64 = 8;
+
+/// Receives the text a story prints. Implemented by the host.
+pub trait OutputSink {
+    /// Text for the screen (stream 1).
+    fn print(&mut self, text: &str);
+
+    /// Text for the transcript (stream 2), while it is selected.
+    fn transcript(&mut self, _text: &str) {}
+
+    /// A line of player input for the command script (stream 4), while it
+    /// is selected.
+    fn command_script(&mut self, _line: &str) {}
+}
+
+/// Lets the host keep a handle on a sink it has given to the VM.
+impl<T: OutputSink> OutputSink for Rc<RefCell<T>> {
+    fn print(&mut self, text: &str) {
+        self.borrow_mut().print(text)
+    }
+
+    fn transcript(&mut self, text: &str) {
+        self.borrow_mut().transcript(text)
+    }
+
+    fn command_script(&mut self, line: &str) {
+        self.borrow_mut().command_script(line)
+    }
+}
+
+/// An `OutputSink` that collects each stream into a string. The VM starts
+/// out with one, so a story runs without a host sink installed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputBuffer {
+    pub screen: String,
+    pub transcript: String,
+    pub command_script: String,
+}
+
+impl OutputSink for OutputBuffer {
+    fn print(&mut self, text: &str) {
+        self.screen.push_str(text);
+    }
+
+    fn transcript(&mut self, text: &str) {
+        self.transcript.push_str(text);
+    }
+
+    fn command_script(&mut self, line: &str) {
+        self.command_script.push_str(line);
+        self.command_script.push('\n');
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputError {
+    /// `output_stream` was given a stream number other than 1-4 or -1 to -4.
+    InvalidStream(i64),
+    /// Stream 1 was deselected; the screen is always selected.
+    CannotDeselectScreen,
+    /// A memory table was selected while `MAX_MEMORY_TABLE_DEPTH` were already.
+    MemoryTableDepth,
+    /// Printing would write past the capacity of the memory table at `table`.
+    MemoryTableFull { table: u64, capacity: u64 },
+    Memory(MemoryError),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputError::InvalidStream(stream) => write!(f, "invalid output stream {}", stream),
+            OutputError::CannotDeselectScreen => write!(f, "the screen output stream cannot be deselected"),
+            OutputError::MemoryTableDepth => {
+                write!(f, "more than {} memory output streams selected", MAX_MEMORY_TABLE_DEPTH)
+            }
+            OutputError::MemoryTableFull { table, capacity } => {
+                write!(f, "memory output table 0x{:X} is full ({} bytes)", table, capacity)
+            }
+            OutputError::Memory(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OutputError::Memory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MemoryError> for OutputError {
+    fn from(e: MemoryError) -> Self {
+        OutputError::Memory(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MemoryTable {
+    address: u64,
+    capacity: u64,
+    len: u64,
+}
+
+/// The selected output streams and the sink and screen they write to.
+pub struct OutputStreams {
+    sink: Box<dyn OutputSink>,
+    screen: Option<Screen>,
+    transcript: bool,
+    command_script: bool,
+    tables: Vec<MemoryTable>,
+}
+
+impl fmt::Debug for OutputStreams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutputStreams")
+            .field("transcript", &self.transcript)
+            .field("command_script", &self.command_script)
+            .field("tables", &self.tables)
+            .field("screen", &self.screen)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for OutputStreams {
+    fn default() -> Self {
+        OutputStreams::new(Box::new(OutputBuffer::default()))
+    }
+}
+
+impl OutputStreams {
+    /// Only the screen is selected.
+    pub fn new(sink: Box<dyn OutputSink>) -> Self {
+        OutputStreams { sink, screen: None, transcript: false, command_script: false, tables: Vec::new() }
+    }
+
+    /// Installs the screen that stream 1 prints to, or with `None` sends
+    /// stream 1 to the sink again. Returns the old screen.
+    pub fn set_screen(&mut self, screen: Option<Screen>) -> Option<Screen> {
+        std::mem::replace(&mut self.screen, screen)
+    }
+
+    pub fn screen(&self) -> Option<&Screen> {
+        self.screen.as_ref()
+    }
+
+    pub fn screen_mut(&mut self) -> Option<&mut Screen> {
+        self.screen.as_mut()
+    }
+
+    /// Replaces the sink, returning the old one. Stream selections are kept.
+    pub fn set_sink(&mut self, sink: Box<dyn OutputSink>) -> Box<dyn OutputSink> {
+        std::mem::replace(&mut self.sink, sink)
+    }
+
+    /// Whether stream `stream` (1-4) is selected.
+    pub fn is_selected(&self, stream: u64) -> bool {
+        match stream {
+            STREAM_SCREEN => true,
+            STREAM_TRANSCRIPT => self.transcript,
+            STREAM_MEMORY => !self.tables.is_empty(),
+            STREAM_COMMAND_SCRIPT => self.command_script,
+            _ => false,
+        }
+    }
+
+    /// How many memory tables are selected.
+    pub fn memory_table_depth(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Selects stream `stream`, or deselects stream `-stream` if it is
+    /// negative. `table` is the memory table for stream 3 and is otherwise
+    /// ignored. Deselecting stream 3 ends the innermost table; deselecting a
+    /// stream that is not selected does nothing.
+    pub fn select(&mut self, memory: &mut Memory, stream: i64, table: u64) -> Result<(), OutputError> {
+        match (stream.signum(), stream.unsigned_abs()) {
+            (1, STREAM_SCREEN) => {}
+            (1, STREAM_TRANSCRIPT) => {
+                self.transcript = memory.header().flags1 & FLAGS1_TRANSCRIPTING != 0;
+            }
+            (1, STREAM_MEMORY) => self.open_table(memory, table)?,
+            (1, STREAM_COMMAND_SCRIPT) => self.command_script = true,
+            (-1, STREAM_SCREEN) => return Err(OutputError::CannotDeselectScreen),
+            (-1, STREAM_TRANSCRIPT) => self.transcript = false,
+            (-1, STREAM_MEMORY) => {
+                self.tables.pop();
+            }
+            (-1, STREAM_COMMAND_SCRIPT) => self.command_script = false,
+            _ => return Err(OutputError::InvalidStream(stream)),
+        }
+        Ok(())
+    }
+
+    fn open_table(&mut self, memory: &mut Memory, address: u64) -> Result<(), OutputError> {
+        if self.tables.len() == MAX_MEMORY_TABLE_DEPTH {
+            return Err(OutputError::MemoryTableDepth);
+        }
+        let capacity = memory.read_word(address)?;
+        let data = address.checked_add(TABLE_LENGTH_SIZE)
+            .ok_or(MemoryError::OutOfBounds { address, len: TABLE_LENGTH_SIZE })?;
+        memory.read_slice(data, capacity)?;
+        memory.write_word(address, 0)?;
+        self.tables.push(MemoryTable { address, capacity, len: 0 });
+        Ok(())
+    }
+
+    /// Prints `text` to the innermost memory table if one is selected, and
+    /// otherwise to the screen and, if selected, the transcript.
+    pub fn print(&mut self, memory: &mut Memory, text: &str) -> Result<(), OutputError> {
+        let Some(table) = self.tables.last_mut() else {
+            match &mut self.screen {
+                Some(screen) => screen.print(text),
+                None => self.sink.print(text),
+            }
+            if self.transcript {
+                self.sink.transcript(text);
+            }
+            return Ok(());
+        };
+        // Every code `to_zscii` produces fits in a byte.
+        let bytes: Vec<u8> = text::to_zscii(text).into_iter().map(|c| c as u8).collect();
+        let len = table.len + bytes.len() as u64;
+        if len > table.capacity {
+            return Err(OutputError::MemoryTableFull { table: table.address, capacity: table.capacity });
+        }
+        memory.write_slice(table.address + TABLE_LENGTH_SIZE + table.len, &bytes)?;
+        memory.write_word(table.address, len)?;
+        table.len = len;
+        Ok(())
+    }
+
+    /// Records a line of player input in the transcript and the command
+    /// script, if selected.
+    pub fn record_command(&mut self, line: &str) {
+        if self.transcript {
+            self.sink.transcript(&format!("{}\n", line));
+        }
+        if self.command_script {
+            self.sink.command_script(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::create_dummy_header_bytes;
+
+    // Tables live in the dynamic section of the dummy header (3328-5375).
+    const TABLE: u64 = 4000;
+    const INNER_TABLE: u64 = 4200;
+
+    fn memory_with_flags(flags1: u32) -> Memory {
+        let mut story_data = create_dummy_header_bytes();
+        story_data[100..104].copy_from_slice(&flags1.to_be_bytes());
+        story_data.resize(3328, 0);
+        Memory::new(story_data).unwrap()
+    }
+
+    fn streams() -> (OutputStreams, Rc<RefCell<OutputBuffer>>) {
+        let buffer = Rc::new(RefCell::new(OutputBuffer::default()));
+        (OutputStreams::new(Box::new(buffer.clone())), buffer)
+    }
+
+    fn table_contents(memory: &Memory, table: u64) -> String {
+        let len = memory.read_word(table).unwrap();
+        String::from_utf8(memory.read_slice(table + 8, len).unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_screen_is_always_selected() {
+        let mut memory = memory_with_flags(0);
+        let (mut streams, buffer) = streams();
+        streams.select(&mut memory, 1, 0).unwrap();
+        streams.print(&mut memory, "hello").unwrap();
+        assert_eq!(buffer.borrow().screen, "hello");
+        assert_eq!(streams.select(&mut memory, -1, 0), Err(OutputError::CannotDeselectScreen));
+        assert_eq!(streams.select(&mut memory, 5, 0), Err(OutputError::InvalidStream(5)));
+        assert_eq!(streams.select(&mut memory, 0, 0), Err(OutputError::InvalidStream(0)));
+        assert_eq!(streams.select(&mut memory, -7, 0), Err(OutputError::InvalidStream(-7)));
+    }
+
+    #[test]
+    fn test_transcript_needs_header_flag() {
+        let mut memory = memory_with_flags(0);
+        let (mut streams, buffer) = streams();
+        streams.select(&mut memory, 2, 0).unwrap();
+        assert!(!streams.is_selected(STREAM_TRANSCRIPT));
+        streams.print(&mut memory, "a").unwrap();
+
+        let mut memory = memory_with_flags(FLAGS1_TRANSCRIPTING);
+        streams.select(&mut memory, 2, 0).unwrap();
+        assert!(streams.is_selected(STREAM_TRANSCRIPT));
+        streams.print(&mut memory, "b").unwrap();
+        streams.select(&mut memory, -2, 0).unwrap();
+        streams.print(&mut memory, "c").unwrap();
+        assert_eq!(buffer.borrow().screen, "abc");
+        assert_eq!(buffer.borrow().transcript, "b");
+    }
+
+    #[test]
+    fn test_memory_tables_nest() {
+        let mut memory = memory_with_flags(FLAGS1_TRANSCRIPTING);
+        memory.write_word(TABLE, 100).unwrap();
+        memory.write_word(INNER_TABLE, 10).unwrap();
+        let (mut streams, buffer) = streams();
+        streams.select(&mut memory, 2, 0).unwrap();
+
+        streams.select(&mut memory, 3, TABLE).unwrap();
+        assert_eq!(memory.read_word(TABLE), Ok(0), "the length word starts at 0");
+        streams.print(&mut memory, "outer\n").unwrap();
+        streams.select(&mut memory, 3, INNER_TABLE).unwrap();
+        assert_eq!(streams.memory_table_depth(), 2);
+        streams.print(&mut memory, "inner").unwrap();
+        streams.select(&mut memory, -3, 0).unwrap();
+        streams.print(&mut memory, "again").unwrap();
+        streams.select(&mut memory, -3, 0).unwrap();
+        streams.select(&mut memory, -3, 0).unwrap(); // nothing left to deselect
+        streams.print(&mut memory, "screen").unwrap();
+
+        assert_eq!(memory.read_word(TABLE), Ok(11));
+        assert_eq!(memory.read_byte(TABLE + 8 + 5), Ok(13), "newlines are stored as ZSCII 13");
+        assert_eq!(table_contents(&memory, TABLE), "outer\ragain");
+        assert_eq!(table_contents(&memory, INNER_TABLE), "inner");
+        assert_eq!(buffer.borrow().screen, "screen");
+        assert_eq!(buffer.borrow().transcript, "screen");
+    }
+
+    #[test]
+    fn test_memory_table_errors() {
+        let mut memory = memory_with_flags(0);
+        let (mut streams, _) = streams();
+
+        memory.write_word(TABLE, 4).unwrap();
+        streams.select(&mut memory, 3, TABLE).unwrap();
+        streams.print(&mut memory, "abc").unwrap();
+        assert_eq!(
+            streams.print(&mut memory, "de"),
+            Err(OutputError::MemoryTableFull { table: TABLE, capacity: 4 })
+        );
+        assert_eq!(table_contents(&memory, TABLE), "abc", "a failed print writes nothing");
+        streams.select(&mut memory, -3, 0).unwrap();
+
+        memory.write_word(TABLE, u64::MAX).unwrap();
+        assert!(matches!(
+            streams.select(&mut memory, 3, TABLE),
+            Err(OutputError::Memory(MemoryError::OutOfBounds { .. }))
+        ));
+        assert!(matches!(
+            streams.select(&mut memory, 3, 1024), // the code section
+            Err(OutputError::Memory(MemoryError::WriteProtected { .. }))
+        ));
+        assert_eq!(streams.memory_table_depth(), 0);
+
+        memory.write_word(TABLE, 0).unwrap();
+        for _ in 0..MAX_MEMORY_TABLE_DEPTH {
+            streams.select(&mut memory, 3, TABLE).unwrap();
+        }
+        assert_eq!(streams.select(&mut memory, 3, TABLE), Err(OutputError::MemoryTableDepth));
+    }
+
+    #[test]
+    fn test_command_script_and_transcript_echo() {
+        let mut memory = memory_with_flags(FLAGS1_TRANSCRIPTING);
+        let (mut streams, buffer) = streams();
+        streams.record_command("north");
+        streams.select(&mut memory, 4, 0).unwrap();
+        streams.record_command("take lamp");
+        streams.select(&mut memory, 2, 0).unwrap();
+        streams.select(&mut memory, -4, 0).unwrap();
+        streams.record_command("south");
+        assert_eq!(buffer.borrow().command_script, "take lamp\n");
+        assert_eq!(buffer.borrow().transcript, "south\n");
+        assert_eq!(buffer.borrow().screen, "");
+    }
+}
@@ -0,0 +1,551 @@
+// zm2_vm/src/screen.rs
+
+//! The screen model (spec sections bk-br; Z-Machine Standard 1.1, section 8).
+//!
+//! The screen is divided into two windows. The upper window, window 1, takes
+//! the top `split_window` lines and is used for status lines, maps and menus:
+//! text printed there is placed at the cursor and cut off at the right edge
+//! and the bottom of the window. The lower window, window 0, takes the rest
+//! of the screen and scrolls: text wraps at the right edge, at word breaks
+//! while buffering is on, and the window scrolls up when the cursor passes
+//! its bottom line.
+//!
+//! `Screen` keeps the window sizes, a cursor for each window and the
+//! buffering mode, and draws through a `ScreenHost` supplied by the host.
+//! Rows and columns are 1-based; cursors are relative to the top left of
+//! their window, and host coordinates to the top left of the screen.
+//!
+//! The spec has `set_cursor` leave the row of the upper window alone; the
+//! cursor can go anywhere in either window instead, since a status line of
+//! more than one row needs it.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+/// Draws the screen. Implemented by the host.
+pub trait ScreenHost {
+    /// The screen's size as `(rows, columns)`, read once when a `Screen` is
+    /// created.
+    fn size(&self) -> (u64, u64);
+
+    /// Draws `text` from `column` of `row`. It always fits on the row.
+    fn write(&mut self, row: u64, column: u64, text: &str);
+
+    /// Clears rows `top` to `bottom`.
+    fn erase_rows(&mut self, top: u64, bottom: u64);
+
+    /// Clears `row` from `column` to the right edge.
+    fn erase_to_end_of_line(&mut self, row: u64, column: u64);
+
+    /// Moves rows `top + 1` to `bottom` up one row and clears row `bottom`.
+    fn scroll(&mut self, top: u64, bottom: u64);
+
+    /// Shows the cursor at `row`, `column`. The default ignores it.
+    fn move_cursor(&mut self, _row: u64, _column: u64) {}
+}
+
+/// Lets the host keep a handle on a screen it has given to the VM.
+impl<T: ScreenHost> ScreenHost for Rc<RefCell<T>> {
+    fn size(&self) -> (u64, u64) {
+        self.borrow().size()
+    }
+
+    fn write(&mut self, row: u64, column: u64, text: &str) {
+        self.borrow_mut().write(row, column, text)
+    }
+
+    fn erase_rows(&mut self, top: u64, bottom: u64) {
+        self.borrow_mut().erase_rows(top, bottom)
+    }
+
+    fn erase_to_end_of_line(&mut self, row: u64, column: u64) {
+        self.borrow_mut().erase_to_end_of_line(row, column)
+    }
+
+    fn scroll(&mut self, top: u64, bottom: u64) {
+        self.borrow_mut().scroll(top, bottom)
+    }
+
+    fn move_cursor(&mut self, row: u64, column: u64) {
+        self.borrow_mut().move_cursor(row, column)
+    }
+}
+
+/// A `ScreenHost` that keeps the screen as a grid of characters, for tests
+/// and hosts that draw the whole screen at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharGrid {
+    cells: Vec<Vec<char>>,
+    columns: u64,
+    cursor: (u64, u64),
+}
+
+impl CharGrid {
+    /// A blank grid of `rows` by `columns`.
+    pub fn new(rows: u64, columns: u64) -> Self {
+        CharGrid { cells: vec![vec![' '; columns as usize]; rows as usize], columns, cursor: (1, 1) }
+    }
+
+    /// The text of `row` (1-based), without trailing spaces.
+    pub fn row(&self, row: u64) -> String {
+        self.cells[row as usize - 1].iter().collect::<String>().trim_end().to_string()
+    }
+
+    /// Every row, as `row` gives them.
+    pub fn rows(&self) -> Vec<String> {
+        (1..=self.cells.len() as u64).map(|row| self.row(row)).collect()
+    }
+
+    /// Where the cursor was last shown, as `(row, column)`.
+    pub fn cursor(&self) -> (u64, u64) {
+        self.cursor
+    }
+}
+
+impl ScreenHost for CharGrid {
+    fn size(&self) -> (u64, u64) {
+        (self.cells.len() as u64, self.columns)
+    }
+
+    fn write(&mut self, row: u64, column: u64, text: &str) {
+        let cells = &mut self.cells[row as usize - 1][column as usize - 1..];
+        for (cell, c) in cells.iter_mut().zip(text.chars()) {
+            *cell = c;
+        }
+    }
+
+    fn erase_rows(&mut self, top: u64, bottom: u64) {
+        for row in &mut self.cells[top as usize - 1..bottom as usize] {
+            row.fill(' ');
+        }
+    }
+
+    fn erase_to_end_of_line(&mut self, row: u64, column: u64) {
+        self.cells[row as usize - 1][column as usize - 1..].fill(' ');
+    }
+
+    fn scroll(&mut self, top: u64, bottom: u64) {
+        self.cells[top as usize - 1..bottom as usize].rotate_left(1);
+        self.cells[bottom as usize - 1].fill(' ');
+    }
+
+    fn move_cursor(&mut self, row: u64, column: u64) {
+        self.cursor = (row, column);
+    }
+}
+
+/// One of the two windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Lower,
+    Upper,
+}
+
+impl Window {
+    /// The window numbered `id` by the opcodes: 0 for lower, 1 for upper.
+    pub fn from_id(id: i64) -> Result<Window, ScreenError> {
+        match id {
+            0 => Ok(Window::Lower),
+            1 => Ok(Window::Upper),
+            _ => Err(ScreenError::InvalidWindow(id)),
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScreenError {
+    /// A window number other than 0 or 1 (or -1 for `erase_window`).
+    InvalidWindow(i64),
+    /// `split_window` asked for more lines than the screen has.
+    SplitTooLarge { lines: u64, rows: u64 },
+    /// `set_cursor` asked for a position outside the window.
+    CursorOutOfBounds { window: Window, row: u64, column: u64 },
+}
+
+impl fmt::Display for ScreenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScreenError::InvalidWindow(id) => write!(f, "invalid window {}", id),
+            ScreenError::SplitTooLarge { lines, rows } => {
+                write!(f, "cannot split {} lines from a screen of {} rows", lines, rows)
+            }
+            ScreenError::CursorOutOfBounds { window, row, column } => {
+                write!(f, "cursor position ({}, {}) is outside the {:?} window", row, column, window)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScreenError {}
+
+/// The window layout and cursors, drawn through a `ScreenHost`.
+pub struct Screen {
+    host: Box<dyn ScreenHost>,
+    rows: u64,
+    columns: u64,
+    upper_lines: u64,
+    current: Window,
+    // Indexed by `Window::index`; `(row, column)` within the window.
+    cursors: [(u64, u64); 2],
+    buffered: bool,
+}
+
+impl fmt::Debug for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Screen")
+            .field("rows", &self.rows)
+            .field("columns", &self.columns)
+            .field("upper_lines", &self.upper_lines)
+            .field("current", &self.current)
+            .field("cursors", &self.cursors)
+            .field("buffered", &self.buffered)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Screen {
+    /// An unsplit screen of the host's size with the lower window selected,
+    /// both cursors at the top left and buffering on.
+    pub fn new(host: Box<dyn ScreenHost>) -> Self {
+        let (rows, columns) = host.size();
+        Screen { host, rows, columns, upper_lines: 0, current: Window::Lower, cursors: [(1, 1); 2], buffered: true }
+    }
+
+    /// The screen's size as `(rows, columns)`.
+    pub fn size(&self) -> (u64, u64) {
+        (self.rows, self.columns)
+    }
+
+    /// Height of the upper window; 0 if the screen is not split.
+    pub fn upper_lines(&self) -> u64 {
+        self.upper_lines
+    }
+
+    pub fn current_window(&self) -> Window {
+        self.current
+    }
+
+    /// Whether lower window text is buffered, so that it wraps between words.
+    pub fn buffered(&self) -> bool {
+        self.buffered
+    }
+
+    pub fn set_buffered(&mut self, buffered: bool) {
+        self.buffered = buffered;
+    }
+
+    /// The current window's cursor, as `(row, column)` within the window.
+    pub fn cursor(&self) -> (u64, u64) {
+        self.cursors[self.current.index()]
+    }
+
+    /// The cursor of `window`.
+    pub fn cursor_in(&self, window: Window) -> (u64, u64) {
+        self.cursors[window.index()]
+    }
+
+    fn height(&self, window: Window) -> u64 {
+        match window {
+            Window::Upper => self.upper_lines,
+            Window::Lower => self.rows - self.upper_lines,
+        }
+    }
+
+    /// Screen row of `row` in `window`.
+    fn screen_row(&self, window: Window, row: u64) -> u64 {
+        match window {
+            Window::Upper => row,
+            Window::Lower => self.upper_lines + row,
+        }
+    }
+
+    fn show_cursor(&mut self) {
+        let (row, column) = self.cursor();
+        let row = self.screen_row(self.current, row);
+        self.host.move_cursor(row, column);
+    }
+
+    /// Gives the upper window `lines` lines and selects the lower window.
+    /// Nothing is erased. The lower window's cursor keeps its place on the
+    /// screen unless the upper window now covers it, when it moves to the
+    /// top left of the lower window; the upper window's cursor moves to its
+    /// top left if it is no longer inside the window.
+    pub fn split_window(&mut self, lines: u64) -> Result<(), ScreenError> {
+        if lines > self.rows {
+            return Err(ScreenError::SplitTooLarge { lines, rows: self.rows });
+        }
+        let (row, column) = self.cursors[Window::Lower.index()];
+        let screen_row = self.upper_lines + row;
+        self.cursors[Window::Lower.index()] = if screen_row > lines { (screen_row - lines, column) } else { (1, 1) };
+        if self.cursors[Window::Upper.index()].0 > lines {
+            self.cursors[Window::Upper.index()] = (1, 1);
+        }
+        self.upper_lines = lines;
+        self.current = Window::Lower;
+        self.show_cursor();
+        Ok(())
+    }
+
+    /// Selects `window` for output, with the cursor where it was left.
+    pub fn set_window(&mut self, window: Window) {
+        self.current = window;
+        self.show_cursor();
+    }
+
+    /// Clears window `id` and moves its cursor to the top left. An `id` of
+    /// -1 clears both windows and selects the lower one.
+    pub fn erase_window(&mut self, id: i64) -> Result<(), ScreenError> {
+        let windows: &[Window] = match id {
+            -1 => {
+                self.current = Window::Lower;
+                &[Window::Upper, Window::Lower]
+            }
+            _ => &[Window::from_id(id)?],
+        };
+        for &window in windows {
+            let height = self.height(window);
+            if height > 0 {
+                let top = self.screen_row(window, 1);
+                self.host.erase_rows(top, top + height - 1);
+            }
+            self.cursors[window.index()] = (1, 1);
+        }
+        self.show_cursor();
+        Ok(())
+    }
+
+    /// Clears the current window's line from the cursor to the right edge.
+    /// The cursor does not move.
+    pub fn erase_line(&mut self) {
+        let (row, column) = self.cursor();
+        if row <= self.height(self.current) && column <= self.columns {
+            let row = self.screen_row(self.current, row);
+            self.host.erase_to_end_of_line(row, column);
+        }
+    }
+
+    /// Moves the cursor of `window`, or of the current window if `None`, to
+    /// `row`, `column` within it.
+    pub fn set_cursor(&mut self, row: u64, column: u64, window: Option<Window>) -> Result<(), ScreenError> {
+        let window = window.unwrap_or(self.current);
+        if row == 0 || row > self.height(window) || column == 0 || column > self.columns {
+            return Err(ScreenError::CursorOutOfBounds { window, row, column });
+        }
+        self.cursors[window.index()] = (row, column);
+        self.show_cursor();
+        Ok(())
+    }
+
+    /// Prints `text` in the current window at its cursor.
+    pub fn print(&mut self, text: &str) {
+        match self.current {
+            Window::Upper => self.print_upper(text),
+            Window::Lower => self.print_lower(text),
+        }
+        self.show_cursor();
+    }
+
+    fn print_upper(&mut self, text: &str) {
+        for (i, line) in text.split('\n').enumerate() {
+            let cursor = &mut self.cursors[Window::Upper.index()];
+            if i > 0 {
+                *cursor = (cursor.0 + 1, 1);
+            }
+            let (row, column) = *cursor;
+            // Text past the right edge or the bottom of the window is lost.
+            cursor.1 = (column + line.chars().count() as u64).min(self.columns + 1);
+            if row <= self.upper_lines && column <= self.columns && !line.is_empty() {
+                let visible: String = line.chars().take((self.columns - column + 1) as usize).collect();
+                self.host.write(row, column, &visible);
+            }
+        }
+    }
+
+    fn print_lower(&mut self, text: &str) {
+        if self.height(Window::Lower) == 0 {
+            return;
+        }
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.new_lower_line();
+            }
+            for word in split_keeping_spaces(line) {
+                let len = word.chars().count() as u64;
+                let column = self.cursors[Window::Lower.index()].1;
+                if word.starts_with(' ') {
+                    for _ in 0..len {
+                        if self.cursors[Window::Lower.index()].1 > self.columns {
+                            self.new_lower_line();
+                        } else {
+                            self.put_lower(" ");
+                        }
+                    }
+                    continue;
+                }
+                // A word that would cross the edge starts a new line, unless
+                // it is longer than a whole line.
+                if self.buffered && column > 1 && len <= self.columns && column + len - 1 > self.columns {
+                    self.new_lower_line();
+                }
+                for c in word.chars() {
+                    if self.cursors[Window::Lower.index()].1 > self.columns {
+                        self.new_lower_line();
+                    }
+                    self.put_lower(c.encode_utf8(&mut [0; 4]));
+                }
+            }
+        }
+    }
+
+    /// Draws `text`, which fits before the right edge, at the lower cursor.
+    fn put_lower(&mut self, text: &str) {
+        let (row, column) = self.cursors[Window::Lower.index()];
+        self.host.write(self.upper_lines + row, column, text);
+        self.cursors[Window::Lower.index()].1 = column + text.chars().count() as u64;
+    }
+
+    /// Moves the lower cursor to the start of the next line, scrolling the
+    /// window if it is on the bottom line.
+    fn new_lower_line(&mut self) {
+        let height = self.height(Window::Lower);
+        let row = self.cursors[Window::Lower.index()].0;
+        if row < height {
+            self.cursors[Window::Lower.index()] = (row + 1, 1);
+        } else {
+            self.host.scroll(self.upper_lines + 1, self.rows);
+            self.cursors[Window::Lower.index()] = (height, 1);
+        }
+    }
+}
+
+/// Splits `line` into runs of spaces and runs of other characters.
+fn split_keeping_spaces(line: &str) -> Vec<&str> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for (i, c) in line.char_indices() {
+        if i > start && (c == ' ') != line[start..].starts_with(' ') {
+            runs.push(&line[start..i]);
+            start = i;
+        }
+    }
+    if start < line.len() {
+        runs.push(&line[start..]);
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(rows: u64, columns: u64) -> (Screen, Rc<RefCell<CharGrid>>) {
+        let grid = Rc::new(RefCell::new(CharGrid::new(rows, columns)));
+        (Screen::new(Box::new(grid.clone())), grid)
+    }
+
+    #[test]
+    fn test_status_line() {
+        let (mut screen, grid) = screen(4, 12);
+        assert_eq!(screen.size(), (4, 12));
+        screen.split_window(1).unwrap();
+        screen.set_window(Window::Upper);
+        screen.print("West of House");
+        screen.set_cursor(1, 10, None).unwrap();
+        screen.print("T:3\nlost");
+        assert_eq!(grid.borrow().rows(), ["West of HT:3", "", "", ""]);
+        assert_eq!(screen.cursor(), (2, 5), "text below the window is lost");
+
+        screen.set_window(Window::Lower);
+        assert_eq!(screen.cursor(), (1, 1));
+        screen.print("You are here.");
+        assert_eq!(grid.borrow().rows(), ["West of HT:3", "You are", "here.", ""]);
+        assert_eq!(screen.cursor(), (2, 6));
+        assert_eq!(grid.borrow().cursor(), (3, 6));
+    }
+
+    #[test]
+    fn test_lower_window_scrolls() {
+        let (mut screen, grid) = screen(3, 10);
+        screen.split_window(1).unwrap();
+        screen.print("one\ntwo\nthree four");
+        assert_eq!(grid.borrow().rows(), ["", "two", "three four"]);
+        screen.print("\n");
+        assert_eq!(grid.borrow().rows(), ["", "three four", ""]);
+        assert_eq!(screen.cursor(), (2, 1));
+
+        // Without buffering, words break at the edge.
+        screen.set_buffered(false);
+        assert!(!screen.buffered());
+        screen.print("abcdefghijklm");
+        assert_eq!(grid.borrow().rows(), ["", "abcdefghij", "klm"]);
+    }
+
+    #[test]
+    fn test_split_moves_cursors() {
+        let (mut screen, _) = screen(6, 10);
+        screen.print("a\nb\nc");
+        assert_eq!(screen.cursor(), (3, 2));
+        screen.split_window(2).unwrap();
+        assert_eq!(screen.cursor(), (1, 2), "the cursor keeps its place on the screen");
+        screen.split_window(4).unwrap();
+        assert_eq!(screen.cursor(), (1, 1), "a cursor under the upper window moves to the top");
+
+        screen.set_cursor(4, 3, Some(Window::Upper)).unwrap();
+        assert_eq!(screen.current_window(), Window::Lower);
+        assert_eq!(screen.cursor_in(Window::Upper), (4, 3));
+        screen.split_window(3).unwrap();
+        assert_eq!(screen.cursor_in(Window::Upper), (1, 1));
+        assert_eq!(screen.upper_lines(), 3);
+        assert_eq!(screen.split_window(7), Err(ScreenError::SplitTooLarge { lines: 7, rows: 6 }));
+    }
+
+    #[test]
+    fn test_erase() {
+        let (mut screen, grid) = screen(3, 8);
+        screen.split_window(1).unwrap();
+        screen.set_window(Window::Upper);
+        screen.print("status");
+        screen.set_window(Window::Lower);
+        screen.print("lower\ntext");
+        screen.set_cursor(1, 3, None).unwrap();
+        screen.erase_line();
+        assert_eq!(grid.borrow().rows(), ["status", "lo", "text"]);
+        assert_eq!(screen.cursor(), (1, 3));
+
+        screen.erase_window(0).unwrap();
+        assert_eq!(grid.borrow().rows(), ["status", "", ""]);
+        assert_eq!(screen.cursor(), (1, 1));
+
+        screen.print("x");
+        screen.set_window(Window::Upper);
+        screen.erase_window(-1).unwrap();
+        assert_eq!(grid.borrow().rows(), ["", "", ""]);
+        assert_eq!(screen.current_window(), Window::Lower);
+        assert_eq!(screen.cursor_in(Window::Upper), (1, 1));
+        assert_eq!(screen.erase_window(2), Err(ScreenError::InvalidWindow(2)));
+    }
+
+    #[test]
+    fn test_set_cursor_bounds() {
+        let (mut screen, _) = screen(5, 10);
+        screen.split_window(2).unwrap();
+        assert!(screen.set_cursor(3, 10, None).is_ok());
+        assert_eq!(
+            screen.set_cursor(3, 1, Some(Window::Upper)),
+            Err(ScreenError::CursorOutOfBounds { window: Window::Upper, row: 3, column: 1 })
+        );
+        assert!(screen.set_cursor(1, 11, None).is_err());
+        assert!(screen.set_cursor(0, 1, None).is_err());
+        assert_eq!(Window::from_id(1), Ok(Window::Upper));
+        assert_eq!(Window::from_id(-1), Err(ScreenError::InvalidWindow(-1)));
+        assert_eq!(
+            ScreenError::CursorOutOfBounds { window: Window::Upper, row: 3, column: 1 }.to_string(),
+            "cursor position (3, 1) is outside the Upper window"
+        );
+    }
+}
@@ -0,0 +1,400 @@
+// zm2_vm/src/text.rs
+
+//! Z-encoded text (Z-Machine Standard 1.1, section 3).
+//!
+//! A string is a sequence of 2-byte big-endian words, each holding three
+//! 5-bit Z-characters; the top bit of the last word is set. Z-characters 6-31
+//! print a letter from the current alphabet: lowercase (A0), uppercase (A1) or
+//! punctuation (A2). Z-characters 4 and 5 shift the next character into A1 or
+//! A2, and 0 is a space. In A2, Z-character 6 starts a 10-bit ZSCII escape
+//! (the next two Z-characters) and 7 is a newline.
+//!
+//! Z-characters 1-3 followed by `x` print abbreviation `32 * (z - 1) + x`. The
+//! abbreviation table at `abbreviations_table_start` holds one 8-byte
+//! absolute address per abbreviation; abbreviations may not use abbreviations.
+//!
+//! The encoder goes the other way, from UTF-8 through ZSCII to Z-characters.
+//! It never emits abbreviations, and characters with no ZSCII code are
+//! encoded as `?`.
+
+use crate::memory::Memory;
+use crate::MemoryError;
+use std::fmt;
+
+/// Alphabet A0: lowercase letters, for Z-characters 6-31.
+pub const ALPHABET_A0: &[u8; 26] = b"abcdefghijklmnopqrstuvwxyz";
+/// Alphabet A1: uppercase letters.
+pub const ALPHABET_A1: &[u8; 26] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+/// Alphabet A2: punctuation. Entries 0 and 1 (Z-characters 6 and 7) are the
+/// ZSCII escape and newline, and never printed from this table.
+pub const ALPHABET_A2: &[u8; 26] = b" \n0123456789.,!?_#'\"/\\-:()";
+
+/// The Unicode characters for ZSCII 155-223 (the standard's default
+/// translation table).
+pub const EXTRA_CHARACTERS: &str = "äöüÄÖÜß»«ëïÿËÏáéíóúýÁÉÍÓÚÝàèìòùÀÈÌÒÙâêîôûÂÊÎÔÛåÅøØãñõÃÑÕæÆçÇþðÞÐ£œŒ¡¿";
+
+/// ZSCII code for a newline; also the terminator of a line of input.
+pub const ZSCII_NEWLINE: u16 = 13;
+
+/// ZSCII code of the first character in `EXTRA_CHARACTERS`.
+pub const FIRST_EXTRA_ZSCII: u16 = 155;
+
+/// Length of an encoded dictionary word (and of `encode_text`'s output):
+/// three words, nine Z-characters.
+pub const DICTIONARY_WORD_BYTES: usize = 6;
+
+/// ZSCII code the encoder substitutes for characters it cannot represent.
+const ZSCII_QUESTION_MARK: u16 = b'?' as u16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextError {
+    /// Reading the string or the abbreviation table failed.
+    Memory(MemoryError),
+    /// The string for an abbreviation itself uses abbreviation `index`.
+    NestedAbbreviation { index: u64 },
+    /// The story has no abbreviation table, but the string uses abbreviation `index`.
+    NoAbbreviationTable { index: u64 },
+}
+
+impl From<MemoryError> for TextError {
+    fn from(e: MemoryError) -> Self {
+        TextError::Memory(e)
+    }
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextError::Memory(e) => write!(f, "reading text failed: {}", e),
+            TextError::NestedAbbreviation { index } => {
+                write!(f, "abbreviation {} used inside an abbreviation", index)
+            }
+            TextError::NoAbbreviationTable { index } => {
+                write!(f, "abbreviation {} used, but the story has no abbreviation table", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TextError::Memory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a ZSCII output code to a character. Returns `None` for codes
+/// with no printable meaning.
+pub fn zscii_to_char(code: u16) -> Option<char> {
+    match code {
+        13 => Some('\n'),
+        32..=126 => Some(code as u8 as char),
+        155..=223 => EXTRA_CHARACTERS.chars().nth((code - FIRST_EXTRA_ZSCII) as usize),
+        _ => None,
+    }
+}
+
+/// Converts a character to its ZSCII code. Returns `None` for characters
+/// ZSCII cannot represent.
+pub fn char_to_zscii(c: char) -> Option<u16> {
+    match c {
+        '\n' => Some(13),
+        ' '..='~' => Some(c as u16),
+        _ => EXTRA_CHARACTERS.chars().position(|e| e == c).map(|i| FIRST_EXTRA_ZSCII + i as u16),
+    }
+}
+
+/// Converts `text` to ZSCII, substituting `?` for unrepresentable characters.
+pub fn to_zscii(text: &str) -> Vec<u16> {
+    text.chars().map(|c| char_to_zscii(c).unwrap_or(ZSCII_QUESTION_MARK)).collect()
+}
+
+/// Z-characters for a sequence of ZSCII codes, using single shifts for A1
+/// and A2 and 10-bit escapes for everything else.
+pub fn zscii_to_zchars(zscii: &[u16]) -> Vec<u8> {
+    let mut zchars = Vec::new();
+    for &code in zscii {
+        let position = |alphabet: &[u8; 26]| {
+            u8::try_from(code).ok().and_then(|b| alphabet.iter().position(|&a| a == b)).map(|i| i as u8 + 6)
+        };
+        if code == b' ' as u16 {
+            zchars.push(0);
+        } else if let Some(z) = position(ALPHABET_A0) {
+            zchars.push(z);
+        } else if let Some(z) = position(ALPHABET_A1) {
+            zchars.extend_from_slice(&[4, z]);
+        } else if code == 13 {
+            zchars.extend_from_slice(&[5, 7]);
+        } else if let Some(z) = position(ALPHABET_A2).filter(|&z| z > 7) {
+            zchars.extend_from_slice(&[5, z]);
+        } else {
+            let code = code & 0x3FF;
+            zchars.extend_from_slice(&[5, 6, (code >> 5) as u8, (code & 0x1F) as u8]);
+        }
+    }
+    zchars
+}
+
+/// Packs Z-characters three to a word, padding the last word with 5s and
+/// setting its end bit.
+pub fn pack_zchars(zchars: &[u8]) -> Vec<u8> {
+    let words = zchars.len().div_ceil(3).max(1);
+    let mut bytes = Vec::with_capacity(2 * words);
+    for n in 0..words {
+        let z = |k: usize| *zchars.get(3 * n + k).unwrap_or(&5) as u16 & 0x1F;
+        let end_bit = if n == words - 1 { 0x8000 } else { 0 };
+        let word = end_bit | (z(0) << 10) | (z(1) << 5) | z(2);
+        bytes.extend_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+/// Encodes `text` as a complete Z-encoded string.
+pub fn encode_string(text: &str) -> Vec<u8> {
+    pack_zchars(&zscii_to_zchars(&to_zscii(text)))
+}
+
+/// Encodes ZSCII text the way dictionary words are stored: exactly
+/// `DICTIONARY_WORD_BYTES` bytes, truncated or padded with 5s.
+pub fn encode_dictionary_word(zscii: &[u16]) -> [u8; DICTIONARY_WORD_BYTES] {
+    let mut zchars = zscii_to_zchars(zscii);
+    zchars.resize(DICTIONARY_WORD_BYTES / 2 * 3, 5);
+    let mut word = [0; DICTIONARY_WORD_BYTES];
+    word.copy_from_slice(&pack_zchars(&zchars));
+    word
+}
+
+/// Decodes the Z-encoded string at `address`. Returns the text and the
+/// address just past the string's last word.
+pub fn decode_string(memory: &Memory, address: u64) -> Result<(String, u64), TextError> {
+    let mut text = String::new();
+    let end = decode_into(memory, address, &mut text, false)?;
+    Ok((text, end))
+}
+
+/// Reads the Z-characters of the string at `address`, stopping after the
+/// word with the top bit set. Returns them and the address after the string.
+fn read_zchars(memory: &Memory, address: u64) -> Result<(Vec<u8>, u64), MemoryError> {
+    let mut zchars = Vec::new();
+    let mut word_address = address;
+    loop {
+        let word = memory.read_u16(word_address)?;
+        word_address += 2;
+        zchars.extend_from_slice(&[(word >> 10) as u8 & 0x1F, (word >> 5) as u8 & 0x1F, word as u8 & 0x1F]);
+        if word & 0x8000 != 0 {
+            return Ok((zchars, word_address));
+        }
+    }
+}
+
+fn decode_into(memory: &Memory, address: u64, text: &mut String, in_abbreviation: bool) -> Result<u64, TextError> {
+    let (zchars, end) = read_zchars(memory, address)?;
+    let mut alphabet = 0;
+    let mut i = 0;
+    // A construct cut short by the end of the string (an abbreviation or
+    // escape missing its last Z-characters) is ignored.
+    while i < zchars.len() {
+        let z = zchars[i];
+        i += 1;
+        match z {
+            0 => text.push(' '),
+            1..=3 => {
+                let Some(&next) = zchars.get(i) else { break };
+                i += 1;
+                let index = 32 * (z as u64 - 1) + next as u64;
+                if in_abbreviation {
+                    return Err(TextError::NestedAbbreviation { index });
+                }
+                let table = memory.header().abbreviations_table_start;
+                if table == 0 {
+                    return Err(TextError::NoAbbreviationTable { index });
+                }
+                let string_address = memory.read_word(table.saturating_add(8 * index))?;
+                decode_into(memory, string_address, text, true)?;
+            }
+            4 | 5 => {
+                alphabet = z - 3;
+                continue;
+            }
+            6 if alphabet == 2 => {
+                let (Some(&hi), Some(&lo)) = (zchars.get(i), zchars.get(i + 1)) else { break };
+                i += 2;
+                let code = ((hi as u16) << 5) | lo as u16;
+                if code != 0 {
+                    text.push(zscii_to_char(code).unwrap_or('?'));
+                }
+            }
+            _ => {
+                let table = match alphabet {
+                    0 => ALPHABET_A0,
+                    1 => ALPHABET_A1,
+                    _ => ALPHABET_A2,
+                };
+                text.push(table[z as usize - 6] as char);
+            }
+        }
+        alphabet = 0;
+    }
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{create_dummy_header_bytes, StoryHeader};
+
+    /// Where test strings are written: the start of static data.
+    const STRINGS: u64 = 1280;
+
+    /// Builds memory holding `strings` back to back from `STRINGS`, with an
+    /// abbreviation table pointing at `abbreviations` (indices into
+    /// `strings`). Returns the memory and each string's address.
+    fn memory_with_strings(strings: &[Vec<u8>], abbreviations: &[usize]) -> (Memory, Vec<u64>) {
+        let mut story_data = create_dummy_header_bytes();
+        story_data.resize(3328, 0);
+        let mut addresses = Vec::new();
+        let mut address = STRINGS;
+        for s in strings {
+            addresses.push(address);
+            story_data[address as usize..address as usize + s.len()].copy_from_slice(s);
+            address += s.len() as u64;
+        }
+        let mut header = StoryHeader::from_bytes(&story_data).unwrap();
+        if abbreviations.is_empty() {
+            header.abbreviations_table_start = 0;
+        } else {
+            header.abbreviations_table_start = address;
+            for (i, &s) in abbreviations.iter().enumerate() {
+                let entry = address as usize + 8 * i;
+                story_data[entry..entry + 8].copy_from_slice(&addresses[s].to_be_bytes());
+            }
+        }
+        header.patch_into(&mut story_data).unwrap();
+        (Memory::new(story_data).unwrap(), addresses)
+    }
+
+    fn decode(zchars: &[u8]) -> String {
+        let (memory, addresses) = memory_with_strings(&[pack_zchars(zchars)], &[]);
+        decode_string(&memory, addresses[0]).unwrap().0
+    }
+
+    #[test]
+    fn test_extra_characters_cover_155_to_223() {
+        assert_eq!(EXTRA_CHARACTERS.chars().count(), 223 - 155 + 1);
+        assert_eq!(zscii_to_char(155), Some('ä'));
+        assert_eq!(zscii_to_char(223), Some('¿'));
+        assert_eq!(zscii_to_char(13), Some('\n'));
+        assert_eq!(zscii_to_char(65), Some('A'));
+        assert_eq!(zscii_to_char(0), None);
+        assert_eq!(zscii_to_char(224), None);
+    }
+
+    #[test]
+    fn test_decode_alphabets_and_shifts() {
+        // "Hi, 42" : shift-A1 h, i, shift-A2 comma, space, shift-A2 4, shift-A2 2
+        assert_eq!(decode(&[4, 13, 14, 5, 19, 0, 5, 12, 5, 10]), "Hi, 42");
+        // Shifts last for one character only; a second shift replaces the first.
+        assert_eq!(decode(&[4, 5, 8, 8]), "0c");
+        // A2 Z-character 7 is a newline.
+        assert_eq!(decode(&[6, 5, 7, 6]), "a\na");
+    }
+
+    #[test]
+    fn test_decode_zscii_escape() {
+        // 'é' is ZSCII 170 = 5 << 5 | 10; '@' is 64 = 2 << 5 | 0.
+        assert_eq!(decode(&[5, 6, 5, 10, 5, 6, 2, 0]), "é@");
+        // Codes with no character print as '?'.
+        assert_eq!(decode(&[5, 6, 31, 31]), "?");
+        // An escape cut off by the end of the string is dropped.
+        assert_eq!(decode(&[6, 5, 6]), "a");
+    }
+
+    #[test]
+    fn test_decode_end_address() {
+        let strings = [pack_zchars(&[6, 7, 8, 9]), pack_zchars(&[10])];
+        let (memory, addresses) = memory_with_strings(&strings, &[]);
+        let (text, end) = decode_string(&memory, addresses[0]).unwrap();
+        assert_eq!(text, "abcd");
+        assert_eq!(end, addresses[1]);
+    }
+
+    #[test]
+    fn test_decode_abbreviations() {
+        // Abbreviation 0 is "the", abbreviation 33 (z 2, x 1) is "ab".
+        let strings = [
+            pack_zchars(&[25, 13, 10]),
+            pack_zchars(&[6, 7]),
+            pack_zchars(&[1, 0, 0, 2, 1, 8]),
+        ];
+        let mut abbreviations = vec![0; 34];
+        abbreviations[33] = 1;
+        let (memory, addresses) = memory_with_strings(&strings, &abbreviations);
+        assert_eq!(decode_string(&memory, addresses[2]).unwrap().0, "the abc");
+    }
+
+    #[test]
+    fn test_decode_abbreviation_errors() {
+        // Abbreviation 0's string uses abbreviation 1.
+        let strings = [pack_zchars(&[6, 1, 1]), pack_zchars(&[1, 0])];
+        let (memory, addresses) = memory_with_strings(&strings, &[0]);
+        assert_eq!(decode_string(&memory, addresses[1]), Err(TextError::NestedAbbreviation { index: 1 }));
+
+        let (memory, addresses) = memory_with_strings(&[pack_zchars(&[3, 4])], &[]);
+        assert_eq!(decode_string(&memory, addresses[0]), Err(TextError::NoAbbreviationTable { index: 68 }));
+    }
+
+    #[test]
+    fn test_decode_runs_off_memory() {
+        let mut story_data = create_dummy_header_bytes();
+        story_data.resize(3328, 0);
+        let memory = Memory::new(story_data).unwrap();
+        let size = memory.size();
+        // Zero words never end the string, so decoding runs into the end of memory.
+        assert_eq!(
+            decode_string(&memory, size - 4),
+            Err(TextError::Memory(MemoryError::OutOfBounds { address: size, len: 2 }))
+        );
+    }
+
+    #[test]
+    fn test_char_to_zscii() {
+        assert_eq!(char_to_zscii('a'), Some(97));
+        assert_eq!(char_to_zscii('\n'), Some(13));
+        assert_eq!(char_to_zscii('é'), Some(170));
+        assert_eq!(char_to_zscii('¿'), Some(223));
+        assert_eq!(char_to_zscii('\t'), None);
+        assert_eq!(char_to_zscii('→'), None);
+        assert_eq!(to_zscii("a→b"), vec![97, 63, 98]);
+    }
+
+    #[test]
+    fn test_encode_zchars() {
+        assert_eq!(zscii_to_zchars(&to_zscii("Hi, 42")), vec![4, 13, 14, 5, 19, 0, 5, 12, 5, 10]);
+        assert_eq!(zscii_to_zchars(&to_zscii("a\n@")), vec![6, 5, 7, 5, 6, 2, 0]);
+        assert_eq!(pack_zchars(&[]), vec![0x94, 0xA5], "the empty string is one padding word");
+        assert_eq!(pack_zchars(&[6, 7, 8, 9]), vec![0x18, 0xE8, 0xA4, 0xA5]);
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let samples = ["The brass lantern.", "Ünïcödé ¿¡ £5", "line one\nline two", "tab\there", "", "~{}|`@$%^&*+=[]<>;"];
+        let strings: Vec<Vec<u8>> = samples.iter().map(|s| encode_string(s)).collect();
+        let (memory, addresses) = memory_with_strings(&strings, &[]);
+        for (i, sample) in samples.iter().enumerate() {
+            let expected = sample.replace('\t', "?");
+            assert_eq!(decode_string(&memory, addresses[i]).unwrap().0, expected);
+        }
+    }
+
+    #[test]
+    fn test_encode_dictionary_word() {
+        // "lamp" pads to nine Z-characters; longer words are cut to nine.
+        assert_eq!(encode_dictionary_word(&to_zscii("lamp")), [0x44, 0xD2, 0x54, 0xA5, 0x94, 0xA5]);
+        let long = encode_dictionary_word(&to_zscii("screwdriver"));
+        assert_eq!(long, encode_dictionary_word(&to_zscii("screwdriv")));
+        let (memory, addresses) = memory_with_strings(&[long.to_vec()], &[]);
+        assert_eq!(decode_string(&memory, addresses[0]).unwrap().0, "screwdriv");
+    }
+}